@@ -1,6 +1,33 @@
+/// Whether the transport is moving
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransportState {
+    Stopped,
+    Rolling,
+}
+
 /// A simple audio property change
 /// Expected to be representable with a Copy type
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum AudioProperties {
-    SampleRate(f32)
+    SampleRate(f32),
+
+    /// Most frames the audio thread will be asked for at once
+    BufferSize(usize),
+
+    /// The audio thread missed a deadline, so a block was dropped or repeated.
+    /// Not really a property, but sent the same way
+    Xrun,
+
+    /// Beats per minute
+    Tempo(f32),
+
+    /// Beats per bar, and the note value of a beat (4 for quarter notes)
+    TimeSignature(f32, f32),
+
+    Transport(TransportState),
+
+    /// Position of the transport, in beats from the start of the song. Only
+    /// sent when the position jumps. While the transport is rolling, the
+    /// position moves at the tempo
+    SongPosition(f64),
 }
@@ -0,0 +1,319 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+use util::filter_design::{self, Alignment, Coefficients, Response};
+
+// steepest cascade that can be built
+const MAX_ORDER: usize = 8;
+
+/// Audio EQ Cookbook filters: lowpass, highpass, bandpass, notch, peaking,
+/// lowshelf, highshelf or allpass, with `gain` in dB for peaking and shelves.
+/// `alignment` "none" is one biquad; "butterworth" and "linkwitz-riley" cascade
+/// low or high passes up to `order` 8, ignoring `q`.
+/// Inputs: frequency_in (octaves), q_in and gain_in (offsets)
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct BiquadConfig {
+    pub name: String,
+    pub response: String,
+    pub frequency: f32,
+    pub q: f32,
+    pub gain: f32,
+    pub alignment: String,
+    pub order: usize,
+}
+
+impl BiquadConfig {
+    fn alignment(&self) -> Result<Option<Alignment>, String>
+    {
+        match self.alignment.as_str() {
+            "none" => Ok(None),
+            name => Alignment::from_name(name)
+                .map(Some)
+                .ok_or_else(|| format!("unknown alignment {}", name)),
+        }
+    }
+}
+
+impl ComponentConfig for BiquadConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Biquad::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+
+    fn validate(&self) -> Result<(), String>
+    {
+        let response = Response::from_name(&self.response)
+            .ok_or_else(|| format!("unknown response {}", self.response))?;
+
+        if self.alignment()?.is_some() {
+            if response != Response::LowPass &&
+               response != Response::HighPass {
+                return Err(format!(
+                        "only low and high pass filters can be {}",
+                        self.alignment));
+            }
+
+            if self.order < 1 || self.order > MAX_ORDER {
+                return Err(format!(
+                        "order must be between 1 and {}", MAX_ORDER));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// One biquad, in transposed direct form II
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    coefficients: Coefficients,
+    z1: f32,
+    z2: f32,
+}
+
+impl Section {
+    fn process(&mut self, x: f32) -> f32
+    {
+        let c = &self.coefficients;
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y
+    }
+}
+
+#[derive(Debug)]
+pub struct Biquad<'a> {
+    config: BiquadConfig,
+    // an invalid config passes everything through
+    response: Option<Response>,
+    alignment: Option<Alignment>,
+    sections: Vec<Section>,
+    sample_rate: Option<f32>,
+    // the settings the coefficients were worked out for
+    designed: Option<(f32, f32, f32)>,
+
+    samples_in: Option<InputPortHandle<'a>>,
+    frequency_in: Option<InputPortHandle<'a>>,
+    q_in: Option<InputPortHandle<'a>>,
+    gain_in: Option<InputPortHandle<'a>>,
+    samples_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Biquad<'a> {
+    pub fn new(config: BiquadConfig) -> Self
+    {
+        let valid = config.validate().is_ok();
+        let response = Response::from_name(&config.response)
+            .filter(|_| valid);
+        let alignment = config.alignment().unwrap_or(None);
+
+        let count = match alignment {
+            Some(a) => filter_design::cascade_sections(a, config.order),
+            None => 1,
+        };
+
+        let section = Section {
+            coefficients: Coefficients::identity(),
+            z1: 0.0,
+            z2: 0.0,
+        };
+
+        Self {
+            config,
+            response,
+            alignment,
+            sections: vec![section; count],
+            sample_rate: None,
+            designed: None,
+            samples_in: None,
+            frequency_in: None,
+            q_in: None,
+            gain_in: None,
+            samples_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.samples_in.is_some()
+            && self.frequency_in.is_some()
+            && self.q_in.is_some()
+            && self.gain_in.is_some()
+            && self.samples_out.is_some()
+    }
+
+    /// Work out the coefficients, if any of the settings have changed
+    fn design(&mut self, freq: f32, q: f32, gain: f32)
+    {
+        if self.designed == Some((freq, q, gain)) {
+            return;
+        }
+
+        self.designed = Some((freq, q, gain));
+
+        let response = match self.response {
+            Some(r) => r,
+            None => return,
+        };
+
+        let rate = self.sample_rate.unwrap();
+        let order = self.config.order;
+        for (i, section) in self.sections.iter_mut().enumerate() {
+            section.coefficients = match self.alignment {
+                Some(a) => filter_design::cascade_section(
+                    response, a, order, i, freq, rate),
+                None => filter_design::design(response, freq, q, gain, rate),
+            };
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32
+    {
+        self.sections.iter_mut().fold(x, |x, s| s.process(x))
+    }
+}
+
+impl<'a> Component<'a> for Biquad<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "samples_in"))?);
+
+        self.frequency_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "frequency_in"))?);
+
+        self.q_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "q_in"))?);
+
+        self.gain_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "gain_in"))?);
+
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let octaves = ports.get_port_value(&self.frequency_in.unwrap());
+        let q = ports.get_port_value(&self.q_in.unwrap());
+        let gain = ports.get_port_value(&self.gain_in.unwrap());
+        self.design(
+            self.config.frequency * octaves.exp2(),
+            self.config.q + q,
+            self.config.gain + gain);
+
+        let x = ports.get_port_value(&self.samples_in.unwrap());
+        let y = self.process(x);
+        ports.set_port_value(&self.samples_out.unwrap(), y);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+
+            // redesigned for the new rate on the next frame
+            self.designed = None;
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ports::PortManagerImpl;
+
+    fn config(response: &str, alignment: &str, order: usize) -> BiquadConfig
+    {
+        BiquadConfig {
+            name: "eq".to_owned(),
+            response: response.to_owned(),
+            frequency: 1000.0,
+            q: 0.707,
+            gain: 0.0,
+            alignment: alignment.to_owned(),
+            order,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config("lowpass", "none", 0).validate().is_ok());
+        assert!(config("peaking", "none", 0).validate().is_ok());
+        assert!(config("highpass", "linkwitz-riley", 4).validate().is_ok());
+
+        assert!(config("lowpas", "none", 0).validate().is_err());
+        assert!(config("lowpass", "bessel", 2).validate().is_err());
+        assert!(config("peaking", "butterworth", 2).validate().is_err());
+        assert!(config("lowpass", "butterworth", 9).validate().is_err());
+    }
+
+    #[test]
+    fn test_redesigned_on_change() {
+        let mut ports = PortManagerImpl::new();
+        let mut filter = Biquad::new(config("lowpass", "butterworth", 4));
+        assert_eq!(filter.sections.len(), 2);
+
+        filter.initialize_ports(&mut ports).unwrap();
+        filter.handle_audio_property_change(
+            AudioProperties::SampleRate(48000.0));
+
+        let freq = ports.register_output_port(
+            &PortName::new("test", "frequency_out")).unwrap();
+        ports.connect(&freq, &filter.frequency_in.unwrap());
+
+        filter.generate(&mut ports);
+        let before = filter.sections[0].coefficients;
+        assert_eq!(before, filter_design::cascade_section(
+                Response::LowPass, Alignment::Butterworth, 4, 0,
+                1000.0, 48000.0));
+
+        // an octave up
+        ports.set_port_value(&freq, 1.0);
+        filter.generate(&mut ports);
+        let after = filter.sections[0].coefficients;
+        assert_eq!(filter.designed, Some((2000.0, 0.707, 0.0)));
+        assert!(after != before);
+
+        // and back down, at a new sample rate
+        ports.set_port_value(&freq, 0.0);
+        filter.handle_audio_property_change(
+            AudioProperties::SampleRate(96000.0));
+        filter.generate(&mut ports);
+        assert_eq!(filter.sections[0].coefficients,
+                   filter_design::cascade_section(
+                       Response::LowPass, Alignment::Butterworth, 4, 0,
+                       1000.0, 96000.0));
+    }
+
+    #[test]
+    fn test_invalid_passes_through() {
+        let mut filter = Biquad::new(config("lowpas", "none", 0));
+        filter.handle_audio_property_change(
+            AudioProperties::SampleRate(48000.0));
+        filter.design(1000.0, 0.707, 0.0);
+
+        for &x in [1.0, -0.5, 0.25].iter() {
+            assert_eq!(filter.process(x), x);
+        }
+    }
+}
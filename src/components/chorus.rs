@@ -0,0 +1,313 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use components::modulation::{Shape, Sweep, MAX_FEEDBACK};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+use util::delay_line::DelayLine;
+
+// longest delay the sweep is centered on. The lines have room for twice this,
+// so the sweep never runs out
+const MAX_DELAY: f32 = 0.05;
+
+/// A chorus or flanger: the input mixed with copies of itself, through delays
+/// which an LFO sweeps back and forth.
+/// The delays are centered on `delay` seconds (up to 50ms), and swing `depth`
+/// (0 to 1) of that either side, `rate` times a second, following `shape` (as
+/// for the LFO). Around 20ms makes a chorus, while a few milliseconds with
+/// some `feedback` (-1 to 1) makes a flanger.
+/// The left and right delays are swept `spread` (0 to 1) of half a cycle
+/// apart, and `mix` (0 to 1) goes from only the input to only the delays.
+/// left_out and right_out carry the stereo result, and samples_out has them
+/// mixed back down to mono.
+/// The other inputs move the settings along: rate_in by octaves, so 1 sweeps
+/// twice as fast, and depth_in, feedback_in and mix_in by adding to them
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct ChorusConfig {
+    pub name: String,
+    pub shape: String,
+    pub rate: f32,
+    pub depth: f32,
+    pub delay: f32,
+    pub feedback: f32,
+    pub spread: f32,
+    pub mix: f32,
+}
+
+impl ComponentConfig for ChorusConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Chorus::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+
+    fn validate(&self) -> Result<(), String>
+    {
+        Shape::from_name(&self.shape)
+            .ok_or_else(|| format!("unknown shape {}", self.shape))?;
+
+        if self.delay <= 0.0 || self.delay > MAX_DELAY {
+            return Err(format!("delay must be between 0 and {}", MAX_DELAY));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Chorus<'a> {
+    config: ChorusConfig,
+    // None if the shape has no name we know, and then the chorus is bypassed
+    sweep: Option<Sweep>,
+    sample_rate: Option<f32>,
+    // left and right
+    lines: [DelayLine; 2],
+
+    samples_in: Option<InputPortHandle<'a>>,
+    rate_in: Option<InputPortHandle<'a>>,
+    depth_in: Option<InputPortHandle<'a>>,
+    feedback_in: Option<InputPortHandle<'a>>,
+    mix_in: Option<InputPortHandle<'a>>,
+    samples_out: Option<OutputPortHandle<'a>>,
+    left_out: Option<OutputPortHandle<'a>>,
+    right_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Chorus<'a> {
+    pub fn new(config: ChorusConfig) -> Self
+    {
+        let sweep = Shape::from_name(&config.shape).map(Sweep::new);
+
+        Self {
+            config,
+            sweep,
+            sample_rate: None,
+            lines: [DelayLine::new(), DelayLine::new()],
+            samples_in: None,
+            rate_in: None,
+            depth_in: None,
+            feedback_in: None,
+            mix_in: None,
+            samples_out: None,
+            left_out: None,
+            right_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.samples_in.is_some()
+            && self.rate_in.is_some()
+            && self.depth_in.is_some()
+            && self.feedback_in.is_some()
+            && self.mix_in.is_some()
+            && self.samples_out.is_some()
+            && self.left_out.is_some()
+            && self.right_out.is_some()
+    }
+
+    /// The left and right output for one frame of input
+    fn process(
+        &mut self,
+        x: f32,
+        octaves: f32,
+        depth: f32,
+        feedback: f32,
+        mix: f32)
+        -> [f32; 2]
+    {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return [x, x],
+        };
+
+        let rate = self.sample_rate.unwrap();
+        let depth = depth.max(0.0).min(1.0);
+        let feedback = feedback.max(-MAX_FEEDBACK).min(MAX_FEEDBACK);
+        let mix = mix.max(0.0).min(1.0);
+        let spread = self.config.spread.max(0.0).min(1.0);
+        let offsets = [0.0, 0.5 * spread];
+
+        let mut out = [0.0; 2];
+        for (c, line) in self.lines.iter_mut().enumerate() {
+            let swing = depth * sweep.value(offsets[c]);
+            let time = self.config.delay * (1.0 + swing);
+            let delayed = line.read(time * rate);
+            line.push(x + feedback * delayed);
+            out[c] = (1.0 - mix) * x + mix * delayed;
+        }
+
+        sweep.advance(self.config.rate * octaves.exp2() / rate);
+        out
+    }
+}
+
+impl<'a> Component<'a> for Chorus<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "samples_in"))?);
+
+        self.rate_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "rate_in"))?);
+
+        self.depth_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "depth_in"))?);
+
+        self.feedback_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "feedback_in"))?);
+
+        self.mix_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "mix_in"))?);
+
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        self.left_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "left_out"))?);
+
+        self.right_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "right_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let x = ports.get_port_value(&self.samples_in.unwrap());
+        let octaves = ports.get_port_value(&self.rate_in.unwrap());
+        let depth = ports.get_port_value(&self.depth_in.unwrap());
+        let feedback = ports.get_port_value(&self.feedback_in.unwrap());
+        let mix = ports.get_port_value(&self.mix_in.unwrap());
+
+        let [left, right] = self.process(
+            x,
+            octaves,
+            self.config.depth + depth,
+            self.config.feedback + feedback,
+            self.config.mix + mix);
+
+        ports.set_port_value(&self.samples_out.unwrap(), 0.5 * (left + right));
+        ports.set_port_value(&self.left_out.unwrap(), left);
+        ports.set_port_value(&self.right_out.unwrap(), right);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+
+            let frames = (2.0 * MAX_DELAY * r).ceil() as usize;
+            for line in self.lines.iter_mut() {
+                line.reserve(frames);
+                line.clear();
+            }
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 5ms chorus, running at a frame every millisecond
+    fn chorus(depth: f32, feedback: f32, spread: f32) -> Chorus<'static>
+    {
+        build(ChorusConfig {
+            name: "chorus".to_owned(),
+            shape: "sine".to_owned(),
+            rate: 10.0,
+            depth,
+            delay: 0.005,
+            feedback,
+            spread,
+            mix: 1.0,
+        })
+    }
+
+    fn build(config: ChorusConfig) -> Chorus<'static>
+    {
+        let mut chorus = Chorus::new(config);
+        chorus.handle_audio_property_change(
+            AudioProperties::SampleRate(1000.0));
+        chorus
+    }
+
+    /// The response to a single click, with the chorus' own settings
+    fn impulse(chorus: &mut Chorus, frames: usize) -> Vec<[f32; 2]>
+    {
+        let (depth, feedback) = (chorus.config.depth, chorus.config.feedback);
+        (0..frames)
+            .map(|i| {
+                let x = if i == 0 { 1.0 } else { 0.0 };
+                chorus.process(x, 0.0, depth, feedback, 1.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bypassed_when_invalid() {
+        let mut config = chorus(0.5, 0.0, 0.0).config;
+        config.delay = 0.1;
+        assert!(config.validate().is_err());
+
+        // an unknown shape can't be swept, so the input comes out untouched
+        config.delay = 0.005;
+        config.shape = "wobbly".to_owned();
+        assert!(config.validate().is_err());
+        assert_eq!(build(config).process(0.5, 0.0, 0.5, 0.0, 1.0), [0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_flanger_feedback() {
+        // with no sweep, the click echoes every 5 frames, flipping each time
+        let out = impulse(&mut chorus(0.0, -0.5, 0.0), 16);
+        assert_eq!(out[5], [1.0, 1.0]);
+        assert_eq!(out[10], [-0.5, -0.5]);
+        assert_eq!(out[15], [0.25, 0.25]);
+
+        let echoes = out.iter().filter(|f| f[0] != 0.0).count();
+        assert_eq!(echoes, 3);
+    }
+
+    #[test]
+    fn test_sweep_and_spread() {
+        // a steady tone comes out wobbling, as the delay changes
+        let tone = |chorus: &mut Chorus| -> Vec<[f32; 2]> {
+            (0..200)
+                .map(|i| {
+                    let x = (i as f32 * 0.3).sin();
+                    chorus.process(x, 0.0, 0.8, 0.0, 1.0)
+                })
+                .collect()
+        };
+
+        // in step without any spread, apart with it
+        let together = tone(&mut chorus(0.8, 0.0, 0.0));
+        assert!(together.iter().all(|f| f[0] == f[1]));
+
+        // the sweeps only cross twice a cycle
+        let apart = tone(&mut chorus(0.8, 0.0, 1.0));
+        assert!(apart[20..].iter().filter(|f| f[0] != f[1]).count() > 150);
+
+        // the left side didn't move
+        let left = |out: &[[f32; 2]]| {
+            out.iter().map(|f| f[0]).collect::<Vec<_>>()
+        };
+
+        assert_eq!(left(&together), left(&apart));
+    }
+}
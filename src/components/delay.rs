@@ -0,0 +1,308 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+use transport::{self, BeatClock};
+use util::delay_line::DelayLine;
+
+// with all of the echoes fed back, they would never die away
+const MAX_FEEDBACK: f32 = 0.99;
+
+// slowest tempo a synced delay keeps room for. Anything slower is cut short
+const MIN_TEMPO: f32 = 30.0;
+
+/// An echo. The delayed signal is fed back into the delay by `feedback` (0 to
+/// 1), and `mix` (0 to 1) goes from only the input to only the echoes.
+/// With `sync` set to "none", the delay is `time` seconds long, otherwise it
+/// is a note division (like "1/8d") at the current tempo.
+/// time_in is added to the delay time in seconds, and can move smoothly
+/// between samples, and feedback_in and mix_in are added to the feedback and
+/// the mix. Room is kept for delays of up to `max_time` seconds (or the synced
+/// time at 30 beats per minute, if that's longer), and longer delays are cut
+/// short
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct DelayConfig {
+    pub name: String,
+    pub time: f32,
+    pub sync: String,
+    pub feedback: f32,
+    pub mix: f32,
+    pub max_time: f32,
+}
+
+impl DelayConfig {
+    fn sync(&self) -> Result<Option<f64>, String>
+    {
+        match self.sync.as_str() {
+            "none" => Ok(None),
+            name => transport::division_beats(name)
+                .map(Some)
+                .ok_or_else(|| format!("unknown note division {}", name)),
+        }
+    }
+}
+
+impl ComponentConfig for DelayConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Delay::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+
+    fn validate(&self) -> Result<(), String>
+    {
+        self.sync()?;
+
+        if self.max_time <= 0.0 {
+            return Err("max_time must be more than zero".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Delay<'a> {
+    config: DelayConfig,
+    // length of the delay in beats, when synced to the tempo
+    beats: Option<f64>,
+    clock: BeatClock,
+    sample_rate: Option<f32>,
+    line: DelayLine,
+
+    samples_in: Option<InputPortHandle<'a>>,
+    time_in: Option<InputPortHandle<'a>>,
+    feedback_in: Option<InputPortHandle<'a>>,
+    mix_in: Option<InputPortHandle<'a>>,
+    samples_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Delay<'a> {
+    pub fn new(config: DelayConfig) -> Self
+    {
+        let beats = config.sync().unwrap_or(None);
+
+        Self {
+            config,
+            beats,
+            clock: BeatClock::new(),
+            sample_rate: None,
+            line: DelayLine::new(),
+            samples_in: None,
+            time_in: None,
+            feedback_in: None,
+            mix_in: None,
+            samples_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.samples_in.is_some()
+            && self.time_in.is_some()
+            && self.feedback_in.is_some()
+            && self.mix_in.is_some()
+            && self.samples_out.is_some()
+    }
+
+    /// Length of the delay in seconds, before any modulation
+    fn time(&self) -> f32
+    {
+        match self.beats {
+            Some(beats) => 60.0 * beats as f32 / self.clock.tempo(),
+            None => self.config.time,
+        }
+    }
+
+    /// Longest delay to keep room for, in seconds. Reserved once, with the
+    /// sample rate and before the audio thread runs the delay, so a tempo
+    /// change never has to allocate
+    fn max_time(&self) -> f32
+    {
+        let synced = self.beats.map_or(0.0, |b| 60.0 * b as f32 / MIN_TEMPO);
+        self.config.max_time.max(synced)
+    }
+
+    fn process(&mut self, x: f32, time: f32, feedback: f32, mix: f32) -> f32
+    {
+        let frames = time * self.sample_rate.unwrap();
+        let delayed = self.line.read(frames);
+
+        let feedback = feedback.max(0.0).min(MAX_FEEDBACK);
+        self.line.push(x + feedback * delayed);
+
+        let mix = mix.max(0.0).min(1.0);
+        (1.0 - mix) * x + mix * delayed
+    }
+}
+
+impl<'a> Component<'a> for Delay<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "samples_in"))?);
+
+        self.time_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "time_in"))?);
+
+        self.feedback_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "feedback_in"))?);
+
+        self.mix_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "mix_in"))?);
+
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let x = ports.get_port_value(&self.samples_in.unwrap());
+        let time = ports.get_port_value(&self.time_in.unwrap());
+        let feedback = ports.get_port_value(&self.feedback_in.unwrap());
+        let mix = ports.get_port_value(&self.mix_in.unwrap());
+
+        let y = self.process(
+            x,
+            self.time() + time,
+            self.config.feedback + feedback,
+            self.config.mix + mix);
+
+        ports.set_port_value(&self.samples_out.unwrap(), y);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        self.clock.handle_audio_property_change(prop);
+
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+            let frames = (self.max_time() * r).ceil() as usize;
+            self.line.reserve(frames);
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A delay running at a frame every millisecond, with room for half a
+    /// second
+    fn delay(time: f32, sync: &str, feedback: f32, mix: f32) -> Delay<'static>
+    {
+        let mut delay = Delay::new(DelayConfig {
+            name: "delay".to_owned(),
+            time,
+            sync: sync.to_owned(),
+            feedback,
+            mix,
+            max_time: 0.5,
+        });
+
+        delay.handle_audio_property_change(
+            AudioProperties::SampleRate(1000.0));
+        delay
+    }
+
+    /// The response to a single click, with the delay's own time
+    fn impulse(delay: &mut Delay, frames: usize) -> Vec<f32>
+    {
+        let (feedback, mix) = (delay.config.feedback, delay.config.mix);
+        (0..frames)
+            .map(|i| {
+                let x = if i == 0 { 1.0 } else { 0.0 };
+                let time = delay.time();
+                delay.process(x, time, feedback, mix)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_echoes() {
+        let out = impulse(&mut delay(0.1, "none", 0.5, 0.5), 350);
+
+        // the click, then echoes every 100 frames, half as loud each time
+        assert_eq!(out[0], 0.5);
+        assert_eq!(out[100], 0.5);
+        assert_eq!(out[200], 0.25);
+        assert_eq!(out[300], 0.125);
+
+        let echoes = out.iter().filter(|&&x| x != 0.0).count();
+        assert_eq!(echoes, 4);
+    }
+
+    #[test]
+    fn test_fractional_time() {
+        let mut delay = delay(0.0, "none", 0.0, 1.0);
+        let out: Vec<f32> = (0..4)
+            .map(|i| {
+                let x = if i == 0 { 1.0 } else { 0.0 };
+                delay.process(x, 0.0015, 0.0, 1.0)
+            })
+            .collect();
+
+        // a frame and a half, split between the frames either side
+        assert_eq!(out, vec![0.0, 0.5, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut delay = delay(0.0, "1/8", 0.0, 1.0);
+        delay.handle_audio_property_change(AudioProperties::Tempo(120.0));
+        assert_eq!(delay.time(), 0.25);
+
+        let out = impulse(&mut delay, 300);
+        assert_eq!(out[250], 1.0);
+
+        // there was already room for the slowest tempo, longer than max_time
+        let capacity = delay.line.capacity();
+        assert!(capacity >= 1000);
+
+        delay.handle_audio_property_change(AudioProperties::Tempo(30.0));
+        assert_eq!(delay.time(), 1.0);
+        assert_eq!(delay.line.capacity(), capacity);
+
+        let out = impulse(&mut delay, 1100);
+        assert_eq!(out[1000], 1.0);
+
+        // slower still, and the delay is cut short
+        delay.handle_audio_property_change(AudioProperties::Tempo(15.0));
+        let out = impulse(&mut delay, 2100);
+        assert_eq!(out[capacity], 1.0);
+    }
+
+    #[test]
+    fn test_tempo_change_keeps_echoes() {
+        let mut delay = delay(0.0, "1/8", 0.0, 1.0);
+        delay.handle_audio_property_change(AudioProperties::Tempo(120.0));
+        delay.process(1.0, 0.25, 0.0, 1.0);
+
+        // a tempo change mid echo moves it, rather than losing it
+        delay.handle_audio_property_change(AudioProperties::Tempo(60.0));
+        let out: Vec<f32> = (1..600)
+            .map(|_| {
+                let time = delay.time();
+                delay.process(0.0, time, 0.0, 1.0)
+            })
+            .collect();
+
+        assert_eq!(out[499], 1.0);
+    }
+}
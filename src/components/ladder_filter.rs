@@ -0,0 +1,309 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+
+use std::f32;
+
+/// A Moog style 24dB per octave low pass, which self oscillates at a
+/// `resonance` of 1. `drive` pushes its saturating stages into growling, and
+/// `oversample` doubles the cost to keep them from aliasing. Each setting has
+/// a matching input, with the cutoff's counted in octaves
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct LadderFilterConfig {
+    pub name: String,
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub drive: f32,
+    pub oversample: bool,
+}
+
+impl ComponentConfig for LadderFilterConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(LadderFilter::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+}
+
+// feedback when the resonance is 1
+const MAX_FEEDBACK: f32 = 4.0;
+
+#[derive(Debug)]
+pub struct LadderFilter<'a> {
+    config: LadderFilterConfig,
+    sample_rate: Option<f32>,
+
+    // the cutoff the coefficient was worked out for
+    cutoff: f32,
+    // coefficient of each one pole stage
+    g: f32,
+    // output of each stage
+    stages: [f32; 4],
+    // the last input, to interpolate between when oversampling
+    last: f32,
+
+    samples_in: Option<InputPortHandle<'a>>,
+    cutoff_in: Option<InputPortHandle<'a>>,
+    resonance_in: Option<InputPortHandle<'a>>,
+    drive_in: Option<InputPortHandle<'a>>,
+    samples_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> LadderFilter<'a> {
+    pub fn new(config: LadderFilterConfig) -> Self
+    {
+        Self {
+            config,
+            sample_rate: None,
+            cutoff: f32::NAN,
+            g: 0.0,
+            stages: [0.0; 4],
+            last: 0.0,
+            samples_in: None,
+            cutoff_in: None,
+            resonance_in: None,
+            drive_in: None,
+            samples_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.samples_in.is_some()
+            && self.cutoff_in.is_some()
+            && self.resonance_in.is_some()
+            && self.drive_in.is_some()
+            && self.samples_out.is_some()
+    }
+
+    /// Work out the coefficient, if the cutoff has changed
+    fn tune(&mut self, cutoff: f32)
+    {
+        if cutoff == self.cutoff {
+            return;
+        }
+
+        self.cutoff = cutoff;
+
+        let mut rate = self.sample_rate.unwrap();
+        if self.config.oversample {
+            rate *= 2.0;
+        }
+
+        let cutoff = cutoff.max(1.0).min(rate * 0.45);
+        self.g = 1.0 - (-2.0 * f32::consts::PI * cutoff / rate).exp();
+    }
+
+    /// Run the ladder for one (possibly oversampled) step
+    fn step(&mut self, x: f32, feedback: f32, drive: f32) -> f32
+    {
+        let mut input = (drive * x - feedback * self.stages[3]).tanh();
+        for stage in self.stages.iter_mut() {
+            *stage += self.g * (input - stage.tanh());
+            input = stage.tanh();
+        }
+
+        self.stages[3]
+    }
+
+    fn process(&mut self, x: f32, resonance: f32, drive: f32) -> f32
+    {
+        let feedback = MAX_FEEDBACK * resonance.max(0.0).min(1.0);
+        let drive = drive.max(0.0);
+
+        let y = if self.config.oversample {
+            // step through the point half way between the samples as well,
+            // and average the two outputs on the way back down
+            let mid = 0.5 * (self.last + x);
+            let y = self.step(mid, feedback, drive);
+            0.5 * (y + self.step(x, feedback, drive))
+        } else {
+            self.step(x, feedback, drive)
+        };
+
+        self.last = x;
+        y
+    }
+}
+
+impl<'a> Component<'a> for LadderFilter<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "samples_in"))?);
+
+        self.cutoff_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "cutoff_in"))?);
+
+        self.resonance_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "resonance_in"))?);
+
+        self.drive_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "drive_in"))?);
+
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let octaves = ports.get_port_value(&self.cutoff_in.unwrap());
+        self.tune(self.config.cutoff * octaves.exp2());
+
+        let resonance = ports.get_port_value(&self.resonance_in.unwrap());
+        let drive = ports.get_port_value(&self.drive_in.unwrap());
+        let x = ports.get_port_value(&self.samples_in.unwrap());
+        let y = self.process(
+            x,
+            self.config.resonance + resonance,
+            self.config.drive + drive);
+
+        ports.set_port_value(&self.samples_out.unwrap(), y);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+
+            // forces tune() to run again at the new rate
+            self.cutoff = f32::NAN;
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ports::PortManagerImpl;
+
+    const RATE: f32 = 48000.0;
+
+    fn filter(cutoff: f32, resonance: f32, oversample: bool)
+        -> LadderFilter<'static>
+    {
+        let mut filter = LadderFilter::new(LadderFilterConfig {
+            name: "ladder".to_owned(),
+            cutoff,
+            resonance,
+            drive: 1.0,
+            oversample,
+        });
+
+        filter.handle_audio_property_change(AudioProperties::SampleRate(RATE));
+        filter.tune(cutoff);
+        filter
+    }
+
+    /// Peak output once a sine wave at `freq` has settled
+    fn amplitude(
+        filter: &mut LadderFilter,
+        freq: f32,
+        level: f32,
+        drive: f32)
+        -> f32
+    {
+        let resonance = filter.config.resonance;
+        let mut peak: f32 = 0.0;
+        for i in 0..9600 {
+            let t = i as f32 / RATE;
+            let x = level * (2.0 * f32::consts::PI * freq * t).sin();
+            let y = filter.process(x, resonance, drive);
+            if i >= 4800 {
+                peak = peak.max(y.abs());
+            }
+        }
+
+        peak
+    }
+
+    #[test]
+    fn test_low_pass() {
+        for &oversample in [false, true].iter() {
+            // quiet enough to stay out of the saturation
+            let pass = amplitude(&mut filter(1000.0, 0.0, oversample),
+                                 50.0, 0.01, 1.0);
+            assert!((pass - 0.01).abs() < 0.001);
+
+            // 24dB per octave, so about 1/4096 three octaves up
+            let stop = amplitude(&mut filter(1000.0, 0.0, oversample),
+                                 8000.0, 0.01, 1.0);
+            assert!(stop < 0.01 / 1000.0);
+        }
+    }
+
+    #[test]
+    fn test_resonance() {
+        for &oversample in [false, true].iter() {
+            let flat = amplitude(&mut filter(1000.0, 0.0, oversample),
+                                 1000.0, 0.01, 1.0);
+            let peaked = amplitude(&mut filter(1000.0, 0.9, oversample),
+                                   1000.0, 0.01, 1.0);
+            assert!(peaked > 2.0 * flat);
+
+            // and the feedback takes away from the bass
+            let bass = amplitude(&mut filter(1000.0, 0.9, oversample),
+                                 50.0, 0.01, 1.0);
+            assert!(bass < 0.01 / 2.0);
+        }
+    }
+
+    #[test]
+    fn test_drive_saturates() {
+        let mut f = filter(1000.0, 0.0, true);
+        let clean = amplitude(&mut f, 50.0, 0.01, 1.0);
+        let driven = amplitude(&mut f, 50.0, 0.01, 100.0);
+
+        // louder, but squashed flat by the stages
+        assert!(driven > 10.0 * clean);
+        assert!(driven < 1.0);
+    }
+
+    #[test]
+    fn test_cutoff_modulation() {
+        let mut ports = PortManagerImpl::new();
+        let mut filter = LadderFilter::new(LadderFilterConfig {
+            name: "ladder".to_owned(),
+            cutoff: 1000.0,
+            resonance: 0.5,
+            drive: 1.0,
+            oversample: false,
+        });
+
+        filter.initialize_ports(&mut ports).unwrap();
+        filter.handle_audio_property_change(AudioProperties::SampleRate(RATE));
+
+        let cutoff = ports.register_output_port(
+            &PortName::new("test", "cutoff_out")).unwrap();
+        ports.connect(&cutoff, &filter.cutoff_in.unwrap());
+
+        // one octave down
+        ports.set_port_value(&cutoff, -1.0);
+        filter.generate(&mut ports);
+        assert_eq!(filter.cutoff, 500.0);
+
+        // the sample rate changing means the coefficient has to be redone
+        let g = filter.g;
+        filter.handle_audio_property_change(
+            AudioProperties::SampleRate(RATE * 2.0));
+        filter.generate(&mut ports);
+        assert!(filter.g < g);
+    }
+}
@@ -0,0 +1,352 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use components::modulation::{self, Shape};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+use transport::{self, BeatClock};
+use util::rng::Rng;
+
+/// A low frequency oscillator, for modulating other components.
+/// `shape` is one of sine, triangle, saw, square or sample-and-hold.
+/// With `sync` set to "none", the LFO runs at `rate` Hz, otherwise it runs
+/// once every note division (like "1/4" or "1/8t") at the current tempo, and
+/// follows the transport while it is rolling.
+/// `phase` (0 to 1) offsets the start of the cycle. The output swings between
+/// -1 and 1, or between 0 and 1 when `unipolar` is set.
+/// With `retrigger` set, the cycle restarts whenever gate_in opens, so every
+/// voice has its own LFO. Otherwise the LFO ignores the gate and runs freely,
+/// in step across all of the voices.
+/// rate_in speeds the LFO up by that many octaves
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct LfoConfig {
+    pub name: String,
+    pub shape: String,
+    pub rate: f32,
+    pub sync: String,
+    pub phase: f32,
+    pub unipolar: bool,
+    pub retrigger: bool,
+}
+
+impl LfoConfig {
+    fn sync(&self) -> Result<Option<f64>, String>
+    {
+        match self.sync.as_str() {
+            "none" => Ok(None),
+            name => transport::division_beats(name)
+                .map(Some)
+                .ok_or_else(|| format!("unknown note division {}", name)),
+        }
+    }
+}
+
+impl ComponentConfig for LfoConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Lfo::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+
+    fn validate(&self) -> Result<(), String>
+    {
+        Shape::from_name(&self.shape)
+            .ok_or_else(|| format!("unknown shape {}", self.shape))?;
+
+        self.sync()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Lfo<'a> {
+    config: LfoConfig,
+    // None for an unknown shape, which holds the output at 0
+    shape: Option<Shape>,
+    // length of a cycle in beats, when synced to the tempo
+    beats: Option<f64>,
+    clock: BeatClock,
+    sample_rate: Option<f32>,
+
+    // how far through the cycle the LFO is, before the offset
+    phase: f64,
+    // the phase of the last frame, after the offset, to spot the cycle ending
+    last_phase: f32,
+    gate: bool,
+    // the value being held by sample and hold
+    held: f32,
+    // every voice starts with the same seed, so free running sample and hold
+    // stays in step
+    rng: Rng,
+
+    gate_in: Option<InputPortHandle<'a>>,
+    rate_in: Option<InputPortHandle<'a>>,
+    samples_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Lfo<'a> {
+    pub fn new(config: LfoConfig) -> Self
+    {
+        let shape = Shape::from_name(&config.shape);
+        let beats = config.sync().unwrap_or(None);
+        let mut rng = Rng::new(1);
+        let held = 2.0 * rng.next_f32() - 1.0;
+
+        Self {
+            config,
+            shape,
+            beats,
+            clock: BeatClock::new(),
+            sample_rate: None,
+            phase: 0.0,
+            last_phase: 0.0,
+            gate: false,
+            held,
+            rng,
+            gate_in: None,
+            rate_in: None,
+            samples_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.gate_in.is_some()
+            && self.rate_in.is_some()
+            && self.samples_out.is_some()
+    }
+
+    /// Cycles per second, before any modulation
+    fn frequency(&self) -> f32
+    {
+        match self.beats {
+            Some(beats) => self.clock.tempo() / (60.0 * beats as f32),
+            None => self.config.rate,
+        }
+    }
+
+    /// Produce the value for this frame, then move on to the next
+    fn step(&mut self, gate: bool, octaves: f32) -> f32
+    {
+        let retriggered = self.config.retrigger && gate && !self.gate;
+        self.gate = gate;
+        if retriggered {
+            self.phase = 0.0;
+        }
+
+        let speed = octaves.exp2();
+        let position = self.clock.tick();
+
+        // a free running, synced LFO is locked to the transport
+        let locked = !self.config.retrigger && self.clock.is_rolling();
+        let phase = match self.beats {
+            Some(beats) if locked => {
+                let cycle = beats / speed as f64;
+                modulation::wrap(position / cycle)
+            },
+            _ => self.phase,
+        };
+
+        let phase = modulation::wrap(phase as f32 + self.config.phase);
+        if retriggered || phase < self.last_phase {
+            self.held = 2.0 * self.rng.next_f32() - 1.0;
+        }
+
+        self.last_phase = phase;
+
+        let step = self.frequency() * speed / self.sample_rate.unwrap();
+        self.phase = modulation::wrap(self.phase + step as f64);
+
+        let value = match self.shape {
+            Some(shape) => shape.value(phase, self.held),
+            None => return 0.0,
+        };
+
+        if self.config.unipolar {
+            0.5 * (value + 1.0)
+        } else {
+            value
+        }
+    }
+}
+
+impl<'a> Component<'a> for Lfo<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.gate_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "gate_in"))?);
+
+        self.rate_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "rate_in"))?);
+
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let gate = ports.get_port_value(&self.gate_in.unwrap()) > 0.0;
+        let octaves = ports.get_port_value(&self.rate_in.unwrap());
+        let value = self.step(gate, octaves);
+        ports.set_port_value(&self.samples_out.unwrap(), value);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+        }
+
+        self.clock.handle_audio_property_change(prop);
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audioprops::TransportState;
+
+    const RATE: f32 = 100.0;
+
+    fn config(shape: &str, sync: &str) -> LfoConfig
+    {
+        LfoConfig {
+            name: "lfo".to_owned(),
+            shape: shape.to_owned(),
+            rate: 1.0,
+            sync: sync.to_owned(),
+            phase: 0.0,
+            unipolar: false,
+            retrigger: false,
+        }
+    }
+
+    fn lfo(config: LfoConfig) -> Lfo<'static>
+    {
+        let mut lfo = Lfo::new(config);
+        lfo.handle_audio_property_change(AudioProperties::SampleRate(RATE));
+        lfo
+    }
+
+    fn close(a: f32, b: f32) -> bool
+    {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config("sine", "none").validate().is_ok());
+        assert!(config("sample-and-hold", "1/8t").validate().is_ok());
+        assert!(config("sinus", "none").validate().is_err());
+        assert!(config("sine", "quarter").validate().is_err());
+    }
+
+    #[test]
+    fn test_shapes() {
+        let quarters = [0.0, 0.25, 0.5, 0.75];
+        let expect = [
+            (Shape::Sine, [0.0, 1.0, 0.0, -1.0]),
+            (Shape::Triangle, [0.0, 1.0, 0.0, -1.0]),
+            (Shape::Saw, [-1.0, -0.5, 0.0, 0.5]),
+            (Shape::Square, [1.0, 1.0, -1.0, -1.0]),
+            (Shape::SampleAndHold, [0.3, 0.3, 0.3, 0.3]),
+        ];
+
+        for &(shape, values) in expect.iter() {
+            for (&p, &v) in quarters.iter().zip(values.iter()) {
+                assert!(close(shape.value(p, 0.3), v), "{:?} {}", shape, p);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rate_and_offset() {
+        let mut c = config("saw", "none");
+        c.phase = 0.5;
+        c.unipolar = true;
+        let mut lfo = lfo(c);
+
+        // one cycle a second, starting half way through
+        assert!(close(lfo.step(false, 0.0), 0.5));
+        for _ in 1..25 {
+            lfo.step(false, 0.0);
+        }
+
+        assert!(close(lfo.step(false, 0.0), 0.75));
+
+        // an octave up moves twice as fast
+        lfo.step(false, 1.0);
+        assert!(close(lfo.step(false, 0.0), 0.78));
+    }
+
+    #[test]
+    fn test_retrigger() {
+        let mut c = config("saw", "none");
+        c.retrigger = true;
+        let mut lfo = lfo(c);
+
+        for _ in 0..30 {
+            lfo.step(false, 0.0);
+        }
+
+        // the gate opening restarts the cycle, holding it open doesn't
+        assert!(close(lfo.step(true, 0.0), -1.0));
+        assert!(close(lfo.step(true, 0.0), -0.98));
+    }
+
+    #[test]
+    fn test_free_running_voices_agree() {
+        let mut a = lfo(config("sample-and-hold", "none"));
+        let mut b = lfo(config("sample-and-hold", "none"));
+
+        let mut values = Vec::new();
+        for i in 0..300 {
+            // the voices play different notes, but they don't retrigger
+            let x = a.step(i % 7 == 0, 0.0);
+            let y = b.step(i % 50 < 25, 0.0);
+            assert_eq!(x, y);
+            values.push(x);
+        }
+
+        // a new value about once a second
+        assert_eq!(values[0], values[98]);
+        assert!(values[98] != values[102]);
+        assert!(values[102] != values[202]);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut lfo = lfo(config("saw", "1/2"));
+        lfo.handle_audio_property_change(AudioProperties::Tempo(60.0));
+
+        // stopped, so it runs on its own, once every two beats
+        assert_eq!(lfo.frequency(), 0.5);
+        for _ in 0..50 {
+            lfo.step(false, 0.0);
+        }
+
+        assert!(close(lfo.step(false, 0.0), -0.5));
+
+        // rolling, so it follows the transport
+        lfo.handle_audio_property_change(AudioProperties::SongPosition(1.0));
+        lfo.handle_audio_property_change(
+            AudioProperties::Transport(TransportState::Rolling));
+        assert!(close(lfo.step(false, 0.0), 0.0));
+        assert!(close(lfo.step(false, 0.0), 0.01));
+    }
+}
@@ -2,16 +2,40 @@ mod traits;
 pub use self::traits::*;
 
 // list of all the components, kept in alphabetical order
+mod biquad;
+mod chorus;
 mod combine;
+mod delay;
+mod ladder_filter;
+mod lfo;
 mod math;
+mod modulation;
+mod noise;
+mod note_out;
 mod onoff;
+mod pan;
+mod phaser;
+mod reverb;
 mod simple_low_pass;
 mod sine;
 mod square;
+mod state_variable_filter;
 
+pub use self::biquad::{Biquad, BiquadConfig};
+pub use self::chorus::{Chorus, ChorusConfig};
 pub use self::combine::CombineInputs;
+pub use self::delay::{Delay, DelayConfig};
+pub use self::ladder_filter::{LadderFilter, LadderFilterConfig};
+pub use self::lfo::{Lfo, LfoConfig};
 pub use self::math::Math;
+pub use self::noise::{Noise, NoiseConfig};
+pub use self::note_out::{NoteOut, NoteOutConfig};
 pub use self::onoff::{OnOff, OnOffConfig};
+pub use self::pan::{Pan, PanConfig};
+pub use self::phaser::{Phaser, PhaserConfig};
+pub use self::reverb::{Reverb, ReverbConfig};
 pub use self::simple_low_pass::{SimpleLowPass, SimpleLowPassConfig};
 pub use self::sine::{SineWaveOscillator, SineWaveOscillatorConfig};
 pub use self::square::{SquareWaveOscillator, SquareWaveOscillatorConfig};
+pub use self::state_variable_filter::{
+    StateVariableFilter, StateVariableFilterConfig};
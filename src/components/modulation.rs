@@ -0,0 +1,143 @@
+// Building blocks for the components which modulate themselves
+// The LFO component's shapes live here, along with a free running oscillator
+// built on them, which the chorus and the phaser sweep their delays and
+// filters with. The delays themselves are made from util::delay_line.
+
+use util::rng::Rng;
+
+use num::Float;
+
+use std::f32;
+
+/// Most of the output which the chorus and phaser feed back in, either way
+/// round. Any closer to 1, and the peaks the feedback makes ring on long after
+/// the sweep has moved past them
+pub const MAX_FEEDBACK: f32 = 0.95;
+
+/// Wrap a phase into [0, 1), counting backwards from 1 for negative phases
+pub fn wrap<T: Float>(phase: T) -> T
+{
+    let p = phase % T::one();
+    if p < T::zero() { p + T::one() } else { p }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleAndHold,
+}
+
+impl Shape {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "sine" => Some(Shape::Sine),
+            "triangle" => Some(Shape::Triangle),
+            "saw" => Some(Shape::Saw),
+            "square" => Some(Shape::Square),
+            "sample-and-hold" => Some(Shape::SampleAndHold),
+            _ => None,
+        }
+    }
+
+    /// The value of the shape at phase p, in [0, 1), given the value being
+    /// held by sample and hold
+    pub fn value(self, p: f32, held: f32) -> f32
+    {
+        match self {
+            Shape::Sine => (2.0 * f32::consts::PI * p).sin(),
+            Shape::Triangle => {
+                if p < 0.25 {
+                    4.0 * p
+                } else if p < 0.75 {
+                    2.0 - 4.0 * p
+                } else {
+                    4.0 * p - 4.0
+                }
+            },
+            Shape::Saw => 2.0 * p - 1.0,
+            Shape::Square => if p < 0.5 { 1.0 } else { -1.0 },
+            Shape::SampleAndHold => held,
+        }
+    }
+}
+
+/// A free running LFO with no ports, which can be read at several points in
+/// its cycle at once. Every sweep starts at the same place with the same seed,
+/// so the copies of an effect in each voice stay in step
+#[derive(Debug, Clone)]
+pub struct Sweep {
+    shape: Shape,
+    phase: f32,
+    held: f32,
+    rng: Rng,
+}
+
+impl Sweep {
+    pub fn new(shape: Shape) -> Self
+    {
+        let mut rng = Rng::new(1);
+        let held = 2.0 * rng.next_f32() - 1.0;
+
+        Self {
+            shape,
+            phase: 0.0,
+            held,
+            rng,
+        }
+    }
+
+    /// Move on by `step`, as a fraction of a cycle
+    pub fn advance(&mut self, step: f32)
+    {
+        self.phase += step.max(0.0);
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.held = 2.0 * self.rng.next_f32() - 1.0;
+        }
+    }
+
+    /// The value, from -1 to 1, `offset` of a cycle ahead of where the sweep
+    /// is now
+    pub fn value(&self, offset: f32) -> f32
+    {
+        let p = wrap(self.phase + offset);
+        self.shape.value(p, self.held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap(0.25f32), 0.25);
+        assert_eq!(wrap(1.25f32), 0.25);
+        assert_eq!(wrap(-0.25f32), 0.75);
+        assert_eq!(wrap(-2.0f64), 0.0);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut sweep = Sweep::new(Shape::Triangle);
+        assert_eq!(sweep.value(0.0), 0.0);
+        assert_eq!(sweep.value(0.25), 1.0);
+        assert_eq!(sweep.value(-0.25), -1.0);
+
+        sweep.advance(0.25);
+        assert_eq!(sweep.value(0.0), 1.0);
+
+        // wrapping around picks a new value to hold
+        let mut sweep = Sweep::new(Shape::SampleAndHold);
+        let held = sweep.value(0.0);
+        sweep.advance(0.75);
+        assert_eq!(sweep.value(0.5), held);
+        sweep.advance(0.5);
+        assert!(sweep.value(0.0) != held);
+        assert_eq!(sweep.value(0.0), sweep.value(0.9));
+    }
+}
@@ -0,0 +1,225 @@
+use components::{Component, ComponentConfig};
+use ports::{OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+use util::rng::Rng;
+
+// number of generators summed to make pink noise, each one changing half as
+// often as the last
+const PINK_ROWS: usize = 16;
+
+// how much of the last sample brown noise keeps, and how loud each step is
+const BROWN_LEAK: f32 = 1.0 / 1.02;
+const BROWN_STEP: f32 = 0.02;
+const BROWN_GAIN: f32 = 3.5;
+
+/// A noise source. `color` is white, pink (3dB quieter every octave) or brown
+/// (6dB quieter every octave), and `seed` picks the sequence of random
+/// numbers, so the same seed always makes the same noise
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct NoiseConfig {
+    pub name: String,
+    pub color: String,
+    pub seed: u32,
+}
+
+impl ComponentConfig for NoiseConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Noise::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+
+    fn validate(&self) -> Result<(), String>
+    {
+        Color::from_name(&self.color)
+            .map(|_| ())
+            .ok_or_else(|| format!("unknown noise color {}", self.color))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Color {
+    White,
+    Pink,
+    Brown,
+}
+
+impl Color {
+    fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "white" => Some(Color::White),
+            "pink" => Some(Color::Pink),
+            "brown" => Some(Color::Brown),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Noise<'a> {
+    config: NoiseConfig,
+    // nothing is generated for an unknown color
+    color: Option<Color>,
+    rng: Rng,
+
+    // pink noise, using the Voss-McCartney algorithm
+    rows: [f32; PINK_ROWS],
+    rows_sum: f32,
+    counter: u32,
+
+    // brown noise
+    last: f32,
+
+    samples_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Noise<'a> {
+    pub fn new(config: NoiseConfig) -> Self
+    {
+        let color = Color::from_name(&config.color);
+        let rng = Rng::new(config.seed);
+
+        Self {
+            config,
+            color,
+            rng,
+            rows: [0.0; PINK_ROWS],
+            rows_sum: 0.0,
+            counter: 0,
+            last: 0.0,
+            samples_out: None,
+        }
+    }
+
+    fn white(&mut self) -> f32
+    {
+        2.0 * self.rng.next_f32() - 1.0
+    }
+
+    fn pink(&mut self) -> f32
+    {
+        // row n changes every 2^n samples, on the samples where the counter
+        // has n trailing zeros
+        self.counter = self.counter.wrapping_add(1);
+        let row = self.counter.trailing_zeros() as usize;
+        if row < PINK_ROWS {
+            let value = self.white();
+            self.rows_sum += value - self.rows[row];
+            self.rows[row] = value;
+        }
+
+        let white = self.white();
+        (self.rows_sum + white) / (PINK_ROWS + 1) as f32
+    }
+
+    fn brown(&mut self) -> f32
+    {
+        let white = self.white();
+        self.last = (self.last + BROWN_STEP * white) * BROWN_LEAK;
+        (self.last * BROWN_GAIN).max(-1.0).min(1.0)
+    }
+
+    fn next(&mut self) -> f32
+    {
+        match self.color {
+            Some(Color::White) => self.white(),
+            Some(Color::Pink) => self.pink(),
+            Some(Color::Brown) => self.brown(),
+            None => 0.0,
+        }
+    }
+}
+
+impl<'a> Component<'a> for Noise<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if let Some(port) = self.samples_out {
+            let value = self.next();
+            ports.set_port_value(&port, value);
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: usize = 48000;
+
+    fn noise(color: &str, seed: u32) -> Vec<f32>
+    {
+        let mut noise = Noise::new(NoiseConfig {
+            name: "noise".to_owned(),
+            color: color.to_owned(),
+            seed,
+        });
+
+        (0..SAMPLES).map(|_| noise.next()).collect()
+    }
+
+    /// How alike neighbouring samples are, from -1 to 1. Noise with more low
+    /// frequencies changes more slowly
+    fn correlation(samples: &[f32]) -> f32
+    {
+        let energy: f32 = samples.iter().map(|x| x * x).sum();
+        let neighbours: f32 = samples.windows(2).map(|w| w[0] * w[1]).sum();
+        neighbours / energy
+    }
+
+    #[test]
+    fn test_seeded() {
+        for &color in ["white", "pink", "brown"].iter() {
+            assert_eq!(noise(color, 7), noise(color, 7));
+            assert!(noise(color, 7) != noise(color, 8));
+        }
+    }
+
+    #[test]
+    fn test_colors() {
+        let white = noise("white", 1);
+        let pink = noise("pink", 1);
+        let brown = noise("brown", 1);
+
+        for samples in [&white, &pink, &brown].iter() {
+            assert!(samples.iter().all(|x| x.abs() <= 1.0));
+
+            let mean = samples.iter().sum::<f32>() / SAMPLES as f32;
+            assert!(mean.abs() < 0.1);
+        }
+
+        assert!(correlation(&white).abs() < 0.05);
+        assert!(correlation(&pink) > 0.5);
+        assert!(correlation(&brown) > 0.95);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = NoiseConfig {
+            name: "noise".to_owned(),
+            color: "pink".to_owned(),
+            seed: 0,
+        };
+
+        assert!(config.validate().is_ok());
+        config.color = "purple".to_owned();
+        assert!(config.validate().is_err());
+    }
+}
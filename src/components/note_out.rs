@@ -0,0 +1,149 @@
+use components::{Component, ComponentConfig};
+use ports::{InputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+
+/// Sends a MIDI note out of the synth whenever the gate opens, for as long as
+/// the gate is open. Connected to the voice, this echoes the notes being
+/// played. The channel is counted from 1
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct NoteOutConfig {
+    pub name: String,
+    pub channel: usize,
+}
+
+impl ComponentConfig for NoteOutConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(NoteOut::new(self.name.clone(), self.channel))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct NoteOut<'a> {
+    name: String,
+    // status byte channel, counted from 0
+    channel: u8,
+    gate_in: Option<InputPortHandle<'a>>,
+    frequency_in: Option<InputPortHandle<'a>>,
+    velocity_in: Option<InputPortHandle<'a>>,
+    // the note which is currently on
+    playing: Option<u8>,
+}
+
+/// Nearest MIDI note to a frequency
+fn frequency_to_note(freq: f32) -> u8
+{
+    let note = 69.0 + 12.0 * (freq / 440.0).log2();
+    if note.is_nan() || note < 0.0 {
+        0
+    } else if note > 127.0 {
+        127
+    } else {
+        note.round() as u8
+    }
+}
+
+/// The voice's velocity (a MIDI velocity over 127) back as a MIDI velocity. A
+/// note on with no velocity is a note off, so this is never zero
+fn velocity_to_midi_velocity(vel: f32) -> u8
+{
+    let v = (vel * 127.0).round();
+    if v < 1.0 { 1 } else if v > 127.0 { 127 } else { v as u8 }
+}
+
+impl<'a> NoteOut<'a> {
+    pub fn new(name: String, channel: usize) -> Self
+    {
+        // channels outside of 1-16 wrap around
+        let channel = (channel.saturating_sub(1) % 16) as u8;
+
+        Self {
+            name,
+            channel,
+            gate_in: None,
+            frequency_in: None,
+            velocity_in: None,
+            playing: None,
+        }
+    }
+}
+
+impl<'a> Component<'a> for NoteOut<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.gate_in = Some(ports.register_input_port(
+                &PortName::new(&self.name, "gate_in"))?);
+
+        self.frequency_in = Some(ports.register_input_port(
+                &PortName::new(&self.name, "frequency_in"))?);
+
+        self.velocity_in = Some(ports.register_input_port(
+                &PortName::new(&self.name, "velocity_in"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if self.gate_in.is_none() || self.frequency_in.is_none() ||
+           self.velocity_in.is_none() {
+            return;
+        }
+
+        let gate = ports.get_port_value(&self.gate_in.unwrap()) != 0.0;
+        match (gate, self.playing) {
+            (true, None) => {
+                let freq = ports.get_port_value(&self.frequency_in.unwrap());
+                let vel = ports.get_port_value(&self.velocity_in.unwrap());
+                let note = frequency_to_note(freq);
+                let vel = velocity_to_midi_velocity(vel);
+
+                ports.send_midi(&[0x90 | self.channel, note, vel]);
+                self.playing = Some(note);
+            },
+
+            (false, Some(note)) => {
+                ports.send_midi(&[0x80 | self.channel, note, 0]);
+                self.playing = None;
+            },
+
+            _ => (),
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use session::midi_velocity_to_velocity;
+
+    #[test]
+    fn test_frequency_to_note() {
+        assert_eq!(frequency_to_note(440.0), 69);
+        assert_eq!(frequency_to_note(261.63), 60);
+        assert_eq!(frequency_to_note(0.0), 0);
+        assert_eq!(frequency_to_note(100000.0), 127);
+    }
+
+    #[test]
+    fn test_velocity_round_trip() {
+        // through the voice and back out again
+        for v in 1..128 {
+            let vel = midi_velocity_to_velocity(v);
+            assert_eq!(velocity_to_midi_velocity(vel), v);
+        }
+
+        assert_eq!(velocity_to_midi_velocity(0.0), 1);
+        assert_eq!(velocity_to_midi_velocity(2.0), 127);
+    }
+}
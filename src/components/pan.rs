@@ -0,0 +1,115 @@
+use components::{Component, ComponentConfig};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+
+use std::f32;
+
+/// Places a mono signal in the stereo field.
+/// pan_in ranges from -1 (hard left) to 1 (hard right). Connecting
+/// ("voice" "unison_pan_out") to pan_in spreads a unison stack across the
+/// stereo field.
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct PanConfig {
+    pub name: String,
+}
+
+impl ComponentConfig for PanConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Pan::new(self.name.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct Pan<'a> {
+    name: String,
+    samples_in: Option<InputPortHandle<'a>>,
+    pan_in: Option<InputPortHandle<'a>>,
+    left_out: Option<OutputPortHandle<'a>>,
+    right_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Pan<'a> {
+    pub fn new(name: String) -> Self
+    {
+        Self {
+            name,
+            samples_in: None,
+            pan_in: None,
+            left_out: None,
+            right_out: None,
+        }
+    }
+
+    /// Equal power gains for the left and right channels
+    fn gains(pan: f32) -> (f32, f32)
+    {
+        let pan = pan.max(-1.0).min(1.0);
+        let angle = (pan + 1.0) * f32::consts::PI / 4.0;
+        (angle.cos(), angle.sin())
+    }
+}
+
+impl<'a> Component<'a> for Pan<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.name, "samples_in"))?);
+
+        self.pan_in = Some(ports.register_input_port(
+                &PortName::new(&self.name, "pan_in"))?);
+
+        self.left_out = Some(ports.register_output_port(
+                &PortName::new(&self.name, "left_out"))?);
+
+        self.right_out = Some(ports.register_output_port(
+                &PortName::new(&self.name, "right_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if self.samples_in.is_none() || self.pan_in.is_none() ||
+           self.left_out.is_none() || self.right_out.is_none() {
+            return;
+        }
+
+        let samples = ports.get_port_value(&self.samples_in.unwrap());
+        let pan = ports.get_port_value(&self.pan_in.unwrap());
+        let (left, right) = Pan::gains(pan);
+
+        ports.set_port_value(&self.left_out.unwrap(), samples * left);
+        ports.set_port_value(&self.right_out.unwrap(), samples * right);
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gains() {
+        let (l, r) = Pan::gains(-1.0);
+        assert!((l - 1.0).abs() < 1e-6 && r.abs() < 1e-6);
+
+        let (l, r) = Pan::gains(1.0);
+        assert!(l.abs() < 1e-6 && (r - 1.0).abs() < 1e-6);
+
+        // constant power everywhere in between
+        for &p in [-0.5, 0.0, 0.3].iter() {
+            let (l, r) = Pan::gains(p);
+            assert!((l * l + r * r - 1.0).abs() < 1e-6);
+        }
+    }
+}
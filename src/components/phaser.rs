@@ -0,0 +1,339 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use components::modulation::{Shape, Sweep, MAX_FEEDBACK};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+
+use std::f32;
+
+const MAX_STAGES: usize = 12;
+
+// highest the notches can be swept, as a fraction of the sample rate
+const MAX_FREQUENCY: f32 = 0.45;
+
+/// A phaser: the input mixed with itself through a chain of all pass filters,
+/// which cuts notches where the two are out of phase. An LFO sweeps the
+/// notches up and down.
+/// There are `stages` all pass filters (an even number, up to 12), making a
+/// notch for every two stages. The lowest notch sits at `frequency`, and
+/// swings `depth` octaves either side of it, `rate` times a second, following
+/// `shape` (as for the LFO). `feedback` (-1 to 1) sends the chain's output
+/// back into it, for sharper notches.
+/// The left and right notches are swept `spread` (0 to 1) of half a cycle
+/// apart, and `mix` (0 to 1) goes from only the input to only the filtered
+/// signal, with the deepest notches at 0.5.
+/// left_out and right_out carry the stereo result, and samples_out has them
+/// mixed back down to mono.
+/// Each octave on rate_in doubles the rate. depth_in moves the notches further
+/// still, in octaves, while feedback_in and mix_in add to their settings
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct PhaserConfig {
+    pub name: String,
+    pub shape: String,
+    pub rate: f32,
+    pub depth: f32,
+    pub frequency: f32,
+    pub stages: usize,
+    pub feedback: f32,
+    pub spread: f32,
+    pub mix: f32,
+}
+
+impl ComponentConfig for PhaserConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Phaser::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+
+    fn validate(&self) -> Result<(), String>
+    {
+        Shape::from_name(&self.shape)
+            .ok_or_else(|| format!("unknown shape {}", self.shape))?;
+
+        let even = self.stages % 2 == 0;
+        if self.stages < 2 || self.stages > MAX_STAGES || !even {
+            return Err(format!(
+                    "stages must be an even number from 2 to {}", MAX_STAGES));
+        }
+
+        if self.frequency <= 0.0 {
+            return Err("frequency must be more than zero".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+/// Coefficient for every filter in a chain of `stages` filters, which puts the
+/// lowest notch at the given frequency. Each filter moves the notch's phase
+/// half a cycle divided by the number of filters
+fn coefficient(frequency: f32, rate: f32, stages: usize) -> f32
+{
+    let frequency = frequency.max(1.0).min(MAX_FREQUENCY * rate);
+    let shift = (f32::consts::PI / (2.0 * stages as f32)).tan();
+    let t = (f32::consts::PI * frequency / rate).tan() / shift;
+    (t - 1.0) / (t + 1.0)
+}
+
+/// One channel's all pass filters
+#[derive(Debug, Clone)]
+struct Chain {
+    // the state of each filter, in transposed direct form II
+    states: Vec<f32>,
+    // the chain's last output, to feed back in
+    last: f32,
+}
+
+impl Chain {
+    fn process(&mut self, x: f32, coefficient: f32, feedback: f32) -> f32
+    {
+        let a = coefficient;
+        let mut y = x + feedback * self.last;
+        for state in self.states.iter_mut() {
+            let out = a * y + *state;
+            *state = y - a * out;
+            y = out;
+        }
+
+        self.last = y;
+        y
+    }
+}
+
+#[derive(Debug)]
+pub struct Phaser<'a> {
+    config: PhaserConfig,
+    // no sweep for an unknown shape, which leaves the input unfiltered
+    sweep: Option<Sweep>,
+    sample_rate: Option<f32>,
+    // left and right
+    chains: [Chain; 2],
+
+    samples_in: Option<InputPortHandle<'a>>,
+    rate_in: Option<InputPortHandle<'a>>,
+    depth_in: Option<InputPortHandle<'a>>,
+    feedback_in: Option<InputPortHandle<'a>>,
+    mix_in: Option<InputPortHandle<'a>>,
+    samples_out: Option<OutputPortHandle<'a>>,
+    left_out: Option<OutputPortHandle<'a>>,
+    right_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Phaser<'a> {
+    pub fn new(config: PhaserConfig) -> Self
+    {
+        let sweep = Shape::from_name(&config.shape).map(Sweep::new);
+        let chain = Chain {
+            states: vec![0.0; config.stages.max(2).min(MAX_STAGES)],
+            last: 0.0,
+        };
+
+        Self {
+            config,
+            sweep,
+            sample_rate: None,
+            chains: [chain.clone(), chain],
+            samples_in: None,
+            rate_in: None,
+            depth_in: None,
+            feedback_in: None,
+            mix_in: None,
+            samples_out: None,
+            left_out: None,
+            right_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.samples_in.is_some()
+            && self.rate_in.is_some()
+            && self.depth_in.is_some()
+            && self.feedback_in.is_some()
+            && self.mix_in.is_some()
+            && self.samples_out.is_some()
+            && self.left_out.is_some()
+            && self.right_out.is_some()
+    }
+
+    /// The left and right output for one frame of input
+    fn process(
+        &mut self,
+        x: f32,
+        octaves: f32,
+        depth: f32,
+        feedback: f32,
+        mix: f32)
+        -> [f32; 2]
+    {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return [x, x],
+        };
+
+        let rate = self.sample_rate.unwrap();
+        let depth = depth.max(0.0);
+        let feedback = feedback.max(-MAX_FEEDBACK).min(MAX_FEEDBACK);
+        let mix = mix.max(0.0).min(1.0);
+        let spread = self.config.spread.max(0.0).min(1.0);
+        let offsets = [0.0, 0.5 * spread];
+
+        let mut out = [0.0; 2];
+        for (c, chain) in self.chains.iter_mut().enumerate() {
+            let swing = (depth * sweep.value(offsets[c])).exp2();
+            let frequency = self.config.frequency * swing;
+            let a = coefficient(frequency, rate, chain.states.len());
+            let y = chain.process(x, a, feedback);
+            out[c] = (1.0 - mix) * x + mix * y;
+        }
+
+        sweep.advance(self.config.rate * octaves.exp2() / rate);
+        out
+    }
+}
+
+impl<'a> Component<'a> for Phaser<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "samples_in"))?);
+
+        self.rate_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "rate_in"))?);
+
+        self.depth_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "depth_in"))?);
+
+        self.feedback_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "feedback_in"))?);
+
+        self.mix_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "mix_in"))?);
+
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        self.left_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "left_out"))?);
+
+        self.right_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "right_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let x = ports.get_port_value(&self.samples_in.unwrap());
+        let octaves = ports.get_port_value(&self.rate_in.unwrap());
+        let depth = ports.get_port_value(&self.depth_in.unwrap());
+        let feedback = ports.get_port_value(&self.feedback_in.unwrap());
+        let mix = ports.get_port_value(&self.mix_in.unwrap());
+
+        let [left, right] = self.process(
+            x,
+            octaves,
+            self.config.depth + depth,
+            self.config.feedback + feedback,
+            self.config.mix + mix);
+
+        ports.set_port_value(&self.samples_out.unwrap(), 0.5 * (left + right));
+        ports.set_port_value(&self.left_out.unwrap(), left);
+        ports.set_port_value(&self.right_out.unwrap(), right);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48000.0;
+
+    fn phaser(depth: f32, mix: f32) -> Phaser<'static>
+    {
+        let mut phaser = Phaser::new(PhaserConfig {
+            name: "phaser".to_owned(),
+            shape: "triangle".to_owned(),
+            rate: 0.5,
+            depth,
+            frequency: 1000.0,
+            stages: 4,
+            feedback: 0.0,
+            spread: 0.5,
+            mix,
+        });
+
+        phaser.handle_audio_property_change(AudioProperties::SampleRate(RATE));
+        phaser
+    }
+
+    /// Loudest the left channel gets once a tone has settled
+    fn peak(phaser: &mut Phaser, frequency: f32) -> f32
+    {
+        let (depth, mix) = (phaser.config.depth, phaser.config.mix);
+        let step = 2.0 * f32::consts::PI * frequency / RATE;
+        let out: Vec<f32> = (0..4800)
+            .map(|i| (i as f32 * step).sin())
+            .map(|x| phaser.process(x, 0.0, depth, 0.0, mix)[0])
+            .collect();
+
+        out[4000..].iter().fold(0.0, |m, x| x.abs().max(m))
+    }
+
+    #[test]
+    fn test_stages() {
+        // only pairs of filters make whole notches
+        let mut config = phaser(0.0, 0.5).config;
+        for &stages in [0, 3, 14].iter() {
+            config.stages = stages;
+            assert!(config.validate().is_err());
+        }
+
+        config.stages = 12;
+        assert!(config.validate().is_ok());
+        assert_eq!(Phaser::new(config).chains[1].states.len(), 12);
+    }
+
+    #[test]
+    fn test_all_pass() {
+        // the filters alone change the phase, but none of the level
+        let mut phaser = phaser(0.0, 1.0);
+        let energy: f32 = (0..4000)
+            .map(|i| if i == 0 { 1.0 } else { 0.0 })
+            .map(|x| phaser.process(x, 0.0, 0.0, 0.0, 1.0)[0].powi(2))
+            .sum();
+
+        assert!((energy - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_notch() {
+        assert!(peak(&mut phaser(0.0, 0.5), 1000.0) < 0.01);
+        assert!(peak(&mut phaser(0.0, 0.5), 3000.0) > 0.8);
+
+        // swept, the notch moves away from where it started
+        assert!(peak(&mut phaser(2.0, 0.5), 1000.0) > 0.2);
+    }
+}
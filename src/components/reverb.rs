@@ -0,0 +1,349 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+use util::delay_line::DelayLine;
+
+// lengths of the filters in frames, at the sample rate they were tuned for.
+// The lengths are spread out so that their echoes rarely line up
+const TUNED_RATE: f32 = 44100.0;
+const COMB_LENGTHS: [usize; 8] =
+    [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+// the combs all add together, so the input has to be turned down a long way
+const INPUT_GAIN: f32 = 0.015;
+
+// feedback of the combs for the smallest and largest rooms
+const MIN_ROOM_FEEDBACK: f32 = 0.7;
+const MAX_ROOM_FEEDBACK: f32 = 0.98;
+
+const MAX_PRE_DELAY: f32 = 0.5;
+
+/// A reverb, in the style of Freeverb: eight damped comb filters side by side
+/// and four all pass filters after them.
+/// `size` (0 to 1) sets how long the reverb rings for, `damping` (0 to 1) how
+/// quickly the high frequencies die away, `pre_delay` is the time in seconds
+/// (up to half a second) before the reverb starts, and `mix` (0 to 1) goes
+/// from only the input to only the reverb.
+/// size_in, damping_in, pre_delay_in and mix_in are added to each setting
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct ReverbConfig {
+    pub name: String,
+    pub size: f32,
+    pub damping: f32,
+    pub pre_delay: f32,
+    pub mix: f32,
+}
+
+impl ComponentConfig for ReverbConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Reverb::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+}
+
+/// A comb filter with a low pass filter in its feedback
+#[derive(Debug, Clone)]
+struct Comb {
+    line: DelayLine,
+    length: f32,
+    filtered: f32,
+}
+
+impl Comb {
+    fn process(&mut self, x: f32, feedback: f32, damping: f32) -> f32
+    {
+        let y = self.line.read(self.length);
+        self.filtered = y * (1.0 - damping) + self.filtered * damping;
+        self.line.push(x + self.filtered * feedback);
+        y
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AllPass {
+    line: DelayLine,
+    length: f32,
+}
+
+impl AllPass {
+    fn process(&mut self, x: f32) -> f32
+    {
+        let delayed = self.line.read(self.length);
+        self.line.push(x + delayed * ALLPASS_FEEDBACK);
+        delayed - x
+    }
+}
+
+#[derive(Debug)]
+pub struct Reverb<'a> {
+    config: ReverbConfig,
+    sample_rate: Option<f32>,
+    pre_delay: DelayLine,
+    combs: Vec<Comb>,
+    allpasses: Vec<AllPass>,
+
+    samples_in: Option<InputPortHandle<'a>>,
+    size_in: Option<InputPortHandle<'a>>,
+    damping_in: Option<InputPortHandle<'a>>,
+    pre_delay_in: Option<InputPortHandle<'a>>,
+    mix_in: Option<InputPortHandle<'a>>,
+    samples_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Reverb<'a> {
+    pub fn new(config: ReverbConfig) -> Self
+    {
+        let combs = COMB_LENGTHS.iter()
+            .map(|_| Comb {
+                line: DelayLine::new(),
+                length: 0.0,
+                filtered: 0.0,
+            })
+            .collect();
+
+        let allpasses = ALLPASS_LENGTHS.iter()
+            .map(|_| AllPass { line: DelayLine::new(), length: 0.0 })
+            .collect();
+
+        Self {
+            config,
+            sample_rate: None,
+            pre_delay: DelayLine::new(),
+            combs,
+            allpasses,
+            samples_in: None,
+            size_in: None,
+            damping_in: None,
+            pre_delay_in: None,
+            mix_in: None,
+            samples_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.samples_in.is_some()
+            && self.size_in.is_some()
+            && self.damping_in.is_some()
+            && self.pre_delay_in.is_some()
+            && self.mix_in.is_some()
+            && self.samples_out.is_some()
+    }
+
+    /// Size all of the filters for a new sample rate. This is the only place
+    /// the reverb allocates
+    fn resize(&mut self, rate: f32)
+    {
+        let scale = rate / TUNED_RATE;
+        let length = |frames: usize| (frames as f32 * scale).round().max(1.0);
+
+        for (comb, &frames) in self.combs.iter_mut().zip(COMB_LENGTHS.iter()) {
+            comb.length = length(frames);
+            comb.line.reserve(comb.length as usize);
+            comb.line.clear();
+            comb.filtered = 0.0;
+        }
+
+        let allpasses = self.allpasses.iter_mut().zip(ALLPASS_LENGTHS.iter());
+        for (allpass, &frames) in allpasses {
+            allpass.length = length(frames);
+            allpass.line.reserve(allpass.length as usize);
+            allpass.line.clear();
+        }
+
+        self.pre_delay.reserve((MAX_PRE_DELAY * rate).ceil() as usize);
+        self.pre_delay.clear();
+    }
+
+    fn process(
+        &mut self,
+        x: f32,
+        size: f32,
+        damping: f32,
+        pre_delay: f32,
+        mix: f32)
+        -> f32
+    {
+        let size = size.max(0.0).min(1.0);
+        let feedback = MIN_ROOM_FEEDBACK
+            + size * (MAX_ROOM_FEEDBACK - MIN_ROOM_FEEDBACK);
+        let damping = damping.max(0.0).min(1.0);
+
+        // a pre delay of zero reads the sample which is about to be pushed
+        let pre_delay = pre_delay.max(0.0) * self.sample_rate.unwrap();
+        self.pre_delay.push(x);
+        let input = self.pre_delay.read(pre_delay + 1.0) * INPUT_GAIN;
+
+        let mut wet = 0.0;
+        for comb in self.combs.iter_mut() {
+            wet += comb.process(input, feedback, damping);
+        }
+
+        for allpass in self.allpasses.iter_mut() {
+            wet = allpass.process(wet);
+        }
+
+        let mix = mix.max(0.0).min(1.0);
+        (1.0 - mix) * x + mix * wet
+    }
+}
+
+impl<'a> Component<'a> for Reverb<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "samples_in"))?);
+
+        self.size_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "size_in"))?);
+
+        self.damping_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "damping_in"))?);
+
+        self.pre_delay_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "pre_delay_in"))?);
+
+        self.mix_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "mix_in"))?);
+
+        self.samples_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "samples_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let x = ports.get_port_value(&self.samples_in.unwrap());
+        let size = ports.get_port_value(&self.size_in.unwrap());
+        let damping = ports.get_port_value(&self.damping_in.unwrap());
+        let pre_delay = ports.get_port_value(&self.pre_delay_in.unwrap());
+        let mix = ports.get_port_value(&self.mix_in.unwrap());
+
+        let y = self.process(
+            x,
+            self.config.size + size,
+            self.config.damping + damping,
+            self.config.pre_delay + pre_delay,
+            self.config.mix + mix);
+
+        ports.set_port_value(&self.samples_out.unwrap(), y);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+            self.resize(r);
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 44100.0;
+
+    fn reverb(size: f32, damping: f32) -> Reverb<'static>
+    {
+        let mut reverb = Reverb::new(ReverbConfig {
+            name: "reverb".to_owned(),
+            size,
+            damping,
+            pre_delay: 0.0,
+            mix: 1.0,
+        });
+
+        reverb.handle_audio_property_change(AudioProperties::SampleRate(RATE));
+        reverb
+    }
+
+    /// The reverb of a single click
+    fn tail(reverb: &mut Reverb, pre_delay: f32, frames: usize) -> Vec<f32>
+    {
+        let (size, damping) = (reverb.config.size, reverb.config.damping);
+        (0..frames)
+            .map(|i| {
+                let x = if i == 0 { 1.0 } else { 0.0 };
+                reverb.process(x, size, damping, pre_delay, 1.0)
+            })
+            .collect()
+    }
+
+    fn energy(samples: &[f32]) -> f32
+    {
+        samples.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn test_pre_delay() {
+        let out = tail(&mut reverb(0.5, 0.5), 0.0, 2000);
+
+        // nothing comes out until the shortest comb and the all passes have
+        // all had a chance to pass the click on
+        let first = out.iter().position(|&x| x != 0.0).unwrap();
+        assert!(first > 0 && first <= COMB_LENGTHS[0]);
+
+        let delayed = tail(&mut reverb(0.5, 0.5), 0.01, 2000);
+        let first_delayed = delayed.iter().position(|&x| x != 0.0).unwrap();
+        assert_eq!(first_delayed, first + 441);
+    }
+
+    #[test]
+    fn test_size() {
+        let second = RATE as usize;
+        let small = tail(&mut reverb(0.1, 0.5), 0.0, 2 * second);
+        let large = tail(&mut reverb(0.9, 0.5), 0.0, 2 * second);
+
+        // the bigger room is still ringing a second later
+        assert!(energy(&large[second..]) > 100.0 * energy(&small[second..]));
+        assert!(large.iter().all(|x| x.abs() < 1.0));
+    }
+
+    #[test]
+    fn test_damping() {
+        let bright = tail(&mut reverb(0.8, 0.0), 0.0, 20000);
+        let dark = tail(&mut reverb(0.8, 0.9), 0.0, 20000);
+
+        // how much the tail jumps from sample to sample, against how loud it
+        // is. A damped tail has lost its high frequencies, so moves less
+        let roughness = |s: &[f32]| {
+            let steps: f32 = s.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum();
+            steps / energy(s)
+        };
+
+        assert!(roughness(&dark[10000..]) < 0.5 * roughness(&bright[10000..]));
+    }
+
+    #[test]
+    fn test_mix() {
+        // fully dry is just the input, with the reverb still running behind it
+        let mut reverb = reverb(0.5, 0.5);
+        for i in 0..100 {
+            assert_eq!(reverb.process(i as f32, 0.5, 0.5, 0.1, 0.0), i as f32);
+        }
+
+        // and fully wet, nothing comes out until the pre-delay is over
+        assert_eq!(reverb.process(1.0, 0.5, 0.5, 0.1, 1.0), 0.0);
+    }
+}
@@ -114,11 +114,16 @@ impl<'a> Component<'a> for SineWaveOscillator<'a> {
 
     fn handle_audio_property_change(&mut self, prop: AudioProperties)
     {
-        match prop {
-            AudioProperties::SampleRate(r) => self.sample_rate = Some(r),
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
         }
     }
 
+    fn reset_phase(&mut self, phase: f32)
+    {
+        self.phase = phase;
+    }
+
     fn get_name(&self) -> String
     {
         self.config.name.clone()
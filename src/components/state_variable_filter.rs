@@ -0,0 +1,310 @@
+use audioprops::AudioProperties;
+use components::{Component, ComponentConfig};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+
+use std::f32;
+
+/// A resonant 2-pole filter with low, high, band pass and notch outputs at
+/// once. Set with `cutoff` in Hz and `resonance` from 0 to 1, and modulated
+/// in octaves by cutoff_in and linearly by resonance_in
+#[derive(Debug, Clone, StructValue, ForeignValue, FromValueClone)]
+pub struct StateVariableFilterConfig {
+    pub name: String,
+    pub cutoff: f32,
+    pub resonance: f32,
+}
+
+impl ComponentConfig for StateVariableFilterConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(StateVariableFilter::new(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig> {
+        Box::new(self.clone())
+    }
+}
+
+// highest resonance, just short of self oscillation
+const MAX_RESONANCE: f32 = 0.98;
+
+#[derive(Debug)]
+pub struct StateVariableFilter<'a> {
+    config: StateVariableFilterConfig,
+    sample_rate: Option<f32>,
+
+    // the cutoff and resonance the coefficients were worked out for
+    cutoff: f32,
+    resonance: f32,
+    // coefficients of the zero delay feedback (topology preserving) form
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    // the state of the two integrators
+    ic1eq: f32,
+    ic2eq: f32,
+
+    samples_in: Option<InputPortHandle<'a>>,
+    cutoff_in: Option<InputPortHandle<'a>>,
+    resonance_in: Option<InputPortHandle<'a>>,
+    low_out: Option<OutputPortHandle<'a>>,
+    high_out: Option<OutputPortHandle<'a>>,
+    band_out: Option<OutputPortHandle<'a>>,
+    notch_out: Option<OutputPortHandle<'a>>,
+}
+
+/// The four responses of the filter to a single sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Outputs {
+    low: f32,
+    high: f32,
+    band: f32,
+    notch: f32,
+}
+
+impl<'a> StateVariableFilter<'a> {
+    pub fn new(config: StateVariableFilterConfig) -> Self
+    {
+        Self {
+            config,
+            sample_rate: None,
+            cutoff: f32::NAN,
+            resonance: f32::NAN,
+            k: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+            samples_in: None,
+            cutoff_in: None,
+            resonance_in: None,
+            low_out: None,
+            high_out: None,
+            band_out: None,
+            notch_out: None,
+        }
+    }
+
+    fn fully_initialized(&self) -> bool
+    {
+        self.sample_rate.is_some()
+            && self.samples_in.is_some()
+            && self.cutoff_in.is_some()
+            && self.resonance_in.is_some()
+            && self.low_out.is_some()
+            && self.high_out.is_some()
+            && self.band_out.is_some()
+            && self.notch_out.is_some()
+    }
+
+    /// Work out the coefficients, if the cutoff or resonance have changed
+    fn tune(&mut self, cutoff: f32, resonance: f32)
+    {
+        if cutoff == self.cutoff && resonance == self.resonance {
+            return;
+        }
+
+        self.cutoff = cutoff;
+        self.resonance = resonance;
+
+        // the cutoff has to stay below nyquist, or the tan blows up
+        let rate = self.sample_rate.unwrap();
+        let cutoff = cutoff.max(1.0).min(rate * 0.49);
+        let resonance = resonance.max(0.0).min(MAX_RESONANCE);
+
+        let g = (f32::consts::PI * cutoff / rate).tan();
+        self.k = 2.0 - 2.0 * resonance;
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    fn process(&mut self, v0: f32) -> Outputs
+    {
+        let v3 = v0 - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let high = v0 - self.k * v1 - v2;
+        Outputs {
+            low: v2,
+            high,
+            band: v1,
+            notch: v2 + high,
+        }
+    }
+}
+
+impl<'a> Component<'a> for StateVariableFilter<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        self.samples_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "samples_in"))?);
+
+        self.cutoff_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "cutoff_in"))?);
+
+        self.resonance_in = Some(ports.register_input_port(
+                &PortName::new(&self.config.name, "resonance_in"))?);
+
+        self.low_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "low_out"))?);
+
+        self.high_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "high_out"))?);
+
+        self.band_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "band_out"))?);
+
+        self.notch_out = Some(ports.register_output_port(
+                &PortName::new(&self.config.name, "notch_out"))?);
+
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        if !self.fully_initialized() {
+            return;
+        }
+
+        let octaves = ports.get_port_value(&self.cutoff_in.unwrap());
+        let resonance = ports.get_port_value(&self.resonance_in.unwrap());
+        let cutoff = self.config.cutoff * octaves.exp2();
+        let resonance = self.config.resonance + resonance;
+        self.tune(cutoff, resonance);
+
+        let x = ports.get_port_value(&self.samples_in.unwrap());
+        let out = self.process(x);
+
+        ports.set_port_value(&self.low_out.unwrap(), out.low);
+        ports.set_port_value(&self.high_out.unwrap(), out.high);
+        ports.set_port_value(&self.band_out.unwrap(), out.band);
+        ports.set_port_value(&self.notch_out.unwrap(), out.notch);
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+
+            // NaN never matches a cutoff, so the next frame retunes
+            self.cutoff = f32::NAN;
+        }
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ports::PortManagerImpl;
+    use util::ft;
+    use util::nmat::{Matrix, RowMajor};
+
+    // 100 Hz between each bin of the transform
+    const RATE: f32 = 6400.0;
+    const SAMPLES: usize = 64;
+
+    /// Magnitude of the filter's response in each bin, up to nyquist
+    fn response<F>(cutoff: f32, resonance: f32, output: F) -> Vec<f32>
+        where F: Fn(Outputs) -> f32
+    {
+        let mut filter = StateVariableFilter::new(StateVariableFilterConfig {
+            name: "svf".to_owned(),
+            cutoff,
+            resonance,
+        });
+
+        filter.handle_audio_property_change(AudioProperties::SampleRate(RATE));
+        filter.tune(cutoff, resonance);
+
+        // the transform of the impulse response is the frequency response
+        let mut samples: Matrix<f32, RowMajor> = Matrix::new((SAMPLES, 1));
+        for i in 0..SAMPLES {
+            let x = if i == 0 { 1.0 } else { 0.0 };
+            samples[(i, 0)] = output(filter.process(x));
+        }
+
+        let out = ft::reference_fourier(&samples);
+        (0..SAMPLES / 2).map(|i| out[(i, 0)].norm()).collect()
+    }
+
+    fn close(a: f32, b: f32) -> bool
+    {
+        (a - b).abs() < 0.02
+    }
+
+    #[test]
+    fn test_low_pass() {
+        let r = response(400.0, 0.0, |o| o.low);
+        assert!(close(r[0], 1.0));
+        assert!(close(r[4], 0.5));
+
+        // 12dB per octave, so under 1/50 three octaves above the cutoff
+        assert!(r[31] < 0.02);
+    }
+
+    #[test]
+    fn test_high_pass() {
+        let r = response(400.0, 0.0, |o| o.high);
+        assert!(close(r[0], 0.0));
+        assert!(close(r[4], 0.5));
+        assert!(close(r[31], 1.0));
+    }
+
+    #[test]
+    fn test_band_pass_and_notch() {
+        let band = response(400.0, 0.0, |o| o.band);
+        let notch = response(400.0, 0.0, |o| o.notch);
+
+        assert!(close(band[0], 0.0) && band[31] < 0.1);
+        assert!(close(band[4], 0.5));
+
+        assert!(close(notch[0], 1.0) && close(notch[31], 1.0));
+        assert!(notch[4] < 0.01);
+    }
+
+    #[test]
+    fn test_resonance() {
+        // resonance boosts the cutoff frequency, and nothing far from it
+        let flat = response(800.0, 0.0, |o| o.low);
+        let peaked = response(800.0, 0.9, |o| o.low);
+
+        assert!(peaked[8] > 4.0 * flat[8]);
+        assert!(close(peaked[0], flat[0]));
+    }
+
+    #[test]
+    fn test_cutoff_modulation() {
+        let mut ports = PortManagerImpl::new();
+        let mut filter = StateVariableFilter::new(StateVariableFilterConfig {
+            name: "svf".to_owned(),
+            cutoff: 1000.0,
+            resonance: 0.5,
+        });
+
+        filter.initialize_ports(&mut ports).unwrap();
+        filter.handle_audio_property_change(AudioProperties::SampleRate(RATE));
+
+        let cutoff = ports.register_output_port(
+            &PortName::new("test", "cutoff_out")).unwrap();
+        ports.connect(&cutoff, &filter.cutoff_in.unwrap());
+
+        // one octave up
+        ports.set_port_value(&cutoff, 1.0);
+        filter.generate(&mut ports);
+        assert_eq!(filter.cutoff, 2000.0);
+        assert_eq!(filter.resonance, 0.5);
+    }
+}
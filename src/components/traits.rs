@@ -5,13 +5,23 @@ use std::fmt;
 
 pub trait Component<'a>: fmt::Debug {
     /// Called when it is time for the component to generate audio on its output
-    /// ports
+    /// ports. Components may also send MIDI out of the synth from here, with
+    /// `ports.send_midi`
     fn generate(&mut self, ports: &mut RealtimePortManager<'a>);
 
     /// Called with the audio system property that has changed
     /// A default noop implementation is provided
     fn handle_audio_property_change(&mut self, _props: AudioProperties) { }
 
+    /// Called when the voice wants any oscillators to restart at the given
+    /// phase, in [0, 1). A default noop implementation is provided
+    fn reset_phase(&mut self, _phase: f32) { }
+
+    /// Frames by which the component's output lags its input, for components
+    /// which need to look ahead. May change when the audio properties change.
+    /// A default implementation, reporting no latency, is provided
+    fn latency(&self) -> usize { 0 }
+
     // port management
     fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
         -> Result<(), PortManagerError>;
@@ -27,6 +37,11 @@ pub trait ComponentConfig: fmt::Debug {
 
     /// Clones the underlying config and returns it as a trait object
     fn box_clone(&self) -> Box<ComponentConfig>;
+
+    /// Checks the config when the patch is loaded, so that mistakes are found
+    /// before the component is built. A default implementation, accepting
+    /// everything, is provided
+    fn validate(&self) -> Result<(), String> { Ok(()) }
 }
 
 impl Clone for Box<ComponentConfig> {
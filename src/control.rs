@@ -0,0 +1,363 @@
+// MIDI controller decoding
+// Raw control changes are only 7 bits wide. The MIDI spec pairs controllers
+// 0-31 (MSB) with 32-63 (LSB) to form 14-bit values, and uses a handful of
+// controllers to select and write Registered/Non-Registered Parameter Numbers.
+// The state machine in this file turns the raw stream into normalized values.
+
+// coarse controllers 0-31 are paired with fine controllers 32-63
+const PAIRED_CONTROLLERS: u8 = 32;
+
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+
+// parameter 127/127 is the "null" parameter, for both RPNs and NRPNs. Selecting
+// it deselects the current parameter
+const NULL_PARAMETER: u16 = 0x3FFF;
+
+const MAX_14_BIT: u16 = 0x3FFF;
+
+/// A decoded controller change. All values are normalized to [0, 1]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ControlEvent {
+    /// A continuous controller changed. For controllers 0-31 this is the full
+    /// 14-bit value formed with the matching LSB controller
+    Controller { cc: u8, value: f32 },
+
+    /// Data entry for the currently selected Registered Parameter
+    Registered { parameter: u16, value: f32 },
+
+    /// Data entry for the currently selected Non-Registered Parameter
+    NonRegistered { parameter: u16, value: f32 },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Selection {
+    Nothing,
+    Registered(u16),
+    NonRegistered(u16),
+}
+
+/// Tracks the controller state for a single MIDI channel.
+/// Everything here is fixed size, so this is safe to use from the audio thread
+#[derive(Debug)]
+pub struct ControllerState {
+    msb: [u8; PAIRED_CONTROLLERS as usize],
+    lsb: [u8; PAIRED_CONTROLLERS as usize],
+
+    // parameter number selection, as last sent by the controller
+    rpn: (u8, u8),
+    nrpn: (u8, u8),
+    selection: Selection,
+
+    // data entry value for the currently selected parameter
+    data: u16,
+}
+
+fn normalize_7_bit(v: u8) -> f32
+{
+    v as f32 / 127.0
+}
+
+fn normalize_14_bit(v: u16) -> f32
+{
+    v as f32 / MAX_14_BIT as f32
+}
+
+fn combine(msb: u8, lsb: u8) -> u16
+{
+    ((msb as u16 & 0x7F) << 7) | (lsb as u16 & 0x7F)
+}
+
+/// Whether a controller number can be bound to. The fine controllers only
+/// change their coarse controller, and the data entry and parameter number
+/// controllers are taken by RPNs and NRPNs, so none of them ever report a
+/// value of their own
+pub fn is_bindable(cc: u8) -> bool
+{
+    match cc {
+        DATA_ENTRY_MSB | DATA_ENTRY_LSB => false,
+        DATA_INCREMENT | DATA_DECREMENT => false,
+        NRPN_LSB | NRPN_MSB | RPN_LSB | RPN_MSB => false,
+        cc if cc < PAIRED_CONTROLLERS => true,
+        cc if cc < 2 * PAIRED_CONTROLLERS => false,
+        cc => cc < 128,
+    }
+}
+
+impl ControllerState {
+    pub fn new() -> Self
+    {
+        Self {
+            msb: [0; PAIRED_CONTROLLERS as usize],
+            lsb: [0; PAIRED_CONTROLLERS as usize],
+            rpn: (0x7F, 0x7F),
+            nrpn: (0x7F, 0x7F),
+            selection: Selection::Nothing,
+            data: 0,
+        }
+    }
+
+    /// Feed a raw control change into the state machine.
+    /// Returns the decoded change, if the message produced one. Parameter
+    /// selection messages never produce an event on their own.
+    pub fn control_value_change(&mut self, cc: u8, val: u8)
+        -> Option<ControlEvent>
+    {
+        let val = val & 0x7F;
+
+        match cc {
+            RPN_MSB | RPN_LSB => {
+                if cc == RPN_MSB {
+                    self.rpn.0 = val;
+                } else {
+                    self.rpn.1 = val;
+                }
+
+                let parameter = combine(self.rpn.0, self.rpn.1);
+                self.select(Selection::Registered(parameter));
+                None
+            },
+
+            NRPN_MSB | NRPN_LSB => {
+                if cc == NRPN_MSB {
+                    self.nrpn.0 = val;
+                } else {
+                    self.nrpn.1 = val;
+                }
+
+                let parameter = combine(self.nrpn.0, self.nrpn.1);
+                self.select(Selection::NonRegistered(parameter));
+                None
+            },
+
+            DATA_ENTRY_MSB if self.selection != Selection::Nothing => {
+                // a new MSB always resets the fine part of the value
+                self.data = combine(val, 0);
+                self.parameter_event()
+            },
+
+            DATA_ENTRY_LSB if self.selection != Selection::Nothing => {
+                self.data = (self.data & !0x7F) | val as u16;
+                self.parameter_event()
+            },
+
+            DATA_INCREMENT => {
+                if self.selection == Selection::Nothing {
+                    return None;
+                }
+
+                if self.data < MAX_14_BIT {
+                    self.data += 1;
+                }
+                self.parameter_event()
+            },
+
+            DATA_DECREMENT => {
+                if self.selection == Selection::Nothing {
+                    return None;
+                }
+
+                if self.data > 0 {
+                    self.data -= 1;
+                }
+                self.parameter_event()
+            },
+
+            cc if cc < PAIRED_CONTROLLERS => {
+                // a new MSB resets the LSB, per the MIDI spec
+                self.msb[cc as usize] = val;
+                self.lsb[cc as usize] = 0;
+                Some(self.controller_event(cc))
+            },
+
+            cc if cc < 2 * PAIRED_CONTROLLERS => {
+                let coarse = cc - PAIRED_CONTROLLERS;
+                self.lsb[coarse as usize] = val;
+                Some(self.controller_event(coarse))
+            },
+
+            cc if cc < 128 => {
+                let value = normalize_7_bit(val);
+                Some(ControlEvent::Controller { cc, value })
+            },
+
+            _ => None,
+        }
+    }
+
+    fn select(&mut self, selection: Selection)
+    {
+        let selection = match selection {
+            Selection::Registered(NULL_PARAMETER) => Selection::Nothing,
+            Selection::NonRegistered(NULL_PARAMETER) => Selection::Nothing,
+            s => s,
+        };
+
+        if selection != self.selection {
+            self.selection = selection;
+            self.data = 0;
+        }
+    }
+
+    fn controller_event(&self, coarse: u8) -> ControlEvent
+    {
+        let v = combine(self.msb[coarse as usize], self.lsb[coarse as usize]);
+        ControlEvent::Controller { cc: coarse, value: normalize_14_bit(v) }
+    }
+
+    fn parameter_event(&self) -> Option<ControlEvent>
+    {
+        let value = normalize_14_bit(self.data);
+        match self.selection {
+            Selection::Nothing => None,
+            Selection::Registered(parameter) =>
+                Some(ControlEvent::Registered { parameter, value }),
+            Selection::NonRegistered(parameter) =>
+                Some(ControlEvent::NonRegistered { parameter, value }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unpaired_controller() {
+        let mut state = ControllerState::new();
+        let ev = state.control_value_change(74, 127);
+        assert_eq!(ev, Some(ControlEvent::Controller { cc: 74, value: 1.0 }));
+    }
+
+    #[test]
+    fn test_is_bindable() {
+        let bindable: Vec<u8> = (0..256)
+            .map(|cc: u16| cc as u8)
+            .filter(|&cc| is_bindable(cc))
+            .collect();
+        let expected: Vec<u8> = (0..32).chain(64..96).chain(102..128)
+            .filter(|&cc| cc != 6)
+            .collect();
+        assert_eq!(bindable, expected);
+    }
+
+    #[test]
+    fn test_msb_lsb_pairing() {
+        let mut state = ControllerState::new();
+
+        let ev = state.control_value_change(1, 0x40).unwrap();
+        assert_eq!(ev, ControlEvent::Controller {
+            cc: 1,
+            value: normalize_14_bit(0x2000),
+        });
+
+        // the fine controller updates the coarse controller's value
+        let ev = state.control_value_change(33, 0x7F).unwrap();
+        assert_eq!(ev, ControlEvent::Controller {
+            cc: 1,
+            value: normalize_14_bit(0x207F),
+        });
+
+        // and a new coarse value resets the fine value
+        let ev = state.control_value_change(1, 0x7F).unwrap();
+        assert_eq!(ev, ControlEvent::Controller {
+            cc: 1,
+            value: normalize_14_bit(0x3F80),
+        });
+    }
+
+    #[test]
+    fn test_rpn_data_entry() {
+        let mut state = ControllerState::new();
+
+        // select pitch bend sensitivity
+        assert!(state.control_value_change(RPN_MSB, 0).is_none());
+        assert!(state.control_value_change(RPN_LSB, 0).is_none());
+
+        let ev = state.control_value_change(DATA_ENTRY_MSB, 2).unwrap();
+        assert_eq!(ev, ControlEvent::Registered {
+            parameter: 0,
+            value: normalize_14_bit(2 << 7),
+        });
+
+        let ev = state.control_value_change(DATA_ENTRY_LSB, 1).unwrap();
+        assert_eq!(ev, ControlEvent::Registered {
+            parameter: 0,
+            value: normalize_14_bit((2 << 7) | 1),
+        });
+
+        let ev = state.control_value_change(DATA_DECREMENT, 0).unwrap();
+        assert_eq!(ev, ControlEvent::Registered {
+            parameter: 0,
+            value: normalize_14_bit(2 << 7),
+        });
+    }
+
+    #[test]
+    fn test_nrpn_data_entry() {
+        let mut state = ControllerState::new();
+
+        state.control_value_change(NRPN_MSB, 1);
+        state.control_value_change(NRPN_LSB, 2);
+
+        let ev = state.control_value_change(DATA_ENTRY_MSB, 0x7F).unwrap();
+        assert_eq!(ev, ControlEvent::NonRegistered {
+            parameter: combine(1, 2),
+            value: normalize_14_bit(0x3F80),
+        });
+
+        // saturates at the top of the range
+        state.control_value_change(DATA_ENTRY_LSB, 0x7F);
+        let ev = state.control_value_change(DATA_INCREMENT, 0).unwrap();
+        assert_eq!(ev, ControlEvent::NonRegistered {
+            parameter: combine(1, 2),
+            value: 1.0,
+        });
+    }
+
+    #[test]
+    fn test_null_rpn_deselects() {
+        let mut state = ControllerState::new();
+
+        state.control_value_change(RPN_MSB, 0);
+        state.control_value_change(RPN_LSB, 0);
+        state.control_value_change(RPN_MSB, 0x7F);
+        state.control_value_change(RPN_LSB, 0x7F);
+
+        // with nothing selected, data entry is an ordinary controller again
+        let ev = state.control_value_change(DATA_ENTRY_MSB, 0x7F).unwrap();
+        assert_eq!(ev, ControlEvent::Controller {
+            cc: DATA_ENTRY_MSB,
+            value: normalize_14_bit(0x3F80),
+        });
+
+        assert!(state.control_value_change(DATA_INCREMENT, 0).is_none());
+    }
+
+    #[test]
+    fn test_null_nrpn_deselects() {
+        let mut state = ControllerState::new();
+
+        state.control_value_change(NRPN_MSB, 1);
+        state.control_value_change(NRPN_LSB, 2);
+        assert!(state.control_value_change(DATA_ENTRY_MSB, 3).is_some());
+
+        state.control_value_change(NRPN_MSB, 0x7F);
+        state.control_value_change(NRPN_LSB, 0x7F);
+
+        // the last parameter doesn't see any more data entry
+        let ev = state.control_value_change(DATA_ENTRY_MSB, 4).unwrap();
+        assert_eq!(ev, ControlEvent::Controller {
+            cc: DATA_ENTRY_MSB,
+            value: normalize_14_bit(4 << 7),
+        });
+
+        assert!(state.control_value_change(DATA_DECREMENT, 0).is_none());
+    }
+}
@@ -0,0 +1,371 @@
+// The master graph, which runs on the mixed voices
+// Effects like reverb only need to run once for the whole soundscape, instead
+// of once in every voice. A patch adds them with add-effect, and they form a
+// graph of their own, fed by the "mix" pseudo component with the voices added
+// together and writing back to it, just like the components in a voice.
+//
+// With connect-effect, the effects can be wired up any way, between the mix's
+// outputs (named like the voice's inputs: "samples_out", "left_out" and
+// "right_out", or "channel_N_out") and its inputs ("samples_in" and so on).
+// With more than one channel, the mix's samples_out carries all of them mixed
+// down to mono.
+// Without any connections, the effects are chained in the order they were
+// added. A stereo effect (with left_in and right_in, or left_out and
+// right_out) keeps the channels apart, otherwise the channels are mixed down
+// into its samples_in, and its samples_out goes to every channel after it.
+// Nothing can mix an effect's left_out and right_out back down to mono, so an
+// effect without a samples_out (a pan, or a filter with only low_out and so
+// on) can only be chained into stereo effects, or last into a stereo mix.
+// Anything else is an error, and has to be wired up with connect-effect.
+
+use audioprops::AudioProperties;
+use components::Component;
+use patch::Patch;
+use ports::{InputPortHandle, OutputPortHandle, PortManagerImpl, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+use schedule::Schedule;
+use voice::channel_port_name;
+
+/// Name of the mix port which carries the given channel of the mixed voices
+/// into the effects
+pub fn mix_port_name(channels: usize, channel: usize) -> String
+{
+    match (channels, channel) {
+        (1, _) => "samples_out".to_owned(),
+        (2, 0) => "left_out".to_owned(),
+        (2, _) => "right_out".to_owned(),
+        (_, n) => format!("channel_{}_out", n),
+    }
+}
+
+/// Whether a component has both of a stereo pair of ports
+fn has_stereo_ports(ports: &PortManagerImpl, name: &str, dir: &str) -> bool
+{
+    let left = PortName::new(name, format!("left_{}", dir));
+    let right = PortName::new(name, format!("right_{}", dir));
+    ports.find_port(&left).is_some() && ports.find_port(&right).is_some()
+}
+
+/// The error for an effect whose outputs can't feed what comes after it
+fn not_chainable(name: &str, into: &str) -> PortManagerError
+{
+    PortManagerError::NotChainable(format!(
+        "effect {} has no samples_out to chain into {}, \
+         wire it up with connect-effect instead", name, into))
+}
+
+/// Connect the effects one after the other, between the mix's outputs and
+/// inputs
+fn chain<'a>(
+    ports: &mut PortManagerImpl<'a>,
+    components: &[Box<Component<'a> + 'a>],
+    channels: usize)
+    -> Result<(), PortManagerError>
+{
+    let stereo = |ports: &PortManagerImpl, name: &str, dir: &str| {
+        channels == 2 && has_stereo_ports(ports, name, dir)
+    };
+
+    // each channel coming out of the last effect, and all of them in mono,
+    // if it has the ports for them
+    let mut last = "mix".to_owned();
+    let mut from: Option<Vec<PortName>> = Some((0..channels)
+        .map(|c| PortName::new("mix", mix_port_name(channels, c)))
+        .collect());
+    let mut mono = Some(PortName::new("mix", "samples_out"));
+
+    for comp in components.iter() {
+        let name = comp.get_name();
+        if stereo(ports, &name, "in") {
+            let from = from.as_ref()
+                .ok_or_else(|| not_chainable(&last, &name))?;
+            ports.connect_by_name(&from[0], &PortName::new(&name, "left_in"))?;
+            ports.connect_by_name(&from[1], &PortName::new(&name, "right_in"))?;
+        } else {
+            let mono = mono.as_ref()
+                .ok_or_else(|| not_chainable(&last, &name))?;
+            ports.connect_by_name(mono, &PortName::new(&name, "samples_in"))?;
+        }
+
+        let out = PortName::new(&name, "samples_out");
+        mono = ports.find_port(&out).map(|_| out);
+        if stereo(ports, &name, "out") {
+            from = Some(vec![
+                PortName::new(&name, "left_out"),
+                PortName::new(&name, "right_out"),
+            ]);
+        } else {
+            from = mono.clone().map(|m| vec![m; channels]);
+        }
+        last = name;
+    }
+
+    let from = from.ok_or_else(|| not_chainable(&last, "the mix"))?;
+    for (c, port) in from.iter().enumerate() {
+        let into = PortName::new("mix", channel_port_name(channels, c));
+        ports.connect_by_name(port, &into)?;
+    }
+
+    Ok(())
+}
+
+/// The effects, with their own ports
+#[derive(Debug)]
+struct Graph<'a> {
+    components: Vec<Box<Component<'a> + 'a>>,
+    ports: PortManagerImpl<'a>,
+    schedule: Schedule,
+    // written with the mixed voices, and read back after the effects
+    mix_out: Vec<OutputPortHandle<'a>>,
+    mix_in: Vec<InputPortHandle<'a>>,
+    // all of the channels mixed down to mono, when there's more than one
+    mono_out: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Graph<'a> {
+    /// Build the patch's effects, either chained one after the other or
+    /// connected the way the patch says
+    fn new(patch: &Patch) -> Result<Self, PortManagerError>
+    {
+        let channels = patch.channels;
+        let mut ports = PortManagerImpl::new();
+        let mut mix_out = Vec::new();
+        let mut mix_in = Vec::new();
+        for c in 0..channels {
+            let out = PortName::new("mix", mix_port_name(channels, c));
+            mix_out.push(ports.register_output_port(&out)?);
+
+            let into = PortName::new("mix", channel_port_name(channels, c));
+            mix_in.push(ports.register_input_port(&into)?);
+        }
+
+        let mono_out = if channels > 1 {
+            let out = PortName::new("mix", "samples_out");
+            Some(ports.register_output_port(&out)?)
+        } else {
+            None
+        };
+
+        let mut components = Vec::new();
+        for config in patch.effects.iter() {
+            let mut comp = config.build_component();
+            comp.initialize_ports(&mut ports)?;
+            components.push(comp);
+        }
+
+        if patch.effect_connections.is_empty() {
+            chain(&mut ports, &components, channels)?;
+        } else {
+            for c in patch.effect_connections.iter() {
+                ports.connect_by_name(&c.first, &c.second)?;
+            }
+        }
+
+        let schedule = Schedule::new(&mut components, &ports, "mix");
+
+        Ok(Self {
+            components,
+            ports,
+            schedule,
+            mix_out,
+            mix_in,
+            mono_out,
+        })
+    }
+
+    fn process(&mut self, frame: &mut [f32])
+    {
+        for (sample, port) in frame.iter().zip(self.mix_out.iter()) {
+            self.ports.set_port_value(port, *sample);
+        }
+
+        if let Some(port) = self.mono_out {
+            let sum: f32 = frame.iter().sum();
+            self.ports.set_port_value(&port, sum / frame.len() as f32);
+        }
+
+        for comp in &mut self.components {
+            comp.generate(&mut self.ports);
+        }
+
+        for (sample, port) in frame.iter_mut().zip(self.mix_in.iter()) {
+            *sample = self.ports.get_port_value(port);
+        }
+    }
+}
+
+/// The effects a patch runs after its voices are mixed together
+#[derive(Debug)]
+pub struct MasterGraph<'a> {
+    // None when the patch has no effects
+    graph: Option<Graph<'a>>,
+}
+
+impl<'a> MasterGraph<'a> {
+    pub fn new(patch: &Patch) -> Result<Self, PortManagerError>
+    {
+        if patch.effects.is_empty() {
+            return Ok(Self { graph: None });
+        }
+
+        Ok(Self { graph: Some(Graph::new(patch)?) })
+    }
+
+    pub fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let Some(graph) = self.graph.as_mut() {
+            for comp in &mut graph.components {
+                comp.handle_audio_property_change(prop);
+            }
+        }
+    }
+
+    /// Frames by which the effects delay the mix, along the slowest path
+    pub fn latency(&mut self) -> usize
+    {
+        self.graph.as_mut()
+            .map(|g| g.schedule.latency(&g.components))
+            .unwrap_or(0)
+    }
+
+    /// Run a frame of the mix through the effects, in place
+    pub fn process(&mut self, frame: &mut [f32])
+    {
+        if let Some(graph) = self.graph.as_mut() {
+            graph.process(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use components::PanConfig;
+    use testing::{connect_effect, delay};
+
+    /// The first few stereo frames out of the effects, after a click
+    fn impulse(patch: &Patch, click: [f32; 2]) -> Vec<[f32; 2]>
+    {
+        let mut graph = MasterGraph::new(patch).unwrap();
+        graph.handle_audio_property_change(AudioProperties::SampleRate(1000.0));
+
+        (0..8)
+            .map(|i| {
+                let mut frame = if i == 0 { click } else { [0.0, 0.0] };
+                graph.process(&mut frame);
+                frame
+            })
+            .collect()
+    }
+
+    /// Check that the effects can't be chained, because of the given effect
+    fn assert_not_chainable(patch: &Patch, name: &str, into: &str)
+    {
+        match MasterGraph::new(patch) {
+            Err(PortManagerError::NotChainable(e)) => {
+                let want = format!("effect {} has no samples_out to chain \
+                                    into {}", name, into);
+                assert!(e.starts_with(&want), "{}", e);
+                assert!(e.contains("connect-effect"), "{}", e);
+            },
+            res => panic!("chained anyway: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut patch = Patch::new();
+        patch.channels = 2;
+        patch.effects.push(Box::new(delay("a", 0.002, 1.0)));
+        patch.effects.push(Box::new(delay("b", 0.003, 1.0)));
+
+        // the delays add up, on the channels mixed down and sent to both
+        let out = impulse(&patch, [1.0, 0.5]);
+        assert_eq!(out[5], [0.75, 0.75]);
+        assert_eq!(out.iter().filter(|f| f[0] != 0.0).count(), 1);
+        assert_eq!(MasterGraph::new(&patch).unwrap().latency(), 0);
+    }
+
+    #[test]
+    fn test_stereo_chain() {
+        let mut patch = Patch::new();
+        patch.channels = 2;
+        patch.effects.push(Box::new(delay("a", 0.002, 1.0)));
+        patch.effects.push(Box::new(PanConfig { name: "pan".to_owned() }));
+
+        // the pan's own left and right reach the mix, centered
+        let out = impulse(&patch, [1.0, 0.5]);
+        let side = 0.75 * 0.5f32.sqrt();
+        assert!((out[2][0] - side).abs() < 1e-6);
+        assert!((out[2][1] - side).abs() < 1e-6);
+
+        // but a mono mix needs a samples_out, which the pan doesn't have
+        patch.channels = 1;
+        assert_not_chainable(&patch, "pan", "the mix");
+    }
+
+    #[test]
+    fn test_mono_after_stereo() {
+        // nothing can mix the pan back down into the delay
+        let mut patch = Patch::new();
+        patch.channels = 2;
+        patch.effects.push(Box::new(PanConfig { name: "pan".to_owned() }));
+        patch.effects.push(Box::new(delay("a", 0.002, 1.0)));
+        assert_not_chainable(&patch, "pan", "a");
+
+        // unless the patch says where each side goes
+        connect_effect(&mut patch, ("mix", "left_out"), ("pan", "samples_in"));
+        connect_effect(&mut patch, ("pan", "left_out"), ("a", "samples_in"));
+        connect_effect(&mut patch, ("a", "samples_out"), ("mix", "left_in"));
+        connect_effect(&mut patch, ("pan", "right_out"), ("mix", "right_in"));
+
+        let out = impulse(&patch, [1.0, 0.5]);
+        let side = 0.5f32.sqrt();
+        assert!((out[0][1] - side).abs() < 1e-6);
+        assert!((out[2][0] - side).abs() < 1e-6);
+        assert_eq!(out[0][0], 0.0);
+    }
+
+    #[test]
+    fn test_connections() {
+        // the channels swap sides, and only the left one is delayed
+        let mut patch = Patch::new();
+        patch.channels = 2;
+        patch.effects.push(Box::new(delay("wet", 0.002, 1.0)));
+        patch.effects.push(Box::new(delay("dry", 0.002, 0.0)));
+
+        connect_effect(&mut patch, ("mix", "left_out"), ("wet", "samples_in"));
+        connect_effect(&mut patch, ("wet", "samples_out"), ("mix", "right_in"));
+        connect_effect(&mut patch, ("mix", "right_out"), ("dry", "samples_in"));
+        connect_effect(&mut patch, ("dry", "samples_out"), ("mix", "left_in"));
+
+        let out = impulse(&patch, [1.0, 0.5]);
+        assert_eq!(out[0], [0.5, 0.0]);
+        assert_eq!(out[2], [0.0, 1.0]);
+        assert_eq!(out.iter().filter(|f| *f != &[0.0, 0.0]).count(), 2);
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut graph = MasterGraph::new(&Patch::new()).unwrap();
+        let mut frame = [0.25];
+        graph.process(&mut frame);
+        assert_eq!(frame, [0.25]);
+        assert_eq!(graph.latency(), 0);
+    }
+
+    #[test]
+    fn test_bad_effects() {
+        // two effects with the same name can't both have their ports
+        let mut patch = Patch::new();
+        patch.effects.push(Box::new(delay("a", 0.1, 1.0)));
+        patch.effects.push(Box::new(delay("a", 0.2, 1.0)));
+        assert!(MasterGraph::new(&patch).is_err());
+
+        // a mono mix has no left channel
+        let mut patch = Patch::new();
+        patch.effects.push(Box::new(delay("a", 0.1, 1.0)));
+        connect_effect(&mut patch, ("mix", "left_out"), ("a", "samples_in"));
+        assert!(MasterGraph::new(&patch).is_err());
+    }
+}
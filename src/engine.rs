@@ -0,0 +1,1035 @@
+// The audio engine
+// Everything the audio thread does, independent of the audio backend. For
+// every block, the backend queues up the block's MIDI events, then copies the
+// rendered (interleaved) frames out to wherever they need to go.
+//
+// The engine is controlled from other threads through an EngineHandle. All
+// communication goes over lock free queues, and anything the engine is done
+// with (like a replaced session) is sent back to the handle to be dropped, so
+// the audio thread never has to wait on a lock or free a large object.
+//
+// Components make room for their delays and buffers when they hear the sample
+// rate, so sessions are told the sample rate and buffer size before they reach
+// the audio thread: by `prepare` for the first one, and by the handle for
+// reloaded ones.
+
+use audioprops::AudioProperties;
+use events::{Control, Event, EventQueue};
+use mappings::{Binding, LearnError, Mapping};
+use midi::{MidiMessage, MidiStatus};
+use ports::PortDirectory;
+use session::Session;
+use transport::{BeatClock, MidiClock};
+use util::spsc;
+
+use std::cmp;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The longest block the engine has room for at first. A BlockSizer can make
+/// room for longer ones
+pub const MAX_BLOCK_FRAMES: usize = 8192;
+
+const QUEUE_SIZE: usize = 64;
+
+// length of the crossfade from an old session to a reloaded one
+const CROSSFADE_SECONDS: f32 = 0.05;
+
+pub type LearnResult = Result<Mapping, LearnError>;
+
+/// What the audio thread shares with the handle
+#[derive(Debug)]
+struct Published {
+    // latency of the session, in frames, for the backend to report
+    latency: AtomicUsize,
+    // the bits of the sample rate, and the buffer size. Zero until known
+    sample_rate: AtomicUsize,
+    buffer_size: AtomicUsize,
+}
+
+/// The outcome of a learn request, identified by the number the handle gave
+/// the request. Only the controller picked is sent back, the handle still has
+/// the rest of the mapping
+#[derive(Debug, Clone, Copy)]
+struct Learned {
+    request: usize,
+    cc: Result<u8, LearnError>,
+}
+
+#[derive(Debug)]
+enum Message<'a> {
+    Learn(usize, Binding<'a>),
+    Reload(Session<'a>),
+}
+
+/// Objects the audio thread is finished with
+#[derive(Debug)]
+enum Garbage<'a> {
+    Session(Session<'a>),
+    Block(Vec<f32>),
+}
+
+/// Fades out a session which has been replaced
+#[derive(Debug)]
+struct Crossfade<'a> {
+    old: Option<Session<'a>>,
+    // the old session's output, so mixing never allocates
+    frame: Vec<f32>,
+    remaining: usize,
+    length: usize,
+}
+
+impl<'a> Crossfade<'a> {
+    fn new(channels: usize) -> Self
+    {
+        Self {
+            old: None,
+            frame: vec![0.0; channels],
+            remaining: 0,
+            length: 0,
+        }
+    }
+
+    /// Start fading out a session. If a session was already fading out, it is
+    /// returned
+    fn start(&mut self, old: Session<'a>, length: usize)
+        -> Option<Session<'a>>
+    {
+        self.length = length;
+        self.remaining = length;
+        mem::replace(&mut self.old, Some(old))
+    }
+
+    /// Mix the fading session into a frame of the current one. Once the fade
+    /// is finished, the old session is returned
+    fn mix(&mut self, frame: &mut [f32]) -> Option<Session<'a>>
+    {
+        let done = match self.old {
+            Some(ref mut old) if self.remaining > 0 => {
+                let t = self.remaining as f32 / self.length as f32;
+                old.generate(&mut self.frame);
+                for (s, o) in frame.iter_mut().zip(self.frame.iter()) {
+                    *s = *s * (1.0 - t) + *o * t;
+                }
+
+                self.remaining -= 1;
+                self.remaining == 0
+            },
+            Some(_) => true,
+            None => false,
+        };
+
+        if done { self.old.take() } else { None }
+    }
+
+    fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let Some(ref mut old) = self.old {
+            old.handle_audio_property_change(prop);
+        }
+    }
+}
+
+/// The audio thread's half of the engine
+pub struct Engine<'a> {
+    session: Session<'a>,
+    channels: usize,
+    // after a reload, the old session keeps playing while it fades out
+    fade: Crossfade<'a>,
+    // remembered so that reloaded sessions can be told about them
+    sample_rate: Option<f32>,
+    buffer_size: Option<usize>,
+    // follows the transport for the same reason
+    beats: BeatClock,
+    midi_clock: MidiClock,
+    // frames rendered since the engine started
+    time: u64,
+
+    // interleaved frames for the current block
+    block: Vec<f32>,
+    block_frames: usize,
+    // the next frame of the block to render
+    position: usize,
+    events: EventQueue,
+    // MIDI sent by the session during the current block
+    midi_out: EventQueue,
+    published: Arc<Published>,
+
+    messages: spsc::Receiver<Message<'a>>,
+    properties: Vec<spsc::Receiver<AudioProperties>>,
+    // larger blocks, allocated on another thread
+    blocks: Option<spsc::Receiver<Vec<f32>>>,
+    learned: spsc::Sender<Learned>,
+    // the number of the learn request the session is working on
+    learn_request: usize,
+    garbage: spsc::Sender<Garbage<'a>>,
+}
+
+/// The control thread's half of the engine
+pub struct EngineHandle<'a> {
+    messages: spsc::Sender<Message<'a>>,
+    learned: spsc::Receiver<Learned>,
+    garbage: spsc::Receiver<Garbage<'a>>,
+    // the ports of the session being played, so mappings can be resolved
+    // here rather than on the audio thread
+    ports: Option<PortDirectory<'a>>,
+    // mappings waiting for a controller to be picked, by request number.
+    // Only the newest can still be learned, but the older ones may have been
+    // learned before the audio thread heard about the newer one
+    learning: Vec<(usize, Mapping)>,
+    next_request: usize,
+    published: Arc<Published>,
+    channels: usize,
+}
+
+/// Audio property changes usually come from a different thread than the rest
+/// of the control messages, so they get their own queues
+pub type PropertySender = spsc::Sender<AudioProperties>;
+
+/// Allocates room for longer blocks, away from the audio thread, whenever the
+/// backend's buffer size grows past what the engine can hold
+pub struct BlockSizer {
+    sender: spsc::Sender<Vec<f32>>,
+    channels: usize,
+    // the longest block the engine has been given room for
+    frames: usize,
+}
+
+impl BlockSizer {
+    /// Make sure the engine can render blocks of the given length. The new
+    /// room is used from the next block the engine starts
+    pub fn resize(&mut self, frames: usize) -> Result<(), String>
+    {
+        if frames <= self.frames {
+            return Ok(());
+        }
+
+        self.sender.send(vec![0.0; frames * self.channels])
+            .map_err(|_| "the audio thread is not keeping up".to_owned())?;
+
+        self.frames = frames;
+        Ok(())
+    }
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(mut session: Session<'a>)
+        -> (Self, EngineHandle<'a>, PropertySender)
+    {
+        let (message_sender, messages) = spsc::channel(QUEUE_SIZE);
+        let (property_sender, properties) = spsc::channel(QUEUE_SIZE);
+        let (learned_sender, learned) = spsc::channel(QUEUE_SIZE);
+        let (garbage_sender, garbage) = spsc::channel(QUEUE_SIZE);
+
+        let channels = session.channels();
+        let ports = session.port_directory();
+        let published = Arc::new(Published {
+            latency: AtomicUsize::new(session.latency()),
+            sample_rate: AtomicUsize::new(0),
+            buffer_size: AtomicUsize::new(0),
+        });
+
+        let engine = Self {
+            session,
+            channels,
+            fade: Crossfade::new(channels),
+            sample_rate: None,
+            buffer_size: None,
+            beats: BeatClock::new(),
+            midi_clock: MidiClock::new(),
+            time: 0,
+            block: vec![0.0; MAX_BLOCK_FRAMES * channels],
+            block_frames: 0,
+            position: 0,
+            events: EventQueue::new(),
+            midi_out: EventQueue::new(),
+            published: published.clone(),
+            messages,
+            properties: vec![properties],
+            blocks: None,
+            learned: learned_sender,
+            learn_request: 0,
+            garbage: garbage_sender,
+        };
+
+        let handle = EngineHandle {
+            messages: message_sender,
+            learned,
+            garbage,
+            ports,
+            learning: Vec::new(),
+            next_request: 0,
+            published,
+            channels,
+        };
+
+        (engine, handle, property_sender)
+    }
+
+    pub fn channels(&self) -> usize
+    {
+        self.channels
+    }
+
+    /// Apply an audio property (like the starting sample rate) to the engine
+    /// and its session. Must be called before the engine is handed to the
+    /// audio thread, which lets components allocate for the property here.
+    /// Once running, properties are sent through a PropertySender instead
+    pub fn prepare(&mut self, prop: AudioProperties)
+    {
+        self.set_property(prop);
+    }
+
+    /// Another queue for audio property changes, for a second source of them
+    /// (like a transport). Must be called before the engine is handed to the
+    /// audio thread
+    pub fn property_sender(&mut self) -> PropertySender
+    {
+        let (sender, receiver) = spsc::channel(QUEUE_SIZE);
+        self.properties.push(receiver);
+        sender
+    }
+
+    /// A way to hand the engine room for larger blocks. Must be called before
+    /// the engine is handed to the audio thread
+    pub fn block_sizer(&mut self) -> BlockSizer
+    {
+        let (sender, receiver) = spsc::channel(QUEUE_SIZE);
+        self.blocks = Some(receiver);
+        BlockSizer {
+            sender,
+            channels: self.channels,
+            frames: self.block.len() / self.channels,
+        }
+    }
+
+    /// Where the transport is, as far as the engine knows
+    pub fn beat_clock(&self) -> &BeatClock
+    {
+        &self.beats
+    }
+
+    /// Start rendering a new block of frames. Blocks longer than the engine
+    /// has room for are cut short, see `block_sizer`
+    pub fn start_block(&mut self, frames: usize)
+    {
+        self.handle_incoming();
+
+        self.block_frames = cmp::min(frames, self.block.len() / self.channels);
+        self.position = 0;
+        self.events.clear();
+        self.midi_out.clear();
+    }
+
+    /// Queue a MIDI message to be applied at a frame in the current block
+    /// Events can be queued in any order. Returns false if there is no room
+    /// for the event
+    pub fn push_midi(&mut self, frame: usize, data: &[u8]) -> bool
+    {
+        self.events.push_midi(frame, data)
+    }
+
+    /// Queue a control event to be applied at a frame in the current block
+    /// Returns false if there is no room for the event
+    pub fn push_control(&mut self, frame: usize, control: Control) -> bool
+    {
+        self.events.push(frame, control)
+    }
+
+    /// Render the block, applying each event just before its frame, and return
+    /// all of the frames, interleaved. Events past the end of the block are
+    /// applied after its last frame
+    pub fn finish_block(&mut self) -> &[f32]
+    {
+        // the queue is read while the engine changes, so it has to be moved
+        // out of the engine for a moment
+        let mut events =
+            mem::replace(&mut self.events, EventQueue::placeholder());
+
+        events.sort();
+        for i in 0..events.len() {
+            let (frame, event) = events.get(i);
+            self.run_until(frame);
+            self.handle_event(event);
+        }
+
+        self.events = events;
+
+        let frames = self.block_frames;
+        self.run_until(frames);
+
+        if let Some(res) = self.session.take_learned() {
+            let learned = Learned {
+                request: self.learn_request,
+                cc: res.map(|binding| binding.cc),
+            };
+
+            // if the queue is full, the result is dropped
+            let _ = self.learned.send(learned);
+        }
+
+        &self.block[..frames * self.channels]
+    }
+
+    /// The MIDI sent by the session during the last block, in order
+    /// Frames are counted from the start of the block
+    pub fn midi_output(&self) -> &EventQueue
+    {
+        &self.midi_out
+    }
+
+    /// Render the block up to (but not including) the given frame
+    fn run_until(&mut self, frame: usize)
+    {
+        let channels = self.channels;
+        let end = cmp::min(frame, self.block_frames);
+
+        while self.position < end {
+            let i = self.position;
+            let frame = &mut self.block[i * channels..(i + 1) * channels];
+            self.session.generate(frame);
+            self.session.drain_midi(i, &mut self.midi_out);
+            if let Some(old) = self.fade.mix(frame) {
+                self.throw_away(Garbage::Session(old));
+            }
+
+            self.beats.tick();
+            self.time += 1;
+            self.position += 1;
+        }
+    }
+
+    fn handle_event(&mut self, event: Event)
+    {
+        match event {
+            Event::Midi(data) => self.handle_midi(data),
+
+            Event::Control(Control::NoteOn { channel, note, velocity }) => {
+                self.session.note_on(channel, note, velocity);
+            },
+
+            Event::Control(Control::NoteOff { channel, note }) => {
+                self.session.note_off(channel, note);
+            },
+
+            Event::Control(Control::ControlChange { channel, cc, value }) => {
+                self.session.control_value_change(channel, cc, value);
+            },
+        }
+    }
+
+    fn handle_midi(&mut self, data: &[u8])
+    {
+        let m = MidiMessage { data };
+        let channel = m.channel().unwrap_or(0);
+        match (m.status(), data.len()) {
+            (Some(MidiStatus::NoteOff), 3) => {
+                self.session.note_off(channel, data[1]);
+            },
+
+            // a note on with no velocity is really a note off
+            (Some(MidiStatus::NoteOn), 3) if data[2] == 0 => {
+                self.session.note_off(channel, data[1]);
+            },
+
+            (Some(MidiStatus::NoteOn), 3) => {
+                self.session.note_on(channel, data[1], data[2]);
+            },
+
+            (Some(MidiStatus::ControlChange), 3) => {
+                self.session.control_value_change(channel, data[1], data[2]);
+            },
+
+            (Some(MidiStatus::SysExStart), _) => {
+                self.session.handle_sysex(data);
+            },
+
+            (Some(MidiStatus::TimingClock), _) |
+            (Some(MidiStatus::Start), _) |
+            (Some(MidiStatus::Continue), _) |
+            (Some(MidiStatus::Stop), _) |
+            (Some(MidiStatus::SongPositionPointer), _) => {
+                let changes = self.midi_clock.handle(data, self.time);
+                for p in changes.iter().filter_map(|p| *p) {
+                    self.set_property(p);
+                }
+            },
+
+            _ => (),
+        }
+    }
+
+    fn set_property(&mut self, p: AudioProperties)
+    {
+        match p {
+            AudioProperties::SampleRate(r) => {
+                self.sample_rate = Some(r);
+                let bits = r.to_bits() as usize;
+                self.published.sample_rate.store(bits, Ordering::Relaxed);
+            },
+
+            AudioProperties::BufferSize(n) => {
+                self.buffer_size = Some(n);
+                self.published.buffer_size.store(n, Ordering::Relaxed);
+            },
+
+            _ => (),
+        }
+
+        self.beats.handle_audio_property_change(p);
+        self.midi_clock.handle_audio_property_change(p);
+        self.session.handle_audio_property_change(p);
+        self.fade.handle_audio_property_change(p);
+        self.update_latency();
+    }
+
+    /// Components may change their latency when the properties change, or
+    /// the session may be replaced
+    fn update_latency(&mut self)
+    {
+        let latency = self.session.latency();
+        self.published.latency.store(latency, Ordering::Relaxed);
+    }
+
+    fn handle_incoming(&mut self)
+    {
+        let mut larger = None;
+        if let Some(ref mut blocks) = self.blocks {
+            while let Some(block) = blocks.recv() {
+                if block.len() > self.block.len() {
+                    larger = Some(block);
+                }
+            }
+        }
+
+        if let Some(block) = larger {
+            let old = mem::replace(&mut self.block, block);
+            self.throw_away(Garbage::Block(old));
+        }
+
+        for i in 0..self.properties.len() {
+            while let Some(p) = self.properties[i].recv() {
+                self.set_property(p);
+            }
+        }
+
+        while let Some(m) = self.messages.recv() {
+            match m {
+                Message::Learn(request, binding) => {
+                    self.learn_request = request;
+                    self.session.learn(binding);
+                },
+
+                Message::Reload(session) => self.reload(session),
+            }
+        }
+    }
+
+    /// Start playing a new session, crossfading from the current one. The
+    /// handle has already told the session about the sample rate and buffer
+    /// size, so this only allocates if they changed on the way
+    fn reload(&mut self, mut session: Session<'a>)
+    {
+        if let Some(r) = self.sample_rate {
+            let prop = AudioProperties::SampleRate(r);
+            session.handle_audio_property_change(prop);
+        }
+
+        if let Some(n) = self.buffer_size {
+            let prop = AudioProperties::BufferSize(n);
+            session.handle_audio_property_change(prop);
+        }
+
+        for &p in self.beats.snapshot().iter() {
+            session.handle_audio_property_change(p);
+        }
+
+        let old = mem::replace(&mut self.session, session);
+        let rate = self.sample_rate.unwrap_or(48000.0);
+        let length = (rate * CROSSFADE_SECONDS) as usize;
+
+        if let Some(older) = self.fade.start(old, length) {
+            self.throw_away(Garbage::Session(older));
+        }
+
+        self.update_latency();
+    }
+
+    fn throw_away(&mut self, garbage: Garbage<'a>)
+    {
+        // if the queue is full, there's no choice but to drop it here
+        let _ = self.garbage.send(garbage);
+    }
+}
+
+impl<'a> EngineHandle<'a> {
+    /// Ask the engine to bind the next controller that moves to the mapping's
+    /// port. The outcome is available from `learned`
+    pub fn learn(&mut self, mapping: Mapping) -> Result<(), String>
+    {
+        let handle = match self.ports {
+            Some(ref ports) => ports.find_input_port(&mapping.port)
+                .map_err(|e| format!("{:?}", e))?,
+            None => return Err("nothing to learn controllers for".to_owned()),
+        };
+
+        let request = self.next_request;
+        let binding = Binding::new(&mapping, handle);
+        self.messages.send(Message::Learn(request, binding))
+            .map_err(|_| "the audio thread is not keeping up".to_owned())?;
+
+        self.learning.push((request, mapping));
+        self.next_request += 1;
+        Ok(())
+    }
+
+    /// The outcome of a previous learn request, if one is available
+    pub fn learned(&mut self) -> Option<LearnResult>
+    {
+        while let Some(learned) = self.learned.recv() {
+            let i = self.learning.iter()
+                .position(|&(request, _)| request == learned.request);
+
+            if let Some(i) = i {
+                // anything asked for before this can't be learned any more
+                let (_, mut mapping) = self.learning.remove(i);
+                self.learning.retain(|&(r, _)| r > learned.request);
+
+                return Some(learned.cc.map(|cc| {
+                    mapping.cc = cc;
+                    mapping
+                }));
+            }
+        }
+
+        None
+    }
+
+    /// Replace the session being played, crossfading from the old session to
+    /// the new one. The new session must have the same number of channels as
+    /// the old one
+    pub fn reload(&mut self, mut session: Session<'a>) -> Result<(), String>
+    {
+        if session.channels() != self.channels {
+            return Err(format!(
+                    "can't change from {} to {} channels without restarting",
+                    self.channels, session.channels()));
+        }
+
+        // so the session makes room for them here, not on the audio thread
+        let rate = self.published.sample_rate.load(Ordering::Relaxed);
+        if rate != 0 {
+            let prop = AudioProperties::SampleRate(f32::from_bits(rate as u32));
+            session.handle_audio_property_change(prop);
+        }
+
+        let size = self.published.buffer_size.load(Ordering::Relaxed);
+        if size != 0 {
+            let prop = AudioProperties::BufferSize(size);
+            session.handle_audio_property_change(prop);
+        }
+
+        let ports = session.port_directory();
+        self.messages.send(Message::Reload(session))
+            .map_err(|_| "the audio thread is not keeping up".to_owned())?;
+
+        // learn requests sent from now on are handled by the new session
+        self.ports = ports;
+        Ok(())
+    }
+
+    /// Frames by which the engine's output lags the events it is sent. Kept
+    /// up to date by the audio thread
+    pub fn latency(&self) -> usize
+    {
+        self.published.latency.load(Ordering::Relaxed)
+    }
+
+    /// Drop everything the engine is finished with. Returns the number of
+    /// objects dropped
+    pub fn collect_garbage(&mut self) -> usize
+    {
+        let mut count = 0;
+        while let Some(garbage) = self.garbage.recv() {
+            match garbage {
+                Garbage::Session(s) => mem::drop(s),
+                Garbage::Block(b) => mem::drop(b),
+            }
+
+            count += 1;
+        }
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use components::{NoteOutConfig, OnOffConfig, SineWaveOscillatorConfig};
+    use patch::Patch;
+    use ports::PortName;
+    use session::{Part, PartConfig, SessionConfig};
+    use soundscape::Soundscape;
+    use testing::{connect, lookahead};
+    use util::alloc::count_allocations;
+
+    use std::fs;
+    use std::path::Path;
+
+    fn sine_session() -> Session<'static>
+    {
+        let mut patch = Patch::new();
+        patch.components.push(Box::new(SineWaveOscillatorConfig {
+            name: "sine".to_owned(),
+            frequency_input_name: "frequency_in".to_owned(),
+            samples_output_name: "samples_out".to_owned(),
+        }));
+        patch.components.push(
+            Box::new(OnOffConfig { name: "onoff".to_owned() }));
+
+        connect(&mut patch,
+                ("voice", "midi_frequency_out"), ("sine", "frequency_in"));
+        connect(&mut patch, ("voice", "midi_gate_out"), ("onoff", "gate_in"));
+        connect(&mut patch, ("sine", "samples_out"), ("onoff", "samples_in"));
+        connect(&mut patch, ("onoff", "samples_out"), ("voice", "samples_in"));
+
+        let config = PartConfig::new("sine", Path::new("sine.patch"), 4);
+        let part = Part::new(config, Soundscape::new(4, patch).unwrap());
+        Session::new(vec![part])
+    }
+
+    fn render(engine: &mut Engine, events: &[(usize, &[u8])]) -> Vec<f32>
+    {
+        engine.start_block(64);
+        for &(frame, data) in events {
+            assert!(engine.push_midi(frame, data));
+        }
+
+        engine.finish_block().to_vec()
+    }
+
+    #[test]
+    fn test_note_starts_at_event() {
+        let (mut engine, _handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        let block = render(&mut engine, &[(10, &[0x90, 69, 100])]);
+        assert!(block[..11].iter().all(|&s| s == 0.0));
+        assert!(block[11..].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn test_events_out_of_order() {
+        let (mut engine, _handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        let block = render(&mut engine, &[
+            (40, &[0x80, 69, 0]),
+            (10, &[0x90, 69, 100]),
+        ]);
+
+        assert!(block[..11].iter().all(|&s| s == 0.0));
+        assert!(block[11..40].iter().any(|&s| s != 0.0));
+        assert!(block[40..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_events_at_same_frame() {
+        let (mut engine, _handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        // applied in the order they were queued
+        let block = render(&mut engine, &[
+            (10, &[0x90, 69, 100]),
+            (10, &[0x80, 69, 0]),
+        ]);
+        assert!(block.iter().all(|&s| s == 0.0));
+
+        let block = render(&mut engine, &[
+            (10, &[0x80, 69, 0]),
+            (10, &[0x90, 69, 100]),
+        ]);
+        assert!(block[11..].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn test_control_events() {
+        let (mut engine, _handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        engine.start_block(64);
+        engine.push_control(20, Control::NoteOff { channel: 0, note: 69 });
+        engine.push_control(
+            0, Control::NoteOn { channel: 0, note: 69, velocity: 100 });
+        let block = engine.finish_block();
+
+        assert!(block[..20].iter().any(|&s| s != 0.0));
+        assert!(block[20..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_late_events_apply_after_block() {
+        let (mut engine, _handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        let block = render(&mut engine, &[(100, &[0x90, 69, 100])]);
+        assert!(block.iter().all(|&s| s == 0.0));
+
+        let block = render(&mut engine, &[]);
+        assert!(block[1..].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn test_midi_output() {
+        let mut patch = Patch::new();
+        patch.components.push(Box::new(NoteOutConfig {
+            name: "thru".to_owned(),
+            channel: 2,
+        }));
+
+        connect(&mut patch,
+                ("voice", "midi_frequency_out"), ("thru", "frequency_in"));
+        connect(&mut patch, ("voice", "midi_gate_out"), ("thru", "gate_in"));
+        connect(&mut patch,
+                ("voice", "midi_velocity_out"), ("thru", "velocity_in"));
+
+        let config = PartConfig::new("thru", Path::new("thru.patch"), 4);
+        let part = Part::new(config, Soundscape::new(4, patch).unwrap());
+        let session = Session::new(vec![part]);
+
+        let (mut engine, _handle, _props) = Engine::new(session);
+        render(&mut engine, &[
+            (10, &[0x90, 69, 100]),
+            (30, &[0x80, 69, 0]),
+        ]);
+
+        let out = engine.midi_output();
+        assert_eq!(out.len(), 2);
+        assert_eq!(out.get(0), (10, Event::Midi(&[0x91, 69, 100])));
+        assert_eq!(out.get(1), (30, Event::Midi(&[0x81, 69, 0])));
+
+        render(&mut engine, &[]);
+        assert!(engine.midi_output().is_empty());
+    }
+
+    #[test]
+    fn test_midi_clock() {
+        let (mut engine, _handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        // 100 beats per minute is a clock tick every 1200 frames
+        engine.start_block(1200);
+        engine.push_midi(0, &[0xFA]);
+        engine.push_midi(0, &[0xF8]);
+        engine.finish_block();
+
+        for _ in 0..47 {
+            engine.start_block(1200);
+            engine.push_midi(0, &[0xF8]);
+            engine.finish_block();
+        }
+
+        let beats = engine.beat_clock();
+        assert!(beats.is_rolling());
+        assert_eq!(beats.tempo(), 100.0);
+        // 48 ticks is two beats, near enough. The first tick doesn't say
+        // anything about the tempo, so the default was used until the second
+        assert!((beats.position() - 2.0).abs() < 0.01);
+
+        render(&mut engine, &[(0, &[0xFC])]);
+        assert!(!engine.beat_clock().is_rolling());
+    }
+
+    #[test]
+    fn test_reload_sends_old_session_back() {
+        let (mut engine, mut handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        handle.reload(sine_session()).unwrap();
+        render(&mut engine, &[]);
+        assert_eq!(handle.collect_garbage(), 0);
+
+        // once the crossfade is over, the old session is handed back
+        for _ in 0..(48000.0 * CROSSFADE_SECONDS) as usize / 64 + 1 {
+            render(&mut engine, &[]);
+        }
+        assert_eq!(handle.collect_garbage(), 1);
+    }
+
+    #[test]
+    fn test_longer_blocks() {
+        let (mut engine, mut handle, _props) = Engine::new(sine_session());
+        let mut sizer = engine.block_sizer();
+
+        // without room, the block is cut short
+        let frames = MAX_BLOCK_FRAMES + 100;
+        engine.start_block(frames);
+        assert_eq!(engine.finish_block().len(), MAX_BLOCK_FRAMES);
+
+        sizer.resize(frames).unwrap();
+        let count = count_allocations(|| {
+            engine.start_block(frames);
+            assert_eq!(engine.finish_block().len(), frames);
+        });
+
+        assert_eq!(count, 0);
+        assert_eq!(handle.collect_garbage(), 1);
+
+        // there's already room for anything shorter
+        sizer.resize(64).unwrap();
+        engine.start_block(64);
+        engine.finish_block();
+        assert_eq!(handle.collect_garbage(), 0);
+    }
+
+    #[test]
+    fn test_latency() {
+        let session = |frames| {
+            let mut patch = Patch::new();
+            lookahead(&mut patch, "a", frames);
+            connect(&mut patch,
+                    ("voice", "midi_gate_out"), ("a", "samples_in"));
+            connect(&mut patch, ("a", "samples_out"), ("voice", "samples_in"));
+
+            let config = PartConfig::new("a", Path::new("a.patch"), 1);
+            let soundscape = Soundscape::new(1, patch).unwrap();
+            Session::new(vec![Part::new(config, soundscape)])
+        };
+
+        let (mut engine, mut handle, _props) = Engine::new(session(10));
+        assert_eq!(handle.latency(), 10);
+
+        // picked up once the engine swaps the new session in
+        handle.reload(session(20)).unwrap();
+        assert_eq!(handle.latency(), 10);
+        render(&mut engine, &[]);
+        assert_eq!(handle.latency(), 20);
+    }
+
+    #[test]
+    fn test_learn() {
+        let (mut engine, mut handle, _props) = Engine::new(sine_session());
+
+        let missing = PortName::new("sine", "nothing_in");
+        assert!(handle.learn(Mapping::new(0, missing)).is_err());
+
+        // only the newest request is learned
+        let gate = PortName::new("onoff", "gate_in");
+        let samples = PortName::new("onoff", "samples_in");
+        handle.learn(Mapping::new(0, gate)).unwrap();
+        handle.learn(Mapping::new(0, samples.clone())).unwrap();
+
+        // data entry can't be saved as a mapping, so it isn't learned
+        render(&mut engine, &[(10, &[0xB0, 6, 64])]);
+        assert_eq!(handle.learned(), None);
+
+        render(&mut engine, &[(10, &[0xB0, 7, 64])]);
+        assert_eq!(handle.learned(), Some(Ok(Mapping::new(7, samples))));
+        assert_eq!(handle.learned(), None);
+    }
+
+    #[test]
+    fn test_process_does_not_allocate() {
+        let (mut engine, mut handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        let note_on: &[u8] = &[0x90, 60, 100];
+        let note_off: &[u8] = &[0x80, 60, 0];
+        let control: &[u8] = &[0xB0, 1, 64];
+
+        // the new session is built here, outside of the audio thread
+        handle.reload(sine_session()).unwrap();
+
+        let count = count_allocations(|| {
+            for _ in 0..100 {
+                engine.start_block(64);
+                engine.push_midi(40, note_off);
+                engine.push_midi(5, note_on);
+                engine.push_midi(20, control);
+                engine.finish_block();
+            }
+        });
+
+        assert_eq!(count, 0);
+        assert_eq!(handle.collect_garbage(), 1);
+    }
+
+    #[test]
+    fn test_learn_and_steal_do_not_allocate() {
+        let (mut engine, mut handle, mut props) = Engine::new(sine_session());
+        props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+
+        let gate = PortName::new("onoff", "gate_in");
+        handle.learn(Mapping::new(0, gate.clone())).unwrap();
+
+        let count = count_allocations(|| {
+            // more notes than voices, so the oldest are stolen
+            for note in 60..68 {
+                engine.start_block(64);
+                engine.push_midi(10, &[0x90, note, 100]);
+                engine.push_midi(20, &[0xB0, note - 50, 64]);
+                engine.finish_block();
+            }
+        });
+
+        assert_eq!(count, 0);
+        assert_eq!(handle.learned(), Some(Ok(Mapping::new(10, gate))));
+    }
+
+    // anything the engine does for a block of notes, over a few channels
+    fn play_notes(engine: &mut Engine)
+    {
+        for note in 36..84 {
+            for channel in 0..2 {
+                let on = [0x90 | channel, note, 100];
+                let off = [0x80 | channel, note, 0];
+                let control = [0xB0 | channel, 1, note];
+
+                engine.start_block(64);
+                engine.push_midi(note as usize % 64, &on);
+                engine.push_midi(note as usize % 64, &control);
+                engine.finish_block();
+
+                engine.start_block(64);
+                engine.push_midi(32, &off);
+                engine.finish_block();
+            }
+        }
+    }
+
+    #[test]
+    fn test_example_patches_do_not_allocate() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/patches");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let config = match path.extension().and_then(|e| e.to_str()) {
+                Some("patch") => SessionConfig::single(&path, 4),
+                Some("session") => SessionConfig::from_file(&path).unwrap(),
+                _ => continue,
+            };
+
+            // set up the way the JACK backend does it
+            let session = Session::from_config(&config).unwrap();
+            let (mut engine, mut handle, mut props) = Engine::new(session);
+            engine.prepare(AudioProperties::SampleRate(48000.0));
+            engine.prepare(AudioProperties::BufferSize(64));
+
+            // JACK tells the running engine the sample rate again
+            props.send(AudioProperties::SampleRate(48000.0)).unwrap();
+            let count = count_allocations(|| play_notes(&mut engine));
+            assert!(count == 0,
+                    "{} allocated {} times", path.display(), count);
+
+            // and the reloaded session, crossfading from the old one
+            let session = Session::from_config(&config).unwrap();
+            handle.reload(session).unwrap();
+            let count = count_allocations(|| play_notes(&mut engine));
+            assert!(count == 0,
+                    "{} allocated {} times after reloading",
+                    path.display(), count);
+            assert_eq!(handle.collect_garbage(), 1);
+        }
+    }
+}
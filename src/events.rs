@@ -0,0 +1,202 @@
+// Per-block event queue
+// Events for a block can arrive in any order, from MIDI ports or from the
+// engine itself. They are collected here, then sorted and applied at their
+// exact frames while the block is rendered. Events at the same frame are
+// applied in the order they were pushed.
+//
+// All of the memory is allocated up front, so events can be queued from the
+// audio thread. When the queue is full, new events are rejected.
+
+/// Most events a single block can hold
+pub const MAX_EVENTS: usize = 1024;
+
+/// Most bytes of MIDI data a single block can hold
+pub const MAX_MIDI_BYTES: usize = 16 * 1024;
+
+/// Events that come from inside the synth, not from a MIDI port
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    ControlChange { channel: u8, cc: u8, value: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event<'a> {
+    /// A raw MIDI message
+    Midi(&'a [u8]),
+    Control(Control),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Payload {
+    // range of the message in the byte buffer
+    Midi(usize, usize),
+    Control(Control),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Queued {
+    frame: usize,
+    // position in the queue, to keep the sort stable
+    order: usize,
+    payload: Payload,
+}
+
+#[derive(Debug)]
+pub struct EventQueue {
+    events: Vec<Queued>,
+    midi: Vec<u8>,
+}
+
+impl EventQueue {
+    pub fn new() -> Self
+    {
+        Self::with_capacity(MAX_EVENTS, MAX_MIDI_BYTES)
+    }
+
+    /// A queue with room for fewer (or more) events than a whole block
+    pub fn with_capacity(events: usize, midi_bytes: usize) -> Self
+    {
+        Self {
+            events: Vec::with_capacity(events),
+            midi: Vec::with_capacity(midi_bytes),
+        }
+    }
+
+    /// A queue with no room for any events. Doesn't allocate, so it can stand
+    /// in for a real queue while that queue is being read
+    pub fn placeholder() -> Self
+    {
+        Self {
+            events: Vec::new(),
+            midi: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.events.is_empty()
+    }
+
+    /// Queue a MIDI message to be applied before the given frame is rendered
+    /// Returns false if the queue is full
+    pub fn push_midi(&mut self, frame: usize, data: &[u8]) -> bool
+    {
+        if self.midi.len() + data.len() > self.midi.capacity() {
+            return false;
+        }
+
+        let start = self.midi.len();
+        if !self.push_payload(frame, Payload::Midi(start, data.len())) {
+            return false;
+        }
+
+        self.midi.extend_from_slice(data);
+        true
+    }
+
+    /// Queue a control event to be applied before the given frame is rendered
+    /// Returns false if the queue is full
+    pub fn push(&mut self, frame: usize, control: Control) -> bool
+    {
+        self.push_payload(frame, Payload::Control(control))
+    }
+
+    fn push_payload(&mut self, frame: usize, payload: Payload) -> bool
+    {
+        if self.events.len() == self.events.capacity() {
+            return false;
+        }
+
+        let order = self.events.len();
+        self.events.push(Queued { frame, order, payload });
+        true
+    }
+
+    /// Put the events in the order they should be applied
+    pub fn sort(&mut self)
+    {
+        // the stable sort allocates, so sort on the push order as well
+        self.events.sort_unstable_by_key(|e| (e.frame, e.order));
+    }
+
+    /// The i-th event and its frame. Only in order after `sort`
+    pub fn get(&self, i: usize) -> (usize, Event)
+    {
+        let e = &self.events[i];
+        let event = match e.payload {
+            Payload::Midi(start, len) =>
+                Event::Midi(&self.midi[start..start + len]),
+            Payload::Control(c) => Event::Control(c),
+        };
+
+        (e.frame, event)
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.events.clear();
+        self.midi.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frames(q: &EventQueue) -> Vec<usize>
+    {
+        (0..q.len()).map(|i| q.get(i).0).collect()
+    }
+
+    #[test]
+    fn test_sorted_by_frame() {
+        let mut q = EventQueue::new();
+        q.push_midi(30, &[0x90, 60, 100]);
+        q.push(10, Control::NoteOff { channel: 0, note: 60 });
+        q.push_midi(20, &[0xF0, 0x7E, 0x7F, 0xF7]);
+        q.sort();
+
+        assert_eq!(frames(&q), vec![10, 20, 30]);
+        assert_eq!(q.get(0).1,
+                   Event::Control(Control::NoteOff { channel: 0, note: 60 }));
+        assert_eq!(q.get(1).1, Event::Midi(&[0xF0, 0x7E, 0x7F, 0xF7]));
+        assert_eq!(q.get(2).1, Event::Midi(&[0x90, 60, 100]));
+    }
+
+    #[test]
+    fn test_same_frame_keeps_push_order() {
+        let mut q = EventQueue::new();
+        for note in 0..100 {
+            q.push_midi(5, &[0x90, note, 100]);
+        }
+        q.push_midi(0, &[0x80, 1, 0]);
+        q.sort();
+
+        assert_eq!(q.get(0).1, Event::Midi(&[0x80, 1, 0]));
+        for note in 0..100 {
+            assert_eq!(q.get(note as usize + 1).1,
+                       Event::Midi(&[0x90, note, 100]));
+        }
+    }
+
+    #[test]
+    fn test_full() {
+        let mut q = EventQueue::new();
+        for _ in 0..MAX_EVENTS {
+            assert!(q.push_midi(0, &[0x90, 60, 100]));
+        }
+        assert!(!q.push_midi(0, &[0x80, 60, 0]));
+
+        q.clear();
+        assert!(q.is_empty());
+        assert!(!q.push_midi(0, &vec![0; MAX_MIDI_BYTES + 1]));
+        assert!(q.is_empty());
+    }
+}
@@ -1,189 +1,618 @@
-use midi::{MidiMessage, MidiStatus};
-use soundscape::Soundscape;
+// The JACK backend
+// The client is opened with the raw JACK API. Everything the synth needs from
+// JACK (MIDI output, the transport, latency reporting) happens on this one
+// client, so it all lines up with the audio in the same process callback.
+//
+// The process callback owns the engine. The notification callbacks (sample
+// rate, buffer size, xruns, latency) run on JACK's other threads, and only
+// talk to the engine through its property queues.
+
 use audioprops::AudioProperties;
+use engine::{BlockSizer, Engine, EngineHandle, LearnResult, PropertySender};
+use events::{Event, EventQueue};
+use mappings::Mapping;
+use session::Session;
+use transport::{TransportFollower, TransportPosition};
+use util::alloc;
+
+use jack_sys;
+
+use std::cmp;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::raw::{c_int, c_ulong, c_void};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use jack;
+type Port = *mut jack_sys::jack_port_t;
 
-use std;
-use std::mem;
-use std::sync::mpsc;
+const AUDIO_TYPE: &str = "32 bit float mono audio";
+
+/// Name of the JACK output port for the given channel. Channels are counted
+/// from 1, to match the system playback ports
+fn output_port_name(channels: usize, channel: usize) -> String
+{
+    if channels == 1 {
+        "audio_out".to_owned()
+    } else {
+        format!("audio_out_{}", channel)
+    }
+}
+
+struct Transport {
+    follower: TransportFollower,
+    sender: PropertySender,
+}
+
+/// Everything the process callback needs
+struct ProcessState<'a> {
+    client: *mut jack_sys::jack_client_t,
+    midi_in: Port,
+    outputs: Vec<Port>,
+    midi_out: Option<Port>,
+    transport: Option<Transport>,
+    // I own the engine, which owns the session
+    engine: Engine<'a>,
+}
+
+impl<'a> ProcessState<'a> {
+    unsafe fn process(&mut self, nframes: jack_sys::jack_nframes_t)
+    {
+        // the transport is read first, so that its changes apply to this block
+        if let Some(ref mut transport) = self.transport {
+            follow_transport(self.client, transport, nframes);
+        }
 
-type OPort = jack::OutputPortHandle<jack::DefaultAudioSample>;
-type IPort = jack::InputPortHandle<jack::MidiEvent>;
+        self.engine.start_block(nframes as usize);
 
-fn midi_note_to_frequency(note: u8) -> f32
+        let buffer = jack_sys::jack_port_get_buffer(self.midi_in, nframes);
+        for i in 0..jack_sys::jack_midi_get_event_count(buffer) {
+            let mut event: jack_sys::jack_midi_event_t = mem::zeroed();
+            if jack_sys::jack_midi_event_get(&mut event, buffer, i) != 0 {
+                continue;
+            }
+
+            // if the block is flooded with events, the rest are dropped
+            let data = slice::from_raw_parts(event.buffer, event.size);
+            self.engine.push_midi(event.time as usize, data);
+        }
+
+        let channels = self.engine.channels();
+        let block = self.engine.finish_block();
+        // only short if the engine wasn't given room for the block in time
+        let rendered = block.len() / channels;
+        for (c, &port) in self.outputs.iter().enumerate() {
+            let buffer = jack_sys::jack_port_get_buffer(port, nframes);
+            let buffer = slice::from_raw_parts_mut(
+                buffer as *mut f32, nframes as usize);
+
+            for (i, sample) in buffer.iter_mut().enumerate() {
+                *sample = if i < rendered {
+                    block[i * channels + c]
+                } else {
+                    0.0
+                };
+            }
+        }
+
+        if let Some(port) = self.midi_out {
+            write_midi_out(port, self.engine.midi_output(), nframes);
+        }
+    }
+}
+
+unsafe fn write_midi_out(
+    port: Port,
+    out: &EventQueue,
+    nframes: jack_sys::jack_nframes_t)
 {
-    let a = 440.0;
-    // this is a magic formula from the internet
-    (a / 32.0) * (2.0_f32.powf((note as f32 - 9.0) / 12.0))
+    let buffer = jack_sys::jack_port_get_buffer(port, nframes);
+    jack_sys::jack_midi_clear_buffer(buffer);
+
+    // the engine's MIDI output is already in order, and inside the block
+    for i in 0..out.len() {
+        if let (frame, Event::Midi(data)) = out.get(i) {
+            let frame = cmp::min(frame, nframes as usize - 1);
+
+            // if the buffer is full, the message is dropped
+            jack_sys::jack_midi_event_write(
+                buffer,
+                frame as jack_sys::jack_nframes_t,
+                data.as_ptr(),
+                data.len());
+        }
+    }
 }
 
-fn midi_velocity_to_velocity(vel: u8) -> f32
+unsafe fn follow_transport(
+    client: *mut jack_sys::jack_client_t,
+    transport: &mut Transport,
+    nframes: jack_sys::jack_nframes_t)
 {
-    vel as f32 / (std::u8::MAX as f32)
+    let mut pos: jack_sys::jack_position_t = mem::zeroed();
+    let state = jack_sys::jack_transport_query(client, &mut pos);
+
+    let bbt = pos.valid & jack_sys::JackPositionBBT != 0;
+    let beats = (pos.bar as f64 - 1.0) * pos.beats_per_bar as f64
+        + (pos.beat as f64 - 1.0)
+        + pos.tick as f64 / pos.ticks_per_beat;
+
+    let pos = TransportPosition {
+        // a transport which is starting is still waiting for slow clients
+        rolling: state == jack_sys::JackTransportRolling,
+        frame: pos.frame as u64,
+        frame_rate: pos.frame_rate,
+        tempo: if bbt { Some(pos.beats_per_minute as f32) } else { None },
+        time_signature: if bbt {
+            Some((pos.beats_per_bar, pos.beat_type))
+        } else {
+            None
+        },
+        beats: if bbt { Some(beats) } else { None },
+    };
+
+    for prop in transport.follower.update(pos, nframes as u64).iter() {
+        if let Some(prop) = *prop {
+            // if the engine isn't keeping up, the change is dropped
+            let _ = transport.sender.send(prop);
+        }
+    }
 }
 
-#[derive(Debug)]
-enum Message {
-    AudioProperties(AudioProperties),
+unsafe extern "C" fn process(
+    nframes: jack_sys::jack_nframes_t,
+    arg: *mut c_void)
+-> c_int
+{
+    let state = &mut *(arg as *mut ProcessState);
+
+    // with the alloc-audit feature, any allocation in here aborts
+    alloc::realtime(|| state.process(nframes));
+    0
 }
 
-struct AudioHandler<'a> {
-    input: IPort,
-    output: OPort,
-    // I own the soundscape
-    soundscape: Soundscape<'a>,
-    // Any additional soundscape operations will be sent over a queue to this
-    // thread, instead of requiring the soundscape to manage any synchronization
-    incoming: mpsc::Receiver<Message>
+/// Everything the notification callbacks need
+struct NotificationState {
+    sender: alloc::Mutex<PropertySender>,
+    sizer: alloc::Mutex<BlockSizer>,
+    xruns: AtomicUsize,
+    // the engine's latency, as last published
+    latency: AtomicUsize,
+    midi_in: Port,
+    outputs: Vec<Port>,
 }
 
-impl<'a> AudioHandler<'a> {
-    pub fn new(
-        input: IPort,
-        output: OPort,
-        soundscape: Soundscape<'a>,
-        incoming: mpsc::Receiver<Message>)
-    -> Self
+impl NotificationState {
+    fn send(&self, prop: AudioProperties) -> c_int
     {
-        Self {
-            input,
-            output,
-            soundscape,
-            incoming,
+        match self.sender.lock().send(prop) {
+            Ok(()) => 0,
+            Err(_) => 1, // the audio thread isn't keeping up
         }
     }
 
-    fn handle_incoming(&mut self)
+    fn buffer_size_changed(&self, frames: usize) -> c_int
     {
-        while let Ok(m) = self.incoming.try_recv() {
-            match m {
-                Message::AudioProperties(p) =>
-                    self.soundscape.handle_audio_property_change(p),
+        // the room for the new blocks is allocated here, not on the audio
+        // thread
+        if self.sizer.lock().resize(frames).is_err() {
+            return 1;
+        }
+
+        self.send(AudioProperties::BufferSize(frames))
+    }
+}
+
+unsafe extern "C" fn sample_rate_changed(
+    nframes: jack_sys::jack_nframes_t,
+    arg: *mut c_void)
+-> c_int
+{
+    let state = &*(arg as *const NotificationState);
+    state.send(AudioProperties::SampleRate(nframes as f32))
+}
+
+unsafe extern "C" fn buffer_size_changed(
+    nframes: jack_sys::jack_nframes_t,
+    arg: *mut c_void)
+-> c_int
+{
+    let state = &*(arg as *const NotificationState);
+    state.buffer_size_changed(nframes as usize)
+}
+
+unsafe extern "C" fn xrun(arg: *mut c_void) -> c_int
+{
+    let state = &*(arg as *const NotificationState);
+    state.xruns.fetch_add(1, Ordering::Relaxed);
+
+    // losing the notification is fine, the count is still right
+    state.send(AudioProperties::Xrun);
+    0
+}
+
+/// Remember the latency JACK has been told about. Returns false if it hasn't
+/// changed
+fn publish(published: &AtomicUsize, latency: usize) -> bool
+{
+    published.swap(latency, Ordering::Relaxed) != latency
+}
+
+/// Tell JACK how long a signal takes to get through the synth. Notes arriving
+/// at the MIDI input are heard that much later at the audio outputs
+unsafe extern "C" fn latency(
+    mode: jack_sys::jack_latency_callback_mode_t,
+    arg: *mut c_void)
+{
+    let state = &*(arg as *const NotificationState);
+    let ours = state.latency.load(Ordering::Relaxed);
+    let ours = ours as jack_sys::jack_nframes_t;
+
+    let mut range = jack_sys::jack_latency_range_t { min: 0, max: 0 };
+    if mode == jack_sys::JackCaptureLatency {
+        // from the capture ports, through the synth, to the outputs
+        jack_sys::jack_port_get_latency_range(state.midi_in, mode, &mut range);
+
+        range.min += ours;
+        range.max += ours;
+        for &port in state.outputs.iter() {
+            jack_sys::jack_port_set_latency_range(port, mode, &mut range);
+        }
+    } else {
+        // from the input, through the synth, to the playback ports. If the
+        // outputs go to different places, the range covers all of them
+        for (i, &port) in state.outputs.iter().enumerate() {
+            let mut r = jack_sys::jack_latency_range_t { min: 0, max: 0 };
+            jack_sys::jack_port_get_latency_range(port, mode, &mut r);
+
+            if i == 0 {
+                range = r;
+            } else {
+                range.min = cmp::min(range.min, r.min);
+                range.max = cmp::max(range.max, r.max);
             }
         }
+
+        range.min += ours;
+        range.max += ours;
+        jack_sys::jack_port_set_latency_range(state.midi_in, mode, &mut range);
     }
 }
 
-impl<'a> jack::ProcessHandler for AudioHandler<'a> {
-    fn process(&mut self, ctx: &jack::CallbackContext, nframes: jack::NumFrames)
-        -> i32
-    {
-        self.handle_incoming();
-
-        let output_buffer = self.output.get_write_buffer(nframes, &ctx);
-        let input_buffer = self.input.get_read_buffer(nframes, &ctx);
-
-        let mut current_event = unsafe { mem::uninitialized() };
-        let mut current_event_index = 0;
-        let event_count = input_buffer.len();
-
-        for i in 0..(nframes as usize) {
-            while current_event_index < event_count {
-                current_event = input_buffer.get(current_event_index);
-                if current_event.get_jack_time() as usize != i {
-                    break;
-                }
-                current_event_index += 1;
-
-                let buf = current_event.raw_midi_bytes();
-                let m = MidiMessage { data: buf };
-                match m.status() {
-                    MidiStatus::NoteOff => {
-                        let f = midi_note_to_frequency(m.data[1]);
-                        self.soundscape.note_off(f);
-                    },
-
-                    MidiStatus::NoteOn => {
-                        let f = midi_note_to_frequency(m.data[1]);
-                        let v = midi_velocity_to_velocity(m.data[2]);
-                        self.soundscape.note_on(f, v);
-                    },
-
-                    MidiStatus::ControlChange => {
-                        let cc = m.data[1];
-                        let val = m.data[2];
-                        self.soundscape.control_value_change(cc, val);
-                    },
-
-                    _ => (),
-                }
+/// The threads will keep running until this struct is shut down or dropped
+pub struct JackAudioThreads<'a> {
+    client: *mut jack_sys::jack_client_t,
+    // owned by JACK's threads until the client is closed
+    process: *mut ProcessState<'a>,
+    notifications: *mut NotificationState,
+    engine: EngineHandle<'a>,
+}
+
+impl<'a> JackAudioThreads<'a> {
+    pub fn shutdown(self) {
+        // closing happens on drop
+    }
+
+    /// Ask the audio thread to bind the next controller that moves to the
+    /// mapping's port. The outcome is available from `learned`
+    pub fn learn(&mut self, mapping: Mapping) -> Result<(), String> {
+        self.engine.learn(mapping)
+    }
+
+    /// The outcome of a previous learn request, if one is available
+    pub fn learned(&mut self) -> Option<LearnResult> {
+        self.engine.learned()
+    }
+
+    /// Replace the session being played, crossfading from the old session to
+    /// the new one. The JACK ports can't change, so the new session must have
+    /// the same number of channels as the old one
+    pub fn reload(&mut self, session: Session<'a>) -> Result<(), String> {
+        self.engine.reload(session)
+    }
+
+    /// Drop everything the audio thread is finished with. Should be called
+    /// regularly, so that the audio thread never has to free anything itself
+    pub fn collect_garbage(&mut self) -> usize {
+        self.engine.collect_garbage()
+    }
+
+    /// Number of xruns since the client started
+    pub fn xruns(&self) -> usize {
+        unsafe { (*self.notifications).xruns.load(Ordering::Relaxed) }
+    }
+
+    /// Tell JACK if the session's latency has changed, and return the new
+    /// latency. Should be called regularly, like `collect_garbage`
+    pub fn publish_latency(&mut self) -> Option<usize> {
+        let latency = self.engine.latency();
 
+        unsafe {
+            if !publish(&(*self.notifications).latency, latency) {
+                return None;
             }
 
-            let s = self.soundscape.generate();
-            output_buffer[i] = s;
+            jack_sys::jack_recompute_total_latencies(self.client);
         }
 
-        0
+        Some(latency)
     }
 }
 
-struct MetadataHandler {
-    sender: mpsc::SyncSender<Message>,
+impl<'a> Drop for JackAudioThreads<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            // stops the callbacks before their state goes away
+            jack_sys::jack_client_close(self.client);
+            drop(Box::from_raw(self.process));
+            drop(Box::from_raw(self.notifications));
+        }
+    }
 }
 
-impl MetadataHandler {
-    fn new(sender: mpsc::SyncSender<Message>) -> Self
-    {
-        Self { sender }
-    }
+/// How the JACK client presents itself, and what it connects to
+#[derive(Debug, Clone)]
+pub struct JackOptions {
+    /// The name requested for the client. JACK may pick another if this one
+    /// is already taken
+    pub client_name: String,
+    /// Ports to connect the audio outputs to, one for each channel. Extra
+    /// channels are left unconnected
+    pub audio_out: Vec<String>,
+    /// Ports to connect to the MIDI input
+    pub midi_in: Vec<String>,
+    /// Ports to send the MIDI output to. The MIDI output port is only
+    /// registered when there is somewhere to send the MIDI
+    pub midi_out: Vec<String>,
+    /// Follow the tempo and position of the JACK transport
+    pub follow_transport: bool,
 }
 
-impl jack::MetadataHandler for MetadataHandler {
-    fn sample_rate_changed(&mut self, srate: jack::NumFrames) -> i32
+impl JackOptions {
+    /// Connect each channel to the matching system playback port, and don't
+    /// connect any MIDI
+    pub fn new(client_name: &str, channels: usize) -> Self
     {
-        let prop = AudioProperties::SampleRate(srate as f32);
-        let message = Message::AudioProperties(prop);
-        self.sender.send(message).unwrap(); // TODO bad!
+        let audio_out = (1..(channels + 1))
+            .map(|c| format!("system:playback_{}", c))
+            .collect();
 
-        0 // success
+        Self {
+            client_name: client_name.to_owned(),
+            audio_out,
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            follow_transport: false,
+        }
     }
+}
 
-    fn callbacks_of_interest(&self) -> Vec<jack::MetadataHandlers>
-    {
-        vec![jack::MetadataHandlers::SampleRate]
+unsafe fn register(
+    client: *mut jack_sys::jack_client_t,
+    name: &str,
+    port_type: &str,
+    flags: u32)
+-> Result<Port, String>
+{
+    let c_name = CString::new(name).unwrap();
+    let c_type = CString::new(port_type).unwrap();
+    let port = jack_sys::jack_port_register(
+        client, c_name.as_ptr(), c_type.as_ptr(), flags as c_ulong, 0);
+
+    if port.is_null() {
+        return Err(format!("could not register the {} port", name));
     }
-}
 
-/// The threads will continue running until this struct is dropped out of scope or until
-pub struct JackAudioThreads<'a> {
-    client: jack::Client<'a>,
+    Ok(port)
 }
 
-impl<'a> JackAudioThreads<'a> {
-    pub fn shutdown(mut self) {
-        self.client.close().unwrap();
+/// The MIDI input, an output for each channel and maybe a MIDI output
+unsafe fn register_ports(
+    client: *mut jack_sys::jack_client_t,
+    channels: usize,
+    midi_out: bool)
+-> Result<(Port, Vec<Port>, Option<Port>), String>
+{
+    let midi = jack_sys::RAW_MIDI_TYPE;
+    let input = jack_sys::JackPortIsInput;
+    let output = jack_sys::JackPortIsOutput;
+    let midi_in = register(client, "midi_in", midi, input)?;
+
+    let mut outputs = Vec::new();
+    for channel in 1..(channels + 1) {
+        let port_name = output_port_name(channels, channel);
+        outputs.push(register(client, &port_name, AUDIO_TYPE, output)?);
     }
+
+    let midi_out = if midi_out {
+        Some(register(client, "midi_out", midi, output)?)
+    } else {
+        None
+    };
+
+    Ok((midi_in, outputs, midi_out))
 }
 
-// TODO find a way to panic if the threads are dropped before jack is shutdown
+fn connect(client: *mut jack_sys::jack_client_t, ours: &str, theirs: &str)
+    -> Result<(), String>
+{
+    let error = |e| format!("could not connect {} to {}: {}", ours, theirs, e);
+    let first = CString::new(ours).map_err(|e| error(e.to_string()))?;
+    let second = CString::new(theirs).map_err(|e| error(e.to_string()))?;
+    let ret = unsafe {
+        jack_sys::jack_connect(client, first.as_ptr(), second.as_ptr())
+    };
+
+    // jack_connect returns EEXIST for ports which are already connected,
+    // which isn't a failure
+    let err = io::Error::from_raw_os_error(ret);
+    if ret != 0 && err.kind() != io::ErrorKind::AlreadyExists {
+        return Err(error(err.to_string()));
+    }
+
+    Ok(())
+}
 
-pub fn run_audio_threads(soundscape: Soundscape) -> JackAudioThreads
+pub fn run_audio_threads<'a>(session: Session<'a>, options: &JackOptions)
+    -> Result<JackAudioThreads<'a>, String>
 {
-    let mut c = jack::Client::open("sine", jack::options::NO_START_SERVER)
-        .unwrap()
-        .0;
+    let client_name = CString::new(options.client_name.as_str()).unwrap();
+
+    unsafe {
+        let mut status = 0;
+        let client = jack_sys::jack_client_open(
+            client_name.as_ptr(), jack_sys::JackNoStartServer, &mut status);
+
+        if client.is_null() {
+            return Err(
+                format!("could not open the JACK client ({:#x})", status));
+        }
 
-    let i = c.register_input_midi_port("midi_in").unwrap();
-    let o = c.register_output_audio_port("audio_out").unwrap();
+        // JACK may have picked another name
+        let name = CStr::from_ptr(jack_sys::jack_get_client_name(client))
+            .to_string_lossy()
+            .into_owned();
+
+        let channels = session.channels();
+        let ports =
+            register_ports(client, channels, !options.midi_out.is_empty());
+
+        let (midi_in, outputs, midi_out) = match ports {
+            Ok(ports) => ports,
+            Err(e) => {
+                jack_sys::jack_client_close(client);
+                return Err(e);
+            },
+        };
+
+        let (mut engine, handle, properties) = Engine::new(session);
+        let mut sizer = engine.block_sizer();
+
+        // JACK only calls back when something changes, so the engine hears
+        // the starting values here, where the session can make room for them
+        let rate = jack_sys::jack_get_sample_rate(client);
+        let size = jack_sys::jack_get_buffer_size(client) as usize;
+        engine.prepare(AudioProperties::SampleRate(rate as f32));
+        engine.prepare(AudioProperties::BufferSize(size));
+        if let Err(e) = sizer.resize(size) {
+            jack_sys::jack_client_close(client);
+            return Err(e);
+        }
 
-    let (sender, receiver) = mpsc::sync_channel(1024);
+        let transport = if options.follow_transport {
+            Some(Transport {
+                follower: TransportFollower::new(),
+                sender: engine.property_sender(),
+            })
+        } else {
+            None
+        };
+
+        let notifications = Box::into_raw(Box::new(NotificationState {
+            sender: alloc::Mutex::new(properties),
+            sizer: alloc::Mutex::new(sizer),
+            xruns: AtomicUsize::new(0),
+            latency: AtomicUsize::new(handle.latency()),
+            midi_in,
+            outputs: outputs.clone(),
+        }));
+
+        // The process callback takes ownership of the engine.
+        // Any external messages to the session must be sent through the handle
+        let process_state = Box::into_raw(Box::new(ProcessState {
+            client,
+            midi_in,
+            outputs,
+            midi_out,
+            transport,
+            engine,
+        }));
+
+        let arg = notifications as *mut c_void;
+        jack_sys::jack_set_process_callback(
+            client, Some(process), process_state as *mut c_void);
+        jack_sys::jack_set_sample_rate_callback(
+            client, Some(sample_rate_changed), arg);
+        jack_sys::jack_set_buffer_size_callback(
+            client, Some(buffer_size_changed), arg);
+        jack_sys::jack_set_xrun_callback(client, Some(xrun), arg);
+        jack_sys::jack_set_latency_callback(client, Some(latency), arg);
+
+        let threads = JackAudioThreads {
+            client,
+            process: process_state,
+            notifications,
+            engine: handle,
+        };
+
+        // dropping the threads closes the client again, as does failing to
+        // make any of the connections asked for
+        if jack_sys::jack_activate(client) != 0 {
+            return Err("could not activate the JACK client".to_owned());
+        }
 
-    // TODO make sure easy jack has appropriate thread safety stuff added
-    // The audio handler thread takes ownership of the soundscape.
-    // Any external messages to the soundscape must be sent over a channel
-    let ahandler = AudioHandler::new(i, o, soundscape, receiver);
-    let mhandler = MetadataHandler::new(sender.clone());
+        for (channel, theirs) in (1..(channels + 1)).zip(&options.audio_out) {
+            let port = output_port_name(channels, channel);
+            connect(client, &format!("{}:{}", name, port), theirs)?;
+        }
 
-    c.set_process_handler(ahandler).unwrap();
-    c.set_metadata_handler(mhandler).unwrap();
+        for theirs in options.midi_in.iter() {
+            connect(client, theirs, &format!("{}:midi_in", name))?;
+        }
+
+        for theirs in options.midi_out.iter() {
+            connect(client, &format!("{}:midi_out", name), theirs)?;
+        }
 
-    c.activate().unwrap();
-    c.connect_ports("sine:audio_out", "system:playback_1")
-        .unwrap();
+        Ok(threads)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use engine::MAX_BLOCK_FRAMES;
+
+    use std::ptr;
+
+    #[test]
+    fn test_publish() {
+        let published = AtomicUsize::new(0);
+        assert!(!publish(&published, 0));
+        assert!(publish(&published, 64));
+        assert!(!publish(&published, 64));
+        assert_eq!(published.load(Ordering::Relaxed), 64);
+    }
 
-    JackAudioThreads {
-        client: c,
+    #[test]
+    fn test_buffer_size_changed() {
+        let (mut engine, mut handle, properties) =
+            Engine::new(Session::new(Vec::new()));
+
+        let state = NotificationState {
+            sender: alloc::Mutex::new(properties),
+            sizer: alloc::Mutex::new(engine.block_sizer()),
+            xruns: AtomicUsize::new(0),
+            latency: AtomicUsize::new(0),
+            midi_in: ptr::null_mut(),
+            outputs: Vec::new(),
+        };
+
+        // the callback makes room before the audio thread needs it
+        let frames = 2 * MAX_BLOCK_FRAMES;
+        let arg = &state as *const NotificationState as *mut c_void;
+        let res = unsafe {
+            buffer_size_changed(frames as jack_sys::jack_nframes_t, arg)
+        };
+        assert_eq!(res, 0);
+
+        let count = alloc::count_allocations(|| {
+            engine.start_block(frames);
+            assert_eq!(engine.finish_block().len(), frames);
+        });
+
+        assert_eq!(count, 0);
+        assert_eq!(handle.collect_garbage(), 1);
     }
 }
@@ -12,7 +12,7 @@ extern crate simd;
 
 #[macro_use]
 extern crate enum_primitive;
-extern crate easyjack as jack;
+extern crate jack_sys;
 
 #[macro_use]
 extern crate ketos;
@@ -22,11 +22,31 @@ extern crate ketos_derive;
 
 pub mod audioprops;
 pub mod components;
+pub mod control;
+pub mod effects;
+pub mod engine;
+pub mod events;
 pub mod jack_engine;
+pub mod mappings;
 pub mod midi;
 pub mod patch;
 pub mod ports;
+pub mod schedule;
+pub mod session;
+pub mod smf;
 pub mod soundscape;
 pub mod topo;
+pub mod transport;
+pub mod tuning;
 pub mod util;
 pub mod voice;
+pub mod wav;
+
+#[cfg(test)]
+mod testing;
+
+// lets tests (and audits) check that the audio thread never allocates
+#[cfg(any(test, feature = "alloc-audit"))]
+#[global_allocator]
+static ALLOCATOR: util::alloc::AuditingAllocator =
+    util::alloc::AuditingAllocator;
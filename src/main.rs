@@ -1,42 +1,523 @@
-extern crate synth;
+extern crate getopts;
 extern crate signal;
+extern crate synth;
 
-use synth::jack_engine::run_audio_threads;
+use synth::audioprops::AudioProperties;
+use synth::engine::Engine;
+use synth::events::{Control, Event};
+use synth::jack_engine::{run_audio_threads, JackOptions};
+use synth::mappings::{self, Curve, Mapping};
 use synth::patch::Patch;
+use synth::ports::PortName;
+use synth::session::{Session, SessionConfig};
+use synth::smf;
 use synth::soundscape::Soundscape;
+use synth::wav;
 
+use getopts::{Matches, Options};
 use signal::trap::Trap;
 
+use std::cmp;
 use std::env;
-use std::path::Path;
-use std::time::Instant;
-use std::time::Duration;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
 use std::thread;
 
+const DEFAULT_POLYPHONY: usize = 8;
+const RENDER_BLOCK_FRAMES: usize = 256;
+
 fn usage()
 {
-    println!("usage: synth2 patch_file");
+    println!("usage: synth <command> [options]");
+    println!("");
+    println!("commands:");
+    println!("    play FILE          play a patch or session with JACK");
+    println!("    render FILE OUT    render a patch or session to a wav file");
+    println!("    validate FILE...   check that patches or sessions load");
+    println!("    graph PATCH        print a patch as a graphviz graph");
+    println!("    list-components    list the components patches can use");
+    println!("");
+    println!("session files must end in .session");
+    println!("run synth <command> --help for the options of each command");
 }
 
-fn main()
+/// Parse the arguments of a subcommand. Prints the usage of the command if it
+/// was asked for, and returns None. Giving the wrong number of free arguments
+/// is an error. A variadic command takes `expected` or more free arguments,
+/// any other takes exactly `expected`
+fn parse_options(
+    command: &str,
+    free: &str,
+    opts: &mut Options,
+    args: &[String],
+    expected: usize,
+    variadic: bool)
+-> Result<Option<Matches>, String>
 {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        usage();
-        return;
+    opts.optflag("h", "help", "print this help");
+
+    let matches = opts.parse(args).map_err(|e| e.to_string())?;
+
+    let brief = format!("usage: synth {} [options] {}", command, free);
+    if matches.opt_present("h") {
+        print!("{}", opts.usage(&brief));
+        return Ok(None);
+    }
+
+    let wrong_count = if variadic {
+        matches.free.len() < expected
+    } else {
+        matches.free.len() != expected
+    };
+
+    if wrong_count {
+        return Err(opts.usage(&brief));
+    }
+
+    Ok(Some(matches))
+}
+
+/// Parse an option, or use the default if it wasn't given
+fn option_or<T: FromStr>(matches: &Matches, name: &str, default: T)
+    -> Result<T, String>
+{
+    match matches.opt_str(name) {
+        Some(s) => s.parse().map_err(|_| format!("bad --{}: {}", name, s)),
+        None => Ok(default),
+    }
+}
+
+fn polyphony_option(opts: &mut Options)
+{
+    opts.optopt("p", "polyphony",
+                "voices for a single patch (default 8). Ignored for sessions",
+                "N");
+}
+
+/// Load a session file, or wrap a single patch up as a session
+fn load_session(path: &Path, polyphony: usize) -> Result<SessionConfig, String>
+{
+    if path.extension().map_or(false, |e| e == "session") {
+        SessionConfig::from_file(path)
+    } else {
+        Ok(SessionConfig::single(path, polyphony))
+    }
+}
+
+/// The files which make up a session: the session file itself (if there is
+/// one), every patch it plays and the tuning files they use
+fn watched_files(path: &Path, session: &Session) -> Vec<PathBuf>
+{
+    let mut files = session.files().to_vec();
+
+    if !files.iter().any(|f| f == path) {
+        files.push(path.to_owned());
+    }
+
+    files
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>>
+{
+    files.iter()
+        .map(|f| fs::metadata(f).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Parse a "learn" command from stdin into a mapping
+/// The cc is filled in by the audio thread once a controller moves
+fn parse_learn(line: &str) -> Result<Mapping, String>
+{
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 3 || fields[0] != "learn" {
+        return Err("usage: learn component port [min max [curve]]".to_owned());
+    }
+
+    let mut mapping = Mapping::new(0, PortName::new(fields[1], fields[2]));
+
+    if fields.len() >= 5 {
+        mapping.min = fields[3].parse().map_err(|_| "bad min".to_owned())?;
+        mapping.max = fields[4].parse().map_err(|_| "bad max".to_owned())?;
+    }
+
+    if fields.len() >= 6 {
+        mapping.curve = Curve::from_name(fields[5])
+            .ok_or("unknown curve".to_owned())?;
+    }
+
+    Ok(mapping)
+}
+
+fn read_commands() -> mpsc::Receiver<String>
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        return;
+                    }
+                },
+                Err(_) => return,
+            }
+        }
+    });
+
+    receiver
+}
+
+fn play(args: &[String]) -> Result<(), String>
+{
+    let mut opts = Options::new();
+    polyphony_option(&mut opts);
+    opts.optopt("n", "name", "JACK client name (default synth)", "NAME");
+    opts.optmulti("a", "audio-out",
+                  "connect the next audio channel to PORT (default is the \
+                   system playback ports)",
+                  "PORT");
+    opts.optflag("", "no-audio-out", "don't connect the audio outputs");
+    opts.optmulti("m", "midi-in", "connect PORT to the MIDI input", "PORT");
+    opts.optmulti("o", "midi-out", "send MIDI output to PORT", "PORT");
+    opts.optflag("t", "transport",
+                 "follow the tempo and position of the JACK transport");
+
+    let parsed = parse_options("play", "FILE", &mut opts, args, 1, false)?;
+    let matches = match parsed {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+
+    let polyphony = option_or(&matches, "p", DEFAULT_POLYPHONY)?;
+    let name = option_or(&matches, "n", "synth".to_owned())?;
+
+    let path = PathBuf::from(&matches.free[0]);
+    let config = load_session(&path, polyphony)?;
+    let session = Session::from_config(&config)?;
+
+    // patches are reloaded whenever they are saved
+    let mut watched = watched_files(&path, &session);
+    let mut mtimes = modified_times(&watched);
+
+    let mut options = JackOptions::new(&name, session.channels());
+    if matches.opt_present("a") {
+        options.audio_out = matches.opt_strs("a");
+    }
+    if matches.opt_present("no-audio-out") {
+        options.audio_out.clear();
     }
+    options.midi_in = matches.opt_strs("m");
+    options.midi_out = matches.opt_strs("o");
+    options.follow_transport = matches.opt_present("t");
+
+    println!("commands (on stdin):");
+    println!("    learn component port [min max [curve]]");
+    println!("in a session, controllers are learned by the first part");
+
+    // learned mappings are saved next to the first part's patch
+    let mut mappings_path = config.parts.first()
+        .map(|p| mappings::mappings_path(&p.patch));
+
+    let mut learned_mappings = match mappings_path {
+        Some(ref path) if path.exists() => mappings::read_mappings(path)?,
+        _ => Vec::new(),
+    };
+
+    // important to hold a reference to the client
+    let mut client = run_audio_threads(session, &options)?;
 
-    let patch = Patch::from_file(Path::new(&args[1])).unwrap();
-    let soundscape = Soundscape::new(1, patch);
-    let client = run_audio_threads(soundscape); // important to hold a reference to the client
+    let commands = read_commands();
+    let mut reported_xruns = 0;
 
     let t = Trap::trap(&[signal::Signal::SIGINT, signal::Signal::SIGTERM]);
     loop {
         let stime = Duration::from_millis(500);
-        if (t.wait(Instant::now() + stime).is_some()) {
+        if t.wait(Instant::now() + stime).is_some() {
             println!("cleaning up");
             client.shutdown();
-            return;
+            return Ok(());
+        }
+
+        if modified_times(&watched) != mtimes {
+            println!("reloading {}", path.display());
+
+            // build the new session here, the audio thread just swaps it in
+            let res = load_session(&path, polyphony).and_then(|config| {
+                let session = Session::from_config(&config)?;
+                let files = watched_files(&path, &session);
+                client.reload(session)?;
+                Ok((config, files))
+            });
+
+            match res {
+                Ok((config, files)) => {
+                    watched = files;
+                    mappings_path = config.parts.first()
+                        .map(|p| mappings::mappings_path(&p.patch));
+                },
+                Err(e) => eprintln!("could not reload: {}", e),
+            }
+
+            // don't try again until the files change again
+            mtimes = modified_times(&watched);
+        }
+
+        while let Ok(line) = commands.try_recv() {
+            match parse_learn(&line) {
+                Ok(mapping) => {
+                    let port = mapping.port.clone();
+                    match client.learn(mapping) {
+                        Ok(()) =>
+                            println!("move a controller to bind {:?}", port),
+                        Err(e) => println!("could not learn: {}", e),
+                    }
+                },
+                Err(e) => println!("{}", e),
+            }
+        }
+
+        // anything the audio thread replaced is freed here, not there
+        client.collect_garbage();
+
+        if let Some(latency) = client.publish_latency() {
+            println!("latency is now {} frames", latency);
+        }
+
+        let xruns = client.xruns();
+        if xruns != reported_xruns {
+            println!("{} xruns", xruns - reported_xruns);
+            reported_xruns = xruns;
+        }
+
+        while let Some(result) = client.learned() {
+            match result {
+                Ok(mapping) => {
+                    println!("bound cc {} to {:?}", mapping.cc, mapping.port);
+
+                    // a port can only be bound once
+                    learned_mappings.retain(|m| m.port != mapping.port);
+                    learned_mappings.push(mapping);
+
+                    if let Some(ref path) = mappings_path {
+                        let res =
+                            mappings::write_mappings(path, &learned_mappings);
+                        if let Err(e) = res {
+                            println!("failed to save mappings: {}", e);
+                        }
+                    }
+                },
+                Err(e) => println!("could not learn: {:?}", e),
+            }
+        }
+    }
+}
+
+fn render(args: &[String]) -> Result<(), String>
+{
+    let mut opts = Options::new();
+    polyphony_option(&mut opts);
+    opts.optopt("r", "sample-rate", "sample rate (default 48000)", "HZ");
+    opts.optmulti("", "note", "MIDI note to play, may be repeated (default 60)",
+                  "NOTE");
+    opts.optopt("", "velocity", "MIDI velocity of the notes (default 100)",
+                "VEL");
+    opts.optopt("l", "length", "seconds to hold the notes (default 1)", "SECS");
+    opts.optopt("", "release", "seconds to render after the notes are \
+                                released (default 1)",
+                "SECS");
+    opts.optopt("", "midi-out", "also write the MIDI output to a MIDI file",
+                "FILE");
+
+    let free = "FILE OUT";
+    let parsed = parse_options("render", free, &mut opts, args, 2, false)?;
+    let matches = match parsed {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+
+    let polyphony = option_or(&matches, "p", DEFAULT_POLYPHONY)?;
+    let rate: u32 = option_or(&matches, "r", 48000)?;
+    let velocity: u8 = option_or(&matches, "velocity", 100)?;
+    let length: f32 = option_or(&matches, "l", 1.0)?;
+    let release: f32 = option_or(&matches, "release", 1.0)?;
+
+    let mut notes = Vec::new();
+    for n in matches.opt_strs("note") {
+        notes.push(n.parse::<u8>().map_err(|_| format!("bad note {}", n))?);
+    }
+    if notes.is_empty() {
+        notes.push(60);
+    }
+
+    let config = load_session(Path::new(&matches.free[0]), polyphony)?;
+    let session = Session::from_config(&config)?;
+    let channels = session.channels();
+
+    // render the same way the audio thread would, a block at a time
+    let (mut engine, _handle, _properties) = Engine::new(session);
+    engine.prepare(AudioProperties::SampleRate(rate as f32));
+
+    let held = (length * rate as f32) as usize;
+    let frames = held + (release * rate as f32) as usize;
+
+    let mut samples = Vec::with_capacity(frames * channels);
+    let mut midi = Vec::new();
+    let mut start = 0;
+    while start < frames {
+        let block_frames = cmp::min(RENDER_BLOCK_FRAMES, frames - start);
+        engine.start_block(block_frames);
+
+        for &note in notes.iter() {
+            if start == 0 {
+                engine.push_control(
+                    0, Control::NoteOn { channel: 0, note, velocity });
+            }
+
+            if held >= start && held < start + block_frames {
+                engine.push_control(
+                    held - start, Control::NoteOff { channel: 0, note });
+            }
+        }
+
+        samples.extend_from_slice(engine.finish_block());
+
+        let out = engine.midi_output();
+        for i in 0..out.len() {
+            if let (frame, Event::Midi(data)) = out.get(i) {
+                midi.push((start + frame, data.to_vec()));
+            }
+        }
+
+        start += block_frames;
+    }
+
+    if let Some(path) = matches.opt_str("midi-out") {
+        let out = Path::new(&path);
+        smf::write_file(out, rate, &midi)
+            .map_err(|e| format!("could not write {}: {}", out.display(), e))?;
+    }
+
+    let out = Path::new(&matches.free[1]);
+    wav::write_file(out, rate, channels as u16, &samples)
+        .map_err(|e| format!("could not write {}: {}", out.display(), e))
+}
+
+/// Load a patch or session, and build a soundscape for every patch to make
+/// sure all of the components, effects and connections are valid
+fn validate_file(path: &Path) -> Result<(), String>
+{
+    let config = load_session(path, 1)?;
+    for part in config.parts.iter() {
+        let patch = Patch::from_file(&part.patch)?;
+        Soundscape::new(1, patch)
+            .map_err(|e| format!("{}: {:?}", part.patch.display(), e))?;
+    }
+
+    Ok(())
+}
+
+fn validate(args: &[String]) -> Result<(), String>
+{
+    let mut opts = Options::new();
+    let free = "FILE...";
+    let parsed = parse_options("validate", free, &mut opts, args, 1, true)?;
+    let matches = match parsed {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+
+    let mut failed = 0;
+    for file in matches.free.iter() {
+        match validate_file(Path::new(file)) {
+            Ok(()) => println!("{}: ok", file),
+            Err(e) => {
+                println!("{}: {}", file, e);
+                failed += 1;
+            },
         }
     }
+
+    if failed > 0 {
+        return Err(format!("{} file(s) failed to validate", failed));
+    }
+
+    Ok(())
+}
+
+fn graph(args: &[String]) -> Result<(), String>
+{
+    let mut opts = Options::new();
+    let parsed = parse_options("graph", "PATCH", &mut opts, args, 1, false)?;
+    let matches = match parsed {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+
+    let patch = Patch::from_file(Path::new(&matches.free[0]))?;
+
+    println!("digraph patch {{");
+    println!("    \"voice\" [shape=box];");
+    for config in patch.components.iter() {
+        println!("    \"{}\";", config.build_component().get_name());
+    }
+
+    for c in patch.connections.iter() {
+        println!("    \"{}\" -> \"{}\" [label=\"{} -> {}\"];",
+                 c.first.component(), c.second.component(),
+                 c.first.port(), c.second.port());
+    }
+    println!("}}");
+
+    Ok(())
+}
+
+fn list_components(args: &[String]) -> Result<(), String>
+{
+    let mut opts = Options::new();
+    let parsed =
+        parse_options("list-components", "", &mut opts, args, 0, false)?;
+    if parsed.is_none() {
+        return Ok(());
+    }
+
+    for (name, fields) in Patch::component_types() {
+        let fields: Vec<String> =
+            fields.iter().map(|f| format!(":{}", f)).collect();
+        println!("{} {}", name, fields.join(" "));
+    }
+
+    Ok(())
+}
+
+fn main()
+{
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        usage();
+        return;
+    }
+
+    let rest = &args[2..];
+    let res = match args[1].as_str() {
+        "play"            => play(rest),
+        "render"          => render(rest),
+        "validate"        => validate(rest),
+        "graph"           => graph(rest),
+        "list-components" => list_components(rest),
+        _ => {
+            usage();
+            process::exit(1);
+        },
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,251 @@
+// MIDI learn mappings
+// A mapping binds a MIDI controller to any component input port, so that
+// patches don't need to hardwire ("voice" "midi_control_N") connections.
+// Mappings are saved next to the patch they belong to, one mapping per line:
+//
+//     # cc component port min max curve
+//     74 lowpass cutoff_in 20.0 8000.0 exponential
+
+use control;
+use ports::{InputPortHandle, PortName};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Response curve applied to the normalized controller value before it is
+/// scaled into the mapping's range
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Curve {
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+impl Curve {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "linear"      => Some(Curve::Linear),
+            "exponential" => Some(Curve::Exponential),
+            "logarithmic" => Some(Curve::Logarithmic),
+            _             => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match *self {
+            Curve::Linear      => "linear",
+            Curve::Exponential => "exponential",
+            Curve::Logarithmic => "logarithmic",
+        }
+    }
+
+    fn shape(&self, v: f32) -> f32
+    {
+        match *self {
+            Curve::Linear      => v,
+            Curve::Exponential => v * v,
+            Curve::Logarithmic => v.sqrt(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mapping {
+    pub cc: u8,
+    pub port: PortName,
+    pub min: f32,
+    pub max: f32,
+    pub curve: Curve,
+}
+
+impl Mapping {
+    /// Create a linear mapping covering [0, 1]
+    pub fn new(cc: u8, port: PortName) -> Self
+    {
+        Self {
+            cc,
+            port,
+            min: 0.0,
+            max: 1.0,
+            curve: Curve::Linear,
+        }
+    }
+
+    /// Scale a normalized controller value into the range of this mapping
+    pub fn apply(&self, value: f32) -> f32
+    {
+        scale(self.curve, self.min, self.max, value)
+    }
+
+    fn parse(line: &str) -> Result<Self, String>
+    {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(format!("expected 6 fields in mapping '{}'", line));
+        }
+
+        let cc = fields[0].parse::<u8>()
+            .map_err(|e| format!("bad cc in mapping '{}': {}", line, e))?;
+
+        if !control::is_bindable(cc) {
+            return Err(format!(
+                    "cc {} in mapping '{}' can't be bound, use 0-31 or 64-127 \
+                     except 6 and 96-101", cc, line));
+        }
+
+        let min = fields[3].parse::<f32>()
+            .map_err(|e| format!("bad min in mapping '{}': {}", line, e))?;
+
+        let max = fields[4].parse::<f32>()
+            .map_err(|e| format!("bad max in mapping '{}': {}", line, e))?;
+
+        let curve = Curve::from_name(fields[5])
+            .ok_or(format!("unknown curve in mapping '{}'", line))?;
+
+        Ok(Self {
+            cc,
+            port: PortName::new(fields[1], fields[2]),
+            min,
+            max,
+            curve,
+        })
+    }
+
+    fn to_line(&self) -> String
+    {
+        format!("{} {} {} {:?} {:?} {}",
+                self.cc, self.port.component(), self.port.port(),
+                self.min, self.max, self.curve.name())
+    }
+}
+
+fn scale(curve: Curve, min: f32, max: f32, value: f32) -> f32
+{
+    let v = curve.shape(value.max(0.0).min(1.0));
+    min + (max - min) * v
+}
+
+/// A mapping which has been resolved to the handle of the port it drives.
+/// Bindings don't hold any names, so the audio thread can copy them around
+/// without allocating
+#[derive(Debug, Clone, Copy)]
+pub struct Binding<'a> {
+    pub cc: u8,
+    pub port: InputPortHandle<'a>,
+    pub min: f32,
+    pub max: f32,
+    pub curve: Curve,
+}
+
+impl<'a> Binding<'a> {
+    pub fn new(mapping: &Mapping, port: InputPortHandle<'a>) -> Self
+    {
+        Self {
+            cc: mapping.cc,
+            port,
+            min: mapping.min,
+            max: mapping.max,
+            curve: mapping.curve,
+        }
+    }
+
+    /// Scale a normalized controller value into the range of this binding
+    pub fn apply(&self, value: f32) -> f32
+    {
+        scale(self.curve, self.min, self.max, value)
+    }
+}
+
+/// Reasons a controller could not be learned
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LearnError {
+    /// Every voice only has room for a binding per port, and they're all used
+    NoFreeBinding,
+}
+
+/// The mappings file which belongs to the given patch file
+pub fn mappings_path(patch_path: &Path) -> PathBuf
+{
+    patch_path.with_extension("mappings")
+}
+
+pub fn read_mappings(path: &Path) -> Result<Vec<Mapping>, String>
+{
+    let f = File::open(path).map_err(|e| format!("{}", e))?;
+
+    let mut mappings = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| format!("{}", e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        mappings.push(Mapping::parse(line)?);
+    }
+
+    Ok(mappings)
+}
+
+pub fn write_mappings(path: &Path, mappings: &[Mapping]) -> Result<(), String>
+{
+    let mut f = File::create(path).map_err(|e| format!("{}", e))?;
+
+    writeln!(f, "# cc component port min max curve")
+        .map_err(|e| format!("{}", e))?;
+
+    for m in mappings {
+        writeln!(f, "{}", m.to_line()).map_err(|e| format!("{}", e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut m = Mapping::new(1, PortName::new("a", "b"));
+        m.min = 10.0;
+        m.max = 20.0;
+
+        assert_eq!(m.apply(0.0), 10.0);
+        assert_eq!(m.apply(0.5), 15.0);
+        assert_eq!(m.apply(1.0), 20.0);
+        assert_eq!(m.apply(2.0), 20.0);
+
+        m.curve = Curve::Exponential;
+        assert_eq!(m.apply(0.5), 12.5);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut m = Mapping::new(74, PortName::new("lowpass", "cutoff_in"));
+        m.min = 20.0;
+        m.max = 8000.5;
+        m.curve = Curve::Logarithmic;
+
+        let parsed = Mapping::parse(&m.to_line()).unwrap();
+        assert_eq!(parsed, m);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Mapping::parse("74 lowpass cutoff_in").is_err());
+        assert!(Mapping::parse("300 lowpass cutoff_in 0 1 linear").is_err());
+        assert!(Mapping::parse("74 lowpass cutoff_in 0 1 wiggly").is_err());
+
+        // controllers which never report a value of their own
+        for cc in &[6, 33, 38, 63, 96, 101, 128, 255] {
+            let line = format!("{} lowpass cutoff_in 0 1 linear", cc);
+            let e = Mapping::parse(&line).unwrap_err();
+            assert!(e.contains(&line), "{}", e);
+        }
+        assert!(Mapping::parse("102 lowpass cutoff_in 0 1 linear").is_ok());
+    }
+}
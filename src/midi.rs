@@ -35,8 +35,51 @@ pub struct MidiMessage<'a> {
 }
 
 impl<'a> MidiMessage<'a> {
-    pub fn status(&self) -> MidiStatus
+    /// The kind of message, with the channel stripped from channel messages.
+    /// None if the message is empty or the status byte is unknown
+    pub fn status(&self) -> Option<MidiStatus>
     {
-        MidiStatus::from_u8(self.data[0]).unwrap()
+        match self.data.first() {
+            Some(&b) if b < 0xF0 => MidiStatus::from_u8(b & 0xF0),
+            Some(&b) => MidiStatus::from_u8(b),
+            None => None,
+        }
+    }
+
+    /// The channel of a channel message, from 0 to 15
+    pub fn channel(&self) -> Option<u8>
+    {
+        match self.data.first() {
+            Some(&b) if b >= 0x80 && b < 0xF0 => Some(b & 0x0F),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_channel_messages() {
+        let m = MidiMessage { data: &[0x9A, 60, 100] };
+        assert_eq!(m.status(), Some(MidiStatus::NoteOn));
+        assert_eq!(m.channel(), Some(10));
+
+        let m = MidiMessage { data: &[0xB0, 1, 0] };
+        assert_eq!(m.status(), Some(MidiStatus::ControlChange));
+        assert_eq!(m.channel(), Some(0));
+    }
+
+    #[test]
+    fn test_system_messages() {
+        let m = MidiMessage { data: &[0xF0, 0x7E, 0xF7] };
+        assert_eq!(m.status(), Some(MidiStatus::SysExStart));
+        assert_eq!(m.channel(), None);
+
+        // undefined status bytes, and stray data bytes
+        assert_eq!(MidiMessage { data: &[0xF4] }.status(), None);
+        assert_eq!(MidiMessage { data: &[0x40] }.status(), None);
+        assert_eq!(MidiMessage { data: &[] }.status(), None);
     }
 }
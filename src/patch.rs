@@ -2,7 +2,11 @@
 // contained to this file, if possible
 
 use components::ComponentConfig;
+use mappings::{self, Mapping};
 use ports::PortName;
+use soundscape::{Limiter, Mixing, NotePriority, Unison, VoiceMode};
+use tuning::Tuning;
+use voice::{Glide, GlideMode};
 
 use ketos;
 use ketos::ModuleLoader;
@@ -34,30 +38,117 @@ impl<'a> KetosConfigInput<'a> {
 
     fn get_all_decoders(&self) -> Vec<Decoder<Self>>
     {
+        use components::BiquadConfig;
+        use components::ChorusConfig;
+        use components::DelayConfig;
+        use components::LadderFilterConfig;
+        use components::LfoConfig;
+        use components::NoiseConfig;
+        use components::NoteOutConfig;
         use components::OnOffConfig;
+        use components::PanConfig;
+        use components::PhaserConfig;
+        use components::ReverbConfig;
         use components::SimpleLowPassConfig;
         use components::SineWaveOscillatorConfig;
         use components::SquareWaveOscillatorConfig;
+        use components::StateVariableFilterConfig;
 
         let mut decoders = Vec::new();
+        decoders.push(self.make_decoder::<BiquadConfig>());
+        decoders.push(self.make_decoder::<ChorusConfig>());
+        decoders.push(self.make_decoder::<DelayConfig>());
+        decoders.push(self.make_decoder::<LadderFilterConfig>());
+        decoders.push(self.make_decoder::<LfoConfig>());
+        decoders.push(self.make_decoder::<NoiseConfig>());
+        decoders.push(self.make_decoder::<NoteOutConfig>());
         decoders.push(self.make_decoder::<OnOffConfig>());
+        decoders.push(self.make_decoder::<PanConfig>());
+        decoders.push(self.make_decoder::<PhaserConfig>());
+        decoders.push(self.make_decoder::<ReverbConfig>());
         decoders.push(self.make_decoder::<SimpleLowPassConfig>());
         decoders.push(self.make_decoder::<SineWaveOscillatorConfig>());
         decoders.push(self.make_decoder::<SquareWaveOscillatorConfig>());
+        decoders.push(self.make_decoder::<StateVariableFilterConfig>());
         decoders
     }
 
     pub fn register_all_decoders(scope: &ketos::Scope)
     {
+        use components::BiquadConfig;
+        use components::ChorusConfig;
+        use components::DelayConfig;
+        use components::LadderFilterConfig;
+        use components::LfoConfig;
+        use components::NoiseConfig;
+        use components::NoteOutConfig;
         use components::OnOffConfig;
+        use components::PanConfig;
+        use components::PhaserConfig;
+        use components::ReverbConfig;
         use components::SimpleLowPassConfig;
         use components::SineWaveOscillatorConfig;
         use components::SquareWaveOscillatorConfig;
-
+        use components::StateVariableFilterConfig;
+
+        scope.register_struct_value::<BiquadConfig>();
+        scope.register_struct_value::<ChorusConfig>();
+        scope.register_struct_value::<DelayConfig>();
+        scope.register_struct_value::<LadderFilterConfig>();
+        scope.register_struct_value::<LfoConfig>();
+        scope.register_struct_value::<NoiseConfig>();
+        scope.register_struct_value::<NoteOutConfig>();
         scope.register_struct_value::<OnOffConfig>();
+        scope.register_struct_value::<PanConfig>();
+        scope.register_struct_value::<PhaserConfig>();
+        scope.register_struct_value::<ReverbConfig>();
         scope.register_struct_value::<SimpleLowPassConfig>();
         scope.register_struct_value::<SineWaveOscillatorConfig>();
         scope.register_struct_value::<SquareWaveOscillatorConfig>();
+        scope.register_struct_value::<StateVariableFilterConfig>();
+    }
+
+    fn component_types() -> Vec<(&'static str, &'static [&'static str])>
+    {
+        use components::BiquadConfig;
+        use components::ChorusConfig;
+        use components::DelayConfig;
+        use components::LadderFilterConfig;
+        use components::LfoConfig;
+        use components::NoiseConfig;
+        use components::NoteOutConfig;
+        use components::OnOffConfig;
+        use components::PanConfig;
+        use components::PhaserConfig;
+        use components::ReverbConfig;
+        use components::SimpleLowPassConfig;
+        use components::SineWaveOscillatorConfig;
+        use components::SquareWaveOscillatorConfig;
+        use components::StateVariableFilterConfig;
+        use ketos::StructValue;
+
+        fn describe<T: StructValue>() -> (&'static str, &'static [&'static str])
+        {
+            (T::struct_name(), T::field_names())
+        }
+
+        vec![
+            describe::<BiquadConfig>(),
+            describe::<ChorusConfig>(),
+            describe::<DelayConfig>(),
+            describe::<LadderFilterConfig>(),
+            describe::<LfoConfig>(),
+            describe::<NoiseConfig>(),
+            describe::<NoteOutConfig>(),
+            describe::<OnOffConfig>(),
+            describe::<PanConfig>(),
+            describe::<PhaserConfig>(),
+            describe::<ReverbConfig>(),
+            describe::<SimpleLowPassConfig>(),
+            describe::<SineWaveOscillatorConfig>(),
+            describe::<SquareWaveOscillatorConfig>(),
+            describe::<StateVariableFilterConfig>(),
+        ]
     }
 
     /// Attempts to build a component config from some underlying config format
@@ -82,6 +173,16 @@ impl<'a> KetosConfigInput<'a> {
 struct Config {
     pub connections: RefCell<Vec<Connection>>,
     pub components: RefCell<Vec<Box<ComponentConfig>>>,
+    pub effects: RefCell<Vec<Box<ComponentConfig>>>,
+    pub effect_connections: RefCell<Vec<Connection>>,
+    pub voice_mode: RefCell<VoiceMode>,
+    pub note_priority: RefCell<NotePriority>,
+    pub glide: RefCell<Glide>,
+    pub unison: RefCell<Unison>,
+    pub mixing: RefCell<Mixing>,
+    pub channels: RefCell<usize>,
+    pub scale: RefCell<Option<String>>,
+    pub keyboard_mapping: RefCell<Option<String>>,
 }
 
 // all the methods need to be available at global scope so might as well not put
@@ -98,13 +199,135 @@ fn connect(config: &Config, first: (&str, &str), second: (&str, &str))
     Ok(())
 }
 
+fn connect_effect(config: &Config, first: (&str, &str), second: (&str, &str))
+    -> Result<(), ketos::Error>
+{
+    let p = Connection {
+        first: PortName::new(first.0, first.1),
+        second: PortName::new(second.0, second.1),
+    };
+
+    config.effect_connections.borrow_mut().push(p);
+    Ok(())
+}
+
+type AddConfig = fn(&Config, Box<ComponentConfig>) -> Result<(), ketos::Error>;
+
 fn add_component(config: &Config, comp: Box<ComponentConfig>)
     -> Result<(), ketos::Error>
 {
+    if let Err(e) = comp.validate() {
+        return Err(ketos::exec::panic(e));
+    }
+
     config.components.borrow_mut().push(comp);
     Ok(())
 }
 
+fn add_effect(config: &Config, comp: Box<ComponentConfig>)
+    -> Result<(), ketos::Error>
+{
+    if let Err(e) = comp.validate() {
+        return Err(ketos::exec::panic(e));
+    }
+
+    config.effects.borrow_mut().push(comp);
+    Ok(())
+}
+
+fn voice_mode(config: &Config, mode: &str) -> Result<(), ketos::Error>
+{
+    match VoiceMode::from_name(mode) {
+        Some(m) => {
+            *config.voice_mode.borrow_mut() = m;
+            Ok(())
+        },
+        None => Err(ketos::exec::panic(format!("unknown voice mode {}", mode))),
+    }
+}
+
+fn note_priority(config: &Config, priority: &str) -> Result<(), ketos::Error>
+{
+    match NotePriority::from_name(priority) {
+        Some(p) => {
+            *config.note_priority.borrow_mut() = p;
+            Ok(())
+        },
+        None => {
+            Err(ketos::exec::panic(
+                    format!("unknown note priority {}", priority)))
+        },
+    }
+}
+
+fn glide(config: &Config, time: f32, mode: &str) -> Result<(), ketos::Error>
+{
+    match GlideMode::from_name(mode) {
+        Some(mode) => {
+            *config.glide.borrow_mut() = Glide { time, mode };
+            Ok(())
+        },
+        None => Err(ketos::exec::panic(format!("unknown glide mode {}", mode))),
+    }
+}
+
+fn unison(config: &Config, voices: usize, detune: f32, phase: f32, spread: f32)
+    -> Result<(), ketos::Error>
+{
+    *config.unison.borrow_mut() = Unison { voices, detune, phase, spread };
+    Ok(())
+}
+
+fn master_gain(config: &Config, db: f32) -> Result<(), ketos::Error>
+{
+    config.mixing.borrow_mut().gain = 10.0_f32.powf(db / 20.0);
+    Ok(())
+}
+
+fn velocity_scaling(config: &Config, amount: f32) -> Result<(), ketos::Error>
+{
+    if amount < 0.0 || amount > 1.0 {
+        return Err(ketos::exec::panic(
+                "velocity scaling must be between 0 and 1"));
+    }
+
+    config.mixing.borrow_mut().velocity = amount;
+    Ok(())
+}
+
+fn limiter(config: &Config, name: &str) -> Result<(), ketos::Error>
+{
+    match Limiter::from_name(name) {
+        Some(l) => {
+            config.mixing.borrow_mut().limiter = l;
+            Ok(())
+        },
+        None => Err(ketos::exec::panic(format!("unknown limiter {}", name))),
+    }
+}
+
+fn channels(config: &Config, channels: usize) -> Result<(), ketos::Error>
+{
+    if channels == 0 {
+        return Err(ketos::exec::panic("a patch needs at least one channel"));
+    }
+
+    *config.channels.borrow_mut() = channels;
+    Ok(())
+}
+
+fn scale(config: &Config, path: &str) -> Result<(), ketos::Error>
+{
+    *config.scale.borrow_mut() = Some(path.to_owned());
+    Ok(())
+}
+
+fn keyboard_mapping(config: &Config, path: &str) -> Result<(), ketos::Error>
+{
+    *config.keyboard_mapping.borrow_mut() = Some(path.to_owned());
+    Ok(())
+}
+
 // TODO don't make everything on these pub?
 
 #[derive(Debug, Clone)]
@@ -117,15 +340,72 @@ pub struct Connection {
 pub struct Patch {
     pub connections: Vec<Connection>,
     pub components: Vec<Box<ComponentConfig>>,
+    /// Effects run on the mixed voices, in the master graph
+    pub effects: Vec<Box<ComponentConfig>>,
+    /// Connections between the effects. Without any, the effects are chained
+    /// in order
+    pub effect_connections: Vec<Connection>,
+    pub mappings: Vec<Mapping>,
+    pub voice_mode: VoiceMode,
+    pub note_priority: NotePriority,
+    pub glide: Glide,
+    pub unison: Unison,
+    /// Levels the voices are mixed at
+    pub mixing: Mixing,
+    /// Number of audio channels each voice produces
+    pub channels: usize,
+    pub tuning: Tuning,
+    /// Files other than the patch itself that it was loaded from, like its
+    /// scale and keyboard mapping
+    pub files: Vec<PathBuf>,
 }
 
 // public impl
 impl Patch {
+    /// An empty patch
+    pub fn new() -> Self
+    {
+        Patch {
+            connections: Vec::new(),
+            components: Vec::new(),
+            effects: Vec::new(),
+            effect_connections: Vec::new(),
+            mappings: Vec::new(),
+            voice_mode: VoiceMode::Poly,
+            note_priority: NotePriority::Last,
+            glide: Glide::off(),
+            unison: Unison::off(),
+            mixing: Mixing::default_headroom(),
+            channels: 1,
+            tuning: Tuning::equal_temperament(),
+            files: Vec::new(),
+        }
+    }
+
+    /// The name and fields of every component config a patch can create
+    pub fn component_types() -> Vec<(&'static str, &'static [&'static str])>
+    {
+        KetosConfigInput::component_types()
+    }
+
+    /// Load a patch from a file. If there is a mappings file next to the patch,
+    /// it will be loaded as well. Scale and keyboard mapping files named by the
+    /// patch are found relative to the patch file.
     pub fn from_file(path: &Path) -> Result<Self, String>
     {
         let config = Rc::new(Config {
             connections: RefCell::new(Vec::new()),
             components: RefCell::new(Vec::new()),
+            effects: RefCell::new(Vec::new()),
+            effect_connections: RefCell::new(Vec::new()),
+            voice_mode: RefCell::new(VoiceMode::Poly),
+            note_priority: RefCell::new(NotePriority::Last),
+            glide: RefCell::new(Glide::off()),
+            unison: RefCell::new(Unison::off()),
+            mixing: RefCell::new(Mixing::default_headroom()),
+            channels: RefCell::new(1),
+            scale: RefCell::new(None),
+            keyboard_mapping: RefCell::new(None),
         });
 
         let loader = ketos::FileModuleLoader::with_search_paths(vec![
@@ -145,31 +425,120 @@ impl Patch {
             -> ()
         }
 
-        interp.scope().add_value_with_name("add-component", |name| {
-            ketos::value::Value::new_foreign_fn(name, move |_scope, args| {
-                let expected = 2;
-                if args.len() != expected {
-                    return Err(From::from(ketos::exec::ExecError::ArityError {
-                        name: Some(name),
-                        expected: ketos::function::Arity::Exact(expected as u32),
-                        found: args.len() as u32
-                    }));
-                }
+        ketos_fn!{
+            interp.scope()
+            => "connect-effect"
+            => fn connect_effect(
+                config: &Config,
+                first: (&str, &str),
+                second: (&str, &str))
+            -> ()
+        }
 
-                let mut iter = (&*args).iter();
+        ketos_fn!{
+            interp.scope()
+            => "voice-mode"
+            => fn voice_mode(config: &Config, mode: &str) -> ()
+        }
 
-                let value = iter.next().unwrap();
-                let config = try!(
-                    <&Config as ketos::value::FromValueRef>::from_value_ref(value));
+        ketos_fn!{
+            interp.scope()
+            => "note-priority"
+            => fn note_priority(config: &Config, priority: &str) -> ()
+        }
 
-                let value = iter.next().unwrap();
-                let kval = KetosConfigInput { value };
-                let compconfig = kval.parse().unwrap(); // TODO
+        ketos_fn!{
+            interp.scope()
+            => "glide"
+            => fn glide(config: &Config, time: f32, mode: &str) -> ()
+        }
 
-                let res = try!(add_component(config, compconfig));
-                Ok(<() as Into<ketos::value::Value>>::into(res))
-            })
-        });
+        ketos_fn!{
+            interp.scope()
+            => "unison"
+            => fn unison(
+                config: &Config,
+                voices: usize,
+                detune: f32,
+                phase: f32,
+                spread: f32)
+            -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "master-gain"
+            => fn master_gain(config: &Config, db: f32) -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "velocity-scaling"
+            => fn velocity_scaling(config: &Config, amount: f32) -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "limiter"
+            => fn limiter(config: &Config, name: &str) -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "channels"
+            => fn channels(config: &Config, channels: usize) -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "scale"
+            => fn scale(config: &Config, path: &str) -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "keyboard-mapping"
+            => fn keyboard_mapping(config: &Config, path: &str) -> ()
+        }
+
+        // components go in every voice, effects go after the voices are mixed
+        let adders: [(&str, AddConfig); 2] = [
+            ("add-component", add_component),
+            ("add-effect", add_effect),
+        ];
+
+        for &(fn_name, add) in adders.iter() {
+            interp.scope().add_value_with_name(fn_name, |name| {
+                ketos::value::Value::new_foreign_fn(name, move |_scope, args| {
+                    let expected = 2;
+                    if args.len() != expected {
+                        let arity = ketos::function::Arity::Exact(
+                            expected as u32);
+
+                        return Err(From::from(
+                            ketos::exec::ExecError::ArityError {
+                                name: Some(name),
+                                expected: arity,
+                                found: args.len() as u32
+                            }));
+                    }
+
+                    let mut iter = (&*args).iter();
+
+                    let value = iter.next().unwrap();
+                    let config = try!(
+                        <&Config as ketos::value::FromValueRef>
+                            ::from_value_ref(value));
+
+                    let value = iter.next().unwrap();
+                    let kval = KetosConfigInput { value };
+                    let compconfig = kval.parse().unwrap(); // TODO
+
+                    let res = try!(add(config, compconfig));
+                    Ok(<() as Into<ketos::value::Value>>::into(res))
+                })
+            });
+        }
 
         KetosConfigInput::register_all_decoders(interp.scope());
 
@@ -180,13 +549,19 @@ impl Patch {
             .map(|_value| {
                 // ignore the return, reuse the original config, then create the
                 // actual patch from the config
-                let mut p = Patch {
-                    connections: Vec::new(),
-                    components: Vec::new(),
-                };
+                let mut p = Patch::new();
 
                 p.connections.clone_from(&*config.connections.borrow());
                 p.components.clone_from(&*config.components.borrow());
+                p.effects.clone_from(&*config.effects.borrow());
+                p.effect_connections.clone_from(
+                    &*config.effect_connections.borrow());
+                p.voice_mode = *config.voice_mode.borrow();
+                p.note_priority = *config.note_priority.borrow();
+                p.glide = *config.glide.borrow();
+                p.unison = *config.unison.borrow();
+                p.mixing = *config.mixing.borrow();
+                p.channels = *config.channels.borrow();
 
                 p
             })
@@ -198,5 +573,34 @@ impl Patch {
                         interp.scope().borrow_names().deref(),
                         &error))
             })
+            .and_then(|mut p| {
+                let mappings_path = mappings::mappings_path(path);
+                if mappings_path.exists() {
+                    p.mappings = mappings::read_mappings(&mappings_path)?;
+                }
+
+                // tuning files are relative to the patch
+                let dir = path.parent().unwrap_or(Path::new("."));
+                let scale = config.scale.borrow().as_ref().map(|s| dir.join(s));
+                let kbm = config.keyboard_mapping.borrow().as_ref()
+                    .map(|s| dir.join(s));
+
+                match (scale, kbm) {
+                    (Some(scale), kbm) => {
+                        p.tuning = Tuning::from_files(
+                            &scale, kbm.as_ref().map(|k| k.as_path()))?;
+
+                        p.files.push(scale);
+                        p.files.extend(kbm);
+                    },
+                    (None, Some(_)) => {
+                        return Err(
+                            "keyboard-mapping requires a scale".to_owned());
+                    },
+                    (None, None) => (),
+                }
+
+                Ok(p)
+            })
     }
 }
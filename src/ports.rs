@@ -1,3 +1,4 @@
+use events::{Event, EventQueue};
 use util;
 
 use std::collections::HashMap;
@@ -5,6 +6,10 @@ use std::marker::PhantomData;
 
 pub type PortId = usize;
 
+// most MIDI messages a single voice can send in one frame
+const MIDI_OUT_EVENTS: usize = 16;
+const MIDI_OUT_BYTES: usize = 256;
+
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum PortDirection {
     Input,
@@ -153,6 +158,16 @@ impl PortName {
             port: port.to_string(),
         }
     }
+
+    pub fn component(&self) -> &str
+    {
+        &self.component
+    }
+
+    pub fn port(&self) -> &str
+    {
+        &self.port
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -161,6 +176,8 @@ pub enum PortManagerError {
     NotOutputPort,
     NotInputPort,
     NoSuchPort(PortName),
+    // effects which can't be chained without connect-effect, and why
+    NotChainable(String),
 }
 
 /// A RealtimePortManager can only access the portions of a PortManager that are
@@ -176,6 +193,10 @@ pub trait RealtimePortManager<'a> {
     /// to a port from a different PortManager is undefined behavior. This may
     /// only be called on an Output port
     fn set_port_value(&mut self, p: &OutputPortHandle, val: f32);
+
+    /// Send a MIDI message out of the synth, at the frame currently being
+    /// generated. Returns false if too many messages were sent in one frame
+    fn send_midi(&mut self, data: &[u8]) -> bool;
 }
 
 /// A port manager manages the connections between different components. Every
@@ -215,6 +236,32 @@ pub trait PortManager<'a>: RealtimePortManager<'a> {
         -> (HashMap<usize, String>, util::nmat::Matrix<bool, util::nmat::RowMajor>);
 }
 
+/// The names of the ports registered with a PortManager, so that ports can be
+/// looked up on another thread, away from the PortManager itself
+#[derive(Debug, Clone)]
+pub struct PortDirectory<'a> {
+    // component_name -> (port_name -> handle)
+    ports: HashMap<String, HashMap<String, UnknownPortHandle<'a>>>,
+}
+
+impl<'a> PortDirectory<'a> {
+    pub fn find_port(&self, name: &PortName) -> Option<UnknownPortHandle<'a>>
+    {
+        self.ports
+            .get(&name.component)
+            .and_then(|comp| comp.get(&name.port))
+            .cloned()
+    }
+
+    pub fn find_input_port(&self, name: &PortName)
+        -> Result<InputPortHandle<'a>, PortManagerError>
+    {
+        self.find_port(name)
+            .ok_or(PortManagerError::NoSuchPort(name.clone()))
+            .and_then(|port| port.promote_to_input())
+    }
+}
+
 #[derive(Debug)]
 pub struct PortManagerImpl<'a> {
     // graph implementation
@@ -227,6 +274,9 @@ pub struct PortManagerImpl<'a> {
     // component_name -> (port_name -> handle)
     ports_meta: HashMap<String, HashMap<String, UnknownPortHandle<'a>>>,
 
+    // MIDI sent by the components during the current frame
+    midi_out: EventQueue,
+
     // used to enforce the lifetime constraints of the port handles
     phantom: PhantomData<&'a usize>,
 }
@@ -294,9 +344,47 @@ impl<'a> PortManagerImpl<'a> {
             ports:       Vec::new(),
             connections: Vec::new(),
             ports_meta:  HashMap::new(),
+            midi_out:    EventQueue::with_capacity(
+                             MIDI_OUT_EVENTS, MIDI_OUT_BYTES),
             phantom:     PhantomData,
         }
     }
+
+    /// Number of ports registered, of either direction
+    pub fn port_count(&self) -> usize
+    {
+        self.ports.len()
+    }
+
+    /// A copy of the port names registered so far
+    pub fn directory(&self) -> PortDirectory<'a>
+    {
+        PortDirectory {
+            ports: self.ports_meta.clone(),
+        }
+    }
+
+    /// Write a value directly onto an input port, from outside of the
+    /// component graph. If an output port is connected to this input, the
+    /// value will be replaced the next time the output port is set.
+    pub fn set_input_port_value(&mut self, p: &InputPortHandle, val: f32)
+    {
+        self.ports[p.id] = val;
+    }
+
+    /// Move the MIDI sent since the last call into another queue, at the
+    /// given frame
+    pub fn drain_midi(&mut self, frame: usize, out: &mut EventQueue)
+    {
+        for i in 0..self.midi_out.len() {
+            if let (_, Event::Midi(data)) = self.midi_out.get(i) {
+                // if the queue is full, the message is dropped
+                out.push_midi(frame, data);
+            }
+        }
+
+        self.midi_out.clear();
+    }
 }
 
 impl<'a> RealtimePortManager<'a> for PortManagerImpl<'a> {
@@ -316,6 +404,11 @@ impl<'a> RealtimePortManager<'a> for PortManagerImpl<'a> {
             }
         }
     }
+
+    fn send_midi(&mut self, data: &[u8]) -> bool
+    {
+        self.midi_out.push_midi(0, data)
+    }
 }
 
 impl<'a> PortManager<'a> for PortManagerImpl<'a> {
@@ -494,6 +587,18 @@ fn test_duplicate_port3()
     assert!(port2.is_err());
 }
 
+#[test]
+fn test_set_input()
+{
+    let mut manager = PortManagerImpl::new();
+    let port = manager
+        .register_input_port(&PortName::new("test", "in"))
+        .unwrap();
+
+    manager.set_input_port_value(&port, 10.0);
+    assert!(manager.get_port_value(&port) == 10.0);
+}
+
 #[test]
 fn test_disconnect()
 {
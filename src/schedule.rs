@@ -0,0 +1,125 @@
+// Running a graph of components
+// Voices and the effects after them are both graphs of components, fed by and
+// feeding a pseudo component (the "voice" or the "mix") which isn't in the
+// component list. A Schedule puts the components in an order where every
+// component runs after the components it reads from, and remembers enough of
+// the graph to work out how far the components delay the sound.
+
+use components::Component;
+use ports::{PortManager, PortManagerImpl};
+use topo;
+
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct Schedule {
+    // the components each component reads from, and the components the
+    // pseudo component reads its samples from, as positions in the component
+    // list
+    inputs: Vec<Vec<usize>>,
+    outputs: Vec<usize>,
+    // the latency at the output of each component
+    scratch: Vec<usize>,
+}
+
+impl Schedule {
+    /// Sort the components into the order they should run in. `start` is the
+    /// name of the pseudo component the graph starts and ends at
+    pub fn new<'a>(
+        components: &mut Vec<Box<Component<'a> + 'a>>,
+        ports: &PortManagerImpl<'a>,
+        start: &str)
+        -> Self
+    {
+        // now the port manager knows all of the connections, we topologically
+        // sort the component connection graph. Some components will use values
+        // produced by other components. We need to do this sort to make sure
+        // that the all of the port values get updated in the right order.
+        let (names, mut adj) = ports.get_component_adjacency_matrix();
+
+        // To allow cycles in the component graph, remove any edges which would
+        // form a cycle by pointing back to a previously visited component.
+        // Starting from the pseudo component means the edges back into it (the
+        // samples it collects) are the ones that get removed
+        let start = names.iter()
+            .find(|&(_, name)| name == start)
+            .map(|(&i, _)| i)
+            .unwrap_or(0);
+        let n = adj.dim().0;
+        let into_start: Vec<usize> = (0..n)
+            .filter(|&i| i != start && adj[(i, start)])
+            .collect();
+
+        topo::remove_back_edges_from(&mut adj, start);
+
+        // the components each component reads from, for working out latency
+        let reads_from: Vec<Vec<usize>> = (0..n)
+            .map(|j| (0..n).filter(|&i| i != start && adj[(i, j)]).collect())
+            .collect();
+
+        let ordering = topo::topological_sort(&mut adj);
+
+        // figure out what that ordering means, and reorder the vector of
+        // components
+        let mut order_by_component_name = HashMap::new();
+        for (index, element) in ordering.iter().enumerate() {
+            let comp_name = names.get(element).unwrap();
+            order_by_component_name.insert(comp_name, index);
+        }
+
+        components.sort_by(|ref e1, ref e2| {
+            let o1 = order_by_component_name[&e1.get_name()];
+            let o2 = order_by_component_name[&e2.get_name()];
+
+            o1.cmp(&o2)
+        });
+
+        // the same graph, by position in the sorted component list
+        let mut position = HashMap::new();
+        for (pos, comp) in components.iter().enumerate() {
+            position.insert(comp.get_name(), pos);
+        }
+
+        let sorted = |i: &usize| position[&names[i]];
+        let inputs = components.iter()
+            .map(|comp| {
+                let i = names.iter()
+                    .find(|&(_, name)| *name == comp.get_name())
+                    .map(|(&i, _)| i)
+                    .unwrap();
+
+                reads_from[i].iter().map(&sorted).collect()
+            })
+            .collect();
+
+        Self {
+            inputs,
+            outputs: into_start.iter().map(&sorted).collect(),
+            scratch: vec![0; components.len()],
+        }
+    }
+
+    /// Frames by which the graph's output lags its input. This is the slowest
+    /// path through the components, adding up the latency of each component
+    /// along the way
+    pub fn latency<'a>(&mut self, components: &[Box<Component<'a> + 'a>])
+        -> usize
+    {
+        // the components are in order, so every input has been worked out by
+        // the time it is needed
+        for (i, comp) in components.iter().enumerate() {
+            let inputs = self.inputs[i].iter()
+                .map(|&j| self.scratch[j])
+                .max()
+                .unwrap_or(0);
+
+            self.scratch[i] = inputs + comp.latency();
+        }
+
+        let scratch = &self.scratch;
+        self.outputs.iter()
+            .map(|&i| scratch[i])
+            .max()
+            .unwrap_or(0)
+    }
+}
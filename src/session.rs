@@ -0,0 +1,554 @@
+// Multi-timbral sessions
+// A session plays several patches at once. Each part of a session has its own
+// patch and polyphony, and only plays the notes on its MIDI channel which fall
+// inside its key and velocity ranges. Parts may overlap to make layers, or
+// divide the keyboard to make splits.
+//
+// Sessions are ketos scripts, like patches. Patch files are found relative to
+// the session file, and channels are numbered 1 to 16:
+//
+//     (define (create session)
+//       (do
+//         (part session "bass" "bass.patch" 2)
+//         (part-channel session "bass" 1)
+//         (part-keys session "bass" 0 59)
+//
+//         (part session "lead" "lead.patch" 8)
+//         (part-keys session "lead" 60 127)))
+
+use audioprops::AudioProperties;
+use events::EventQueue;
+use mappings::{Binding, LearnError};
+use patch::Patch;
+use ports::PortDirectory;
+use soundscape::Soundscape;
+
+use ketos;
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// MIDI velocities go up to 127, which is full velocity
+pub fn midi_velocity_to_velocity(vel: u8) -> f32
+{
+    vel as f32 / 127.0
+}
+
+/// Everything needed to build a single part of a session
+#[derive(Debug, PartialEq, Clone)]
+pub struct PartConfig {
+    pub name: String,
+    pub patch: PathBuf,
+    pub polyphony: usize,
+    /// MIDI channel, from 0 to 15. None responds to every channel
+    pub channel: Option<u8>,
+    /// Lowest and highest notes played by the part, inclusive
+    pub keys: (u8, u8),
+    /// Lowest and highest velocities played by the part, inclusive
+    pub velocities: (u8, u8),
+}
+
+impl PartConfig {
+    /// A part which plays everything it hears
+    pub fn new<T: ToString>(name: T, patch: &Path, polyphony: usize) -> Self
+    {
+        Self {
+            name: name.to_string(),
+            patch: patch.to_owned(),
+            polyphony,
+            channel: None,
+            keys: (0, 127),
+            velocities: (0, 127),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SessionConfig {
+    pub parts: Vec<PartConfig>,
+}
+
+/// Exists only to allow us to read values from ketos
+#[derive(Debug, ForeignValue, FromValueRef)]
+struct Script {
+    dir: PathBuf,
+    parts: RefCell<Vec<PartConfig>>,
+}
+
+fn with_part<F>(script: &Script, name: &str, f: F) -> Result<(), ketos::Error>
+    where F: FnOnce(&mut PartConfig)
+{
+    let mut parts = script.parts.borrow_mut();
+    match parts.iter_mut().find(|p| p.name == name) {
+        Some(p) => {
+            f(p);
+            Ok(())
+        },
+        None => Err(ketos::exec::panic(format!("unknown part {}", name))),
+    }
+}
+
+fn midi_range(what: &str, low: usize, high: usize)
+    -> Result<(u8, u8), ketos::Error>
+{
+    if low > high || high > 127 {
+        return Err(ketos::exec::panic(
+                format!("bad {} range {} to {}", what, low, high)));
+    }
+
+    Ok((low as u8, high as u8))
+}
+
+fn part(script: &Script, name: &str, patch: &str, polyphony: usize)
+    -> Result<(), ketos::Error>
+{
+    let mut parts = script.parts.borrow_mut();
+    if parts.iter().any(|p| p.name == name) {
+        return Err(ketos::exec::panic(format!("duplicate part {}", name)));
+    }
+
+    parts.push(PartConfig::new(name, &script.dir.join(patch), polyphony));
+    Ok(())
+}
+
+fn part_channel(script: &Script, name: &str, channel: usize)
+    -> Result<(), ketos::Error>
+{
+    if channel < 1 || channel > 16 {
+        return Err(ketos::exec::panic(format!("bad channel {}", channel)));
+    }
+
+    with_part(script, name, |p| p.channel = Some(channel as u8 - 1))
+}
+
+fn part_keys(script: &Script, name: &str, low: usize, high: usize)
+    -> Result<(), ketos::Error>
+{
+    let keys = midi_range("key", low, high)?;
+    with_part(script, name, |p| p.keys = keys)
+}
+
+fn part_velocities(script: &Script, name: &str, low: usize, high: usize)
+    -> Result<(), ketos::Error>
+{
+    let velocities = midi_range("velocity", low, high)?;
+    with_part(script, name, |p| p.velocities = velocities)
+}
+
+impl SessionConfig {
+    /// A session with a single part, playing one patch on every channel
+    pub fn single(patch: &Path, polyphony: usize) -> Self
+    {
+        Self {
+            parts: vec![PartConfig::new("main", patch, polyphony)],
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String>
+    {
+        let script = Rc::new(Script {
+            dir: path.parent().unwrap_or(Path::new(".")).to_owned(),
+            parts: RefCell::new(Vec::new()),
+        });
+
+        let interp = ketos::Interpreter::new();
+
+        ketos_fn!{
+            interp.scope()
+            => "part"
+            => fn part(
+                script: &Script,
+                name: &str,
+                patch: &str,
+                polyphony: usize)
+            -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "part-channel"
+            => fn part_channel(script: &Script, name: &str, channel: usize)
+            -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "part-keys"
+            => fn part_keys(
+                script: &Script,
+                name: &str,
+                low: usize,
+                high: usize)
+            -> ()
+        }
+
+        ketos_fn!{
+            interp.scope()
+            => "part-velocities"
+            => fn part_velocities(
+                script: &Script,
+                name: &str,
+                low: usize,
+                high: usize)
+            -> ()
+        }
+
+        interp.run_file(path)
+            .and_then(|_| {
+                let value = ketos::Value::Foreign(script.clone());
+                interp.call("create", vec![value])
+            })
+            .map(|_value| Self { parts: script.parts.borrow().clone() })
+            .map_err(|error| {
+                use ketos::name::display_names;
+                use std::ops::Deref;
+
+                format!("{}", display_names(
+                        interp.scope().borrow_names().deref(),
+                        &error))
+            })
+    }
+}
+
+/// A soundscape, and the notes it responds to
+#[derive(Debug)]
+pub struct Part<'a> {
+    config: PartConfig,
+    soundscape: Soundscape<'a>,
+}
+
+impl<'a> Part<'a> {
+    pub fn new(config: PartConfig, soundscape: Soundscape<'a>) -> Self
+    {
+        Self { config, soundscape }
+    }
+
+    pub fn name(&self) -> &str
+    {
+        &self.config.name
+    }
+
+    /// The file the part's patch was loaded from
+    pub fn patch_path(&self) -> &Path
+    {
+        &self.config.patch
+    }
+
+    fn listens_to(&self, channel: u8) -> bool
+    {
+        self.config.channel.map(|c| c == channel).unwrap_or(true)
+    }
+
+    fn plays(&self, channel: u8, note: u8) -> bool
+    {
+        let (low, high) = self.config.keys;
+        self.listens_to(channel) && note >= low && note <= high
+    }
+
+    fn accepts_velocity(&self, vel: u8) -> bool
+    {
+        let (low, high) = self.config.velocities;
+        vel >= low && vel <= high
+    }
+}
+
+/// Several parts, mixed together
+#[derive(Debug)]
+pub struct Session<'a> {
+    parts: Vec<Part<'a>>,
+    channels: usize,
+    // scratch space for a single part's output
+    part_frame: Vec<f32>,
+    // the patches the parts were loaded from, and the files they use
+    files: Vec<PathBuf>,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(parts: Vec<Part<'a>>) -> Self
+    {
+        let channels = parts.iter()
+            .map(|p| p.soundscape.channels())
+            .max()
+            .unwrap_or(1);
+
+        Self {
+            parts,
+            channels,
+            part_frame: Vec::with_capacity(channels),
+            files: Vec::new(),
+        }
+    }
+
+    /// Load every patch in the session
+    pub fn from_config(config: &SessionConfig) -> Result<Self, String>
+    {
+        let mut parts = Vec::new();
+        let mut files = Vec::new();
+        for part in config.parts.iter() {
+            let patch = Patch::from_file(&part.patch)
+                .map_err(|e| format!("{}: {}", part.patch.display(), e))?;
+
+            files.push(part.patch.clone());
+            files.extend(patch.files.iter().cloned());
+
+            let soundscape = Soundscape::new(part.polyphony, patch)
+                .map_err(|e| format!("{}: {:?}", part.patch.display(), e))?;
+
+            parts.push(Part::new(part.clone(), soundscape));
+        }
+
+        let mut session = Self::new(parts);
+        session.files = files;
+        Ok(session)
+    }
+
+    /// Every file the session's patches were loaded from. The session file
+    /// itself isn't included
+    pub fn files(&self) -> &[PathBuf]
+    {
+        &self.files
+    }
+
+    pub fn parts(&self) -> &[Part<'a>]
+    {
+        &self.parts
+    }
+
+    /// Number of audio channels in every frame. Parts with fewer channels are
+    /// mixed into all of the channels
+    pub fn channels(&self) -> usize
+    {
+        self.channels
+    }
+
+    pub fn note_on(&mut self, channel: u8, note: u8, vel: u8)
+    {
+        let v = midi_velocity_to_velocity(vel);
+        for part in &mut self.parts {
+            if part.plays(channel, note) && part.accepts_velocity(vel) {
+                part.soundscape.note_on(note, v);
+            }
+        }
+    }
+
+    /// Note offs don't carry the velocity of the note on, so every part which
+    /// could be playing the note is told to stop it
+    pub fn note_off(&mut self, channel: u8, note: u8)
+    {
+        for part in &mut self.parts {
+            if part.plays(channel, note) {
+                part.soundscape.note_off(note);
+            }
+        }
+    }
+
+    pub fn control_value_change(&mut self, channel: u8, cc: u8, val: u8)
+    {
+        for part in &mut self.parts {
+            if part.listens_to(channel) {
+                part.soundscape.control_value_change(cc, val);
+            }
+        }
+    }
+
+    /// Tuning messages apply to every part
+    pub fn handle_sysex(&mut self, data: &[u8])
+    {
+        for part in &mut self.parts {
+            part.soundscape.handle_sysex(data);
+        }
+    }
+
+    /// The names of the first part's ports, which controllers are learned
+    /// for
+    pub fn port_directory(&self) -> Option<PortDirectory<'a>>
+    {
+        self.parts.first().and_then(|p| p.soundscape.port_directory())
+    }
+
+    /// Start MIDI learn on the first part
+    pub fn learn(&mut self, binding: Binding<'a>)
+    {
+        if let Some(part) = self.parts.first_mut() {
+            part.soundscape.learn(binding);
+        }
+    }
+
+    pub fn take_learned(&mut self) -> Option<Result<Binding<'a>, LearnError>>
+    {
+        self.parts.first_mut().and_then(|p| p.soundscape.take_learned())
+    }
+
+    pub fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        for part in &mut self.parts {
+            part.soundscape.handle_audio_property_change(prop);
+        }
+    }
+
+    /// Frames by which the session's output lags the notes it is played,
+    /// which is the latency of the slowest part
+    pub fn latency(&mut self) -> usize
+    {
+        self.parts.iter_mut()
+            .map(|p| p.soundscape.latency())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Move the MIDI sent by every part into another queue, at the given
+    /// frame
+    pub fn drain_midi(&mut self, frame: usize, out: &mut EventQueue)
+    {
+        for part in &mut self.parts {
+            part.soundscape.drain_midi(frame, out);
+        }
+    }
+
+    /// Mix a single frame from all of the parts
+    pub fn generate(&mut self, frame: &mut [f32])
+    {
+        debug_assert!(frame.len() == self.channels);
+
+        for sample in frame.iter_mut() {
+            *sample = 0.0;
+        }
+
+        for part in &mut self.parts {
+            let channels = part.soundscape.channels();
+
+            // never grows past the largest part
+            self.part_frame.resize(channels, 0.0);
+            part.soundscape.generate(&mut self.part_frame);
+
+            for (c, sample) in frame.iter_mut().enumerate() {
+                // mono parts are copied into every channel
+                let from = if channels == 1 { 0 } else { c };
+                if from < channels {
+                    *sample += self.part_frame[from];
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soundscape::{Limiter, Mixing};
+    use testing::connect;
+
+    fn part(channel: Option<u8>, keys: (u8, u8), velocities: (u8, u8))
+        -> Part<'static>
+    {
+        let mut config = PartConfig::new("p", Path::new("p.patch"), 2);
+        config.channel = channel;
+        config.keys = keys;
+        config.velocities = velocities;
+        Part::new(config, Soundscape::new(2, Patch::new()).unwrap())
+    }
+
+    fn playing(s: &Session) -> Vec<bool>
+    {
+        s.parts.iter().map(|p| p.soundscape.is_playing(60)).collect()
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut s = Session::new(vec![
+            part(Some(0), (0, 127), (0, 127)),
+            part(Some(1), (0, 127), (0, 127)),
+            part(None, (0, 127), (0, 127)),
+        ]);
+
+        s.note_on(1, 60, 100);
+        assert_eq!(playing(&s), vec![false, true, true]);
+
+        s.note_off(1, 60);
+        assert_eq!(playing(&s), vec![false, false, false]);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut s = Session::new(vec![
+            part(None, (0, 59), (0, 127)),
+            part(None, (60, 127), (0, 127)),
+        ]);
+
+        s.note_on(0, 60, 100);
+        assert_eq!(playing(&s), vec![false, true]);
+    }
+
+    #[test]
+    fn test_velocity_layers() {
+        let mut s = Session::new(vec![
+            part(None, (0, 127), (0, 63)),
+            part(None, (0, 127), (64, 127)),
+        ]);
+
+        s.note_on(0, 60, 30);
+        assert_eq!(playing(&s), vec![true, false]);
+
+        // the note off reaches the layer which played the note
+        s.note_off(0, 60);
+        assert_eq!(playing(&s), vec![false, false]);
+
+        s.note_on(0, 60, 127);
+        assert_eq!(playing(&s), vec![false, true]);
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("patches");
+        let patch = dir.join("just_sine.patch");
+        let config = SessionConfig::single(&patch, 1);
+
+        let session = Session::from_config(&config).unwrap();
+        assert_eq!(session.files(), &[patch, dir.join("just_sine.scl")]);
+    }
+
+    #[test]
+    fn test_full_velocity_is_unit_gain() {
+        // the gate is fully open, scaled by the velocity alone
+        let mut patch = Patch::new();
+        connect(&mut patch,
+                ("voice", "midi_gate_out"), ("voice", "samples_in"));
+        patch.mixing = Mixing {
+            gain: 1.0,
+            velocity: 1.0,
+            limiter: Limiter::Off,
+        };
+
+        let config = PartConfig::new("p", Path::new("p.patch"), 1);
+        let mut s = Session::new(vec![
+            Part::new(config, Soundscape::new(1, patch).unwrap()),
+        ]);
+        s.handle_audio_property_change(AudioProperties::SampleRate(1000.0));
+
+        s.note_on(0, 60, 127);
+        let mut frame = [0.0];
+        s.generate(&mut frame);
+        assert_eq!(frame, [1.0]);
+    }
+
+    #[test]
+    fn test_mix_mono_into_stereo() {
+        let mut stereo = Patch::new();
+        stereo.channels = 2;
+
+        let config = PartConfig::new("p", Path::new("p.patch"), 1);
+        let mono = Soundscape::new(1, Patch::new()).unwrap();
+        let stereo = Soundscape::new(1, stereo).unwrap();
+        let mut s = Session::new(vec![
+            Part::new(config.clone(), mono),
+            Part::new(config, stereo),
+        ]);
+
+        assert_eq!(s.channels(), 2);
+
+        let mut frame = [1.0, 1.0];
+        s.generate(&mut frame);
+        assert_eq!(frame, [0.0, 0.0]);
+    }
+}
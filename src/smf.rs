@@ -0,0 +1,140 @@
+// Minimal Standard MIDI File writer for offline rendering
+// Everything goes in a single track (format 0), at the default tempo of 120
+// beats per minute, so every second is exactly two beats
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub const TICKS_PER_BEAT: u16 = 480;
+
+// 120 beats per minute
+const MICROSECONDS_PER_BEAT: u32 = 500_000;
+const TICKS_PER_SECOND: u64 = TICKS_PER_BEAT as u64 * 2;
+
+fn write_u16<W: Write>(w: &mut W, v: u16) -> io::Result<()>
+{
+    w.write_all(&[(v >> 8) as u8, v as u8])
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()>
+{
+    w.write_all(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8])
+}
+
+/// Variable length quantity, seven bits at a time, most significant first
+fn push_vlq(out: &mut Vec<u8>, mut v: u32)
+{
+    let mut bytes = [0; 5];
+    let mut i = bytes.len() - 1;
+    bytes[i] = (v & 0x7F) as u8;
+    v >>= 7;
+
+    while v > 0 {
+        i -= 1;
+        bytes[i] = (v & 0x7F) as u8 | 0x80;
+        v >>= 7;
+    }
+
+    out.extend_from_slice(&bytes[i..]);
+}
+
+/// Write MIDI messages to any writer. Each message is paired with the frame it
+/// was sent at, and the messages must be in order
+pub fn write<W: Write>(
+    w: &mut W,
+    sample_rate: u32,
+    events: &[(usize, Vec<u8>)])
+-> io::Result<()>
+{
+    let mut track = Vec::new();
+
+    // tempo
+    track.extend_from_slice(&[0x00, 0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&[
+        (MICROSECONDS_PER_BEAT >> 16) as u8,
+        (MICROSECONDS_PER_BEAT >> 8) as u8,
+        MICROSECONDS_PER_BEAT as u8,
+    ]);
+
+    let mut last = 0;
+    for &(frame, ref data) in events {
+        if data.is_empty() {
+            continue;
+        }
+
+        let tick = frame as u64 * TICKS_PER_SECOND / sample_rate as u64;
+        push_vlq(&mut track, (tick - last) as u32);
+        last = tick;
+
+        // system exclusive messages are stored with their length
+        if data[0] == 0xF0 {
+            track.push(0xF0);
+            push_vlq(&mut track, data.len() as u32 - 1);
+            track.extend_from_slice(&data[1..]);
+        } else {
+            track.extend_from_slice(data);
+        }
+    }
+
+    // end of track
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    w.write_all(b"MThd")?;
+    write_u32(w, 6)?;
+    write_u16(w, 0)?; // format
+    write_u16(w, 1)?; // tracks
+    write_u16(w, TICKS_PER_BEAT)?;
+
+    w.write_all(b"MTrk")?;
+    write_u32(w, track.len() as u32)?;
+    w.write_all(&track)
+}
+
+pub fn write_file(
+    path: &Path,
+    sample_rate: u32,
+    events: &[(usize, Vec<u8>)])
+-> io::Result<()>
+{
+    let mut w = BufWriter::new(File::create(path)?);
+    write(&mut w, sample_rate, events)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vlq() {
+        let mut out = Vec::new();
+        push_vlq(&mut out, 0);
+        push_vlq(&mut out, 0x7F);
+        push_vlq(&mut out, 0x80);
+        push_vlq(&mut out, 0x0FFFFFFF);
+        assert_eq!(out, vec![0x00, 0x7F, 0x81, 0x00, 0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn test_one_note() {
+        let mut out = Vec::new();
+        let events = vec![
+            (0, vec![0x90, 60, 100]),
+            (48000, vec![0x80, 60, 0]),
+        ];
+        write(&mut out, 48000, &events).unwrap();
+
+        assert_eq!(&out[0..14], &[
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xE0,
+        ]);
+        assert_eq!(&out[14..22], b"MTrk\0\0\0\x14");
+        assert_eq!(&out[22..], &[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x00, 0x90, 60, 100,
+            // one second is 960 ticks
+            0x87, 0x40, 0x80, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ]);
+    }
+}
@@ -1,6 +1,210 @@
 use audioprops::AudioProperties;
+use control::{self, ControlEvent, ControllerState};
+use effects::MasterGraph;
+use events::EventQueue;
+use mappings::{Binding, LearnError};
 use patch::Patch;
-use voice::Voice;
+use ports::{PortDirectory, PortManagerError};
+use tuning::Tuning;
+use util::rng::Rng;
+use voice::{Glide, Voice};
+
+use std::cmp;
+
+// there are only 128 MIDI notes, so the note stack can never grow larger
+const MAX_HELD_NOTES: usize = 128;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VoiceMode {
+    /// Every note is given its own voice
+    Poly,
+    /// A single voice, every new note retriggers it
+    Mono,
+    /// A single voice, overlapping notes only change the pitch
+    Legato,
+}
+
+impl VoiceMode {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "poly"   => Some(VoiceMode::Poly),
+            "mono"   => Some(VoiceMode::Mono),
+            "legato" => Some(VoiceMode::Legato),
+            _        => None,
+        }
+    }
+}
+
+/// Which of the held notes a mono or legato voice should play
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+impl NotePriority {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "last" => Some(NotePriority::Last),
+            "low"  => Some(NotePriority::Low),
+            "high" => Some(NotePriority::High),
+            _      => None,
+        }
+    }
+}
+
+/// Unison stacks several voices on every note
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Unison {
+    /// Number of voices started for each note
+    pub voices: usize,
+    /// Pitch distance between the highest and lowest voice, in cents
+    pub detune: f32,
+    /// Amount of random phase offset given to each voice, from 0 to 1
+    pub phase: f32,
+    /// How far apart the voices are panned, from 0 (center) to 1 (hard
+    /// left and right)
+    pub spread: f32,
+}
+
+impl Unison {
+    /// One voice per note
+    pub fn off() -> Self
+    {
+        Self {
+            voices: 1,
+            detune: 0.0,
+            phase: 0.0,
+            spread: 0.0,
+        }
+    }
+
+    /// Position of the i'th voice in the stack, from -1 to 1
+    fn position(&self, i: usize) -> f32
+    {
+        if self.voices < 2 {
+            return 0.0;
+        }
+
+        2.0 * (i as f32 / (self.voices - 1) as f32) - 1.0
+    }
+
+    fn frequency(&self, i: usize, freq: f32) -> f32
+    {
+        let cents = 0.5 * self.detune * self.position(i);
+        freq * 2.0_f32.powf(cents / 1200.0)
+    }
+
+    fn pan(&self, i: usize) -> f32
+    {
+        self.spread * self.position(i)
+    }
+}
+
+// samples below the knee are left alone by the soft clipper
+const SOFT_CLIP_KNEE: f32 = 0.5;
+
+/// What happens to the output after the effects
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Limiter {
+    /// The output is left as it is, and may go past full scale
+    Off,
+    /// Samples past the knee are bent smoothly towards full scale, so the
+    /// output never goes past it
+    SoftClip,
+}
+
+impl Limiter {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "off"       => Some(Limiter::Off),
+            "soft-clip" => Some(Limiter::SoftClip),
+            _           => None,
+        }
+    }
+
+    fn process(&self, x: f32) -> f32
+    {
+        match *self {
+            Limiter::Off => x,
+            Limiter::SoftClip => {
+                if x.abs() <= SOFT_CLIP_KNEE {
+                    return x;
+                }
+
+                // tanh leaves the knee at the same slope, and levels off at 1
+                let room = 1.0 - SOFT_CLIP_KNEE;
+                let over = (x.abs() - SOFT_CLIP_KNEE) / room;
+                x.signum() * (SOFT_CLIP_KNEE + room * over.tanh())
+            },
+        }
+    }
+}
+
+/// Gain staging for the mixed voices. Every voice is added in at the same
+/// level, no matter how many voices there are, so a note is as loud on its own
+/// as it is in a chord
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Mixing {
+    /// Gain applied to the sum of the voices, before the effects
+    pub gain: f32,
+    /// How much a note's velocity sets the level of its voice, from 0 (every
+    /// note at full level) to 1 (level follows velocity)
+    pub velocity: f32,
+    pub limiter: Limiter,
+}
+
+impl Mixing {
+    /// What a patch gets without master-gain: each voice at half of full scale
+    /// (-6 dB), so a chord of a few notes has headroom before the soft clipper
+    /// bends it. master-gain sets the gain from full scale, so master-gain 0
+    /// is twice as loud as this
+    pub fn default_headroom() -> Self
+    {
+        Self {
+            gain: 0.5,
+            velocity: 0.0,
+            limiter: Limiter::SoftClip,
+        }
+    }
+
+    fn voice_gain(&self, vel: f32) -> f32
+    {
+        let amount = self.velocity.max(0.0).min(1.0);
+        1.0 - amount + amount * vel
+    }
+}
+
+/// Start the i'th voice of a unison stack. If slide is set, the voice changes
+/// pitch without being retriggered
+fn start_voice(
+    voice: &mut Voice,
+    unison: &Unison,
+    rng: &mut Rng,
+    i: usize,
+    note: u8,
+    freq: f32,
+    vel: f32,
+    slide: bool)
+{
+    let freq = unison.frequency(i, freq);
+    voice.set_pan(unison.pan(i));
+
+    if slide {
+        voice.slide_to(note, freq);
+        return;
+    }
+
+    if unison.phase > 0.0 {
+        voice.reset_phase(rng.next_f32() * unison.phase);
+    }
+
+    voice.note_on(note, freq, vel);
+}
 
 /// A soundscape contains many voices, manages NoteOn/NoteOff for each voice
 /// For the moment, this will just make lots of copies. There's lots of room
@@ -12,51 +216,326 @@ pub struct Soundscape<'a> {
     // I'm using a vector.  Don't ever resize it!
     // TODO make this not resizable
     voices: Vec<Voice<'a>>,
+    mode: VoiceMode,
+    priority: NotePriority,
+    // all of the notes currently held down, in the order they were pressed,
+    // with their velocities
+    held_notes: Vec<(u8, f32)>,
+    unison: Unison,
+    // every note is given a serial number when it starts, so that the voices
+    // in a unison group can be found together and the oldest note can be
+    // stolen when all of the voices are busy
+    serials: Vec<u64>,
+    next_serial: u64,
+    stealing: Vec<bool>,
+    rng: Rng,
+    controllers: ControllerState,
+    // a binding waiting for the next controller to move
+    learning: Option<Binding<'a>>,
+    // the outcome of the last learn, waiting to be picked up
+    learned: Option<Result<Binding<'a>, LearnError>>,
+    tuning: Tuning,
+    mixing: Mixing,
+    // scratch space for a single voice's output, so that generating a frame
+    // never allocates
+    voice_frame: Vec<f32>,
+    // shared effects, after the voices are mixed
+    effects: MasterGraph<'a>,
 }
 
 impl<'a> Soundscape<'a> {
-    pub fn new(polyphony: usize, p: Patch) -> Self
+    pub fn new(polyphony: usize, p: Patch) -> Result<Self, PortManagerError>
     {
         let mut voices = Vec::new();
         for _ in 0..polyphony {
-            voices.push(Voice::new(&p).unwrap());
+            let mut voice = Voice::new(&p)?;
+            voice.set_glide(p.glide);
+            voices.push(voice);
         }
 
-        Self { voices }
+        let effects = MasterGraph::new(&p)?;
+
+        Ok(Self {
+            voices,
+            mode: p.voice_mode,
+            priority: p.note_priority,
+            held_notes: Vec::with_capacity(MAX_HELD_NOTES),
+            unison: p.unison,
+            serials: vec![0; polyphony],
+            next_serial: 0,
+            stealing: vec![false; polyphony],
+            rng: Rng::new(1),
+            controllers: ControllerState::new(),
+            learning: None,
+            learned: None,
+            tuning: p.tuning,
+            mixing: p.mixing,
+            voice_frame: vec![0.0; p.channels],
+            effects,
+        })
     }
 
-    pub fn note_on(&mut self, freq: f32, vel: f32)
+    /// Change the voice mode. Any notes currently playing are stopped.
+    /// Mono and legato modes only ever use the first voice (or the first
+    /// unison group)
+    pub fn set_voice_mode(&mut self, mode: VoiceMode, priority: NotePriority)
     {
+        self.mode = mode;
+        self.priority = priority;
+        self.all_notes_off();
+    }
+
+    /// Change the unison settings. Any notes currently playing are stopped.
+    pub fn set_unison(&mut self, unison: Unison)
+    {
+        self.unison = unison;
+        self.all_notes_off();
+    }
+
+    fn all_notes_off(&mut self)
+    {
+        self.held_notes.clear();
         for voice in &mut self.voices {
-            match voice.current_frequency() {
-                Some(_f) => (),
-                None => {
-                    voice.note_on(freq, vel);
-                    return;
-                },
-            }
+            voice.note_off();
         }
+    }
 
-        // TODO replacement policy
+    /// Number of voices each note uses
+    fn group_size(&self) -> usize
+    {
+        cmp::max(1, cmp::min(self.unison.voices, self.voices.len()))
     }
 
-    pub fn note_off(&mut self, freq: f32)
+    pub fn set_glide(&mut self, glide: Glide)
     {
         for voice in &mut self.voices {
-            if let Some(f) = voice.current_frequency() {
-                if f == freq {
-                    voice.note_off(f)
+            voice.set_glide(glide);
+        }
+    }
+
+    pub fn note_on(&mut self, note: u8, vel: f32)
+    {
+        match self.mode {
+            VoiceMode::Poly => self.poly_note_on(note, vel),
+            _ => {
+                self.held_notes.retain(|&(n, _)| n != note);
+                if self.held_notes.len() < MAX_HELD_NOTES {
+                    self.held_notes.push((note, vel));
+                }
+
+                self.play_priority_note();
+            },
+        }
+    }
+
+    /// True if any voice is currently playing the note
+    pub fn is_playing(&self, note: u8) -> bool
+    {
+        self.voices.iter().any(|v| v.current_note() == Some(note))
+    }
+
+    pub fn note_off(&mut self, note: u8)
+    {
+        match self.mode {
+            VoiceMode::Poly => {
+                for voice in &mut self.voices {
+                    if voice.current_note() == Some(note) {
+                        voice.note_off()
+                    }
                 }
+            },
+
+            _ => {
+                self.held_notes.retain(|&(n, _)| n != note);
+                self.play_priority_note();
+            },
+        }
+    }
+
+    fn poly_note_on(&mut self, note: u8, vel: f32)
+    {
+        if self.voices.is_empty() {
+            return;
+        }
+
+        let needed = self.group_size();
+        while self.available_voices() < needed {
+            self.steal_oldest_group();
+        }
+
+        let serial = self.next_serial;
+        self.next_serial += 1;
+
+        let freq = self.tuning.frequency(note);
+        let mut started = 0;
+        for (idx, voice) in self.voices.iter_mut().enumerate() {
+            let free = voice.current_note().is_none() || self.stealing[idx];
+            if started == needed || !free {
+                continue;
+            }
+
+            let (unison, rng) = (&self.unison, &mut self.rng);
+            start_voice(voice, unison, rng, started, note, freq, vel, false);
+
+            self.serials[idx] = serial;
+            self.stealing[idx] = false;
+            started += 1;
+        }
+
+        // a stolen note is released as a whole, even if only some of its voices
+        // were needed
+        for (idx, voice) in self.voices.iter_mut().enumerate() {
+            if self.stealing[idx] {
+                voice.note_off();
+                self.stealing[idx] = false;
+            }
+        }
+    }
+
+    fn available_voices(&self) -> usize
+    {
+        self.voices.iter()
+            .zip(self.stealing.iter())
+            .filter(|&(v, &stealing)| v.current_note().is_none() || stealing)
+            .count()
+    }
+
+    /// Mark every voice of the oldest sounding note to be stolen
+    fn steal_oldest_group(&mut self)
+    {
+        let oldest = self.voices.iter()
+            .zip(self.serials.iter().zip(self.stealing.iter()))
+            .filter(|&(v, (_, &stealing))| {
+                v.current_note().is_some() && !stealing
+            })
+            .map(|(_, (&serial, _))| serial)
+            .min();
+
+        let oldest = match oldest {
+            Some(serial) => serial,
+            None => return,
+        };
+
+        for (idx, voice) in self.voices.iter().enumerate() {
+            if voice.current_note().is_some() && self.serials[idx] == oldest {
+                self.stealing[idx] = true;
             }
         }
     }
 
+    /// The held note which should be sounding, according to the note priority
+    fn priority_note(&self) -> Option<(u8, f32)>
+    {
+        let notes = self.held_notes.iter().cloned();
+        match self.priority {
+            NotePriority::Last => self.held_notes.last().cloned(),
+            NotePriority::Low  => notes.min_by_key(|&(n, _)| n),
+            NotePriority::High => notes.max_by_key(|&(n, _)| n),
+        }
+    }
+
+    /// Make the mono voice (or unison group) play whichever note has
+    /// priority, after the held notes have changed
+    fn play_priority_note(&mut self)
+    {
+        let next = self.priority_note();
+        let legato = self.mode == VoiceMode::Legato;
+        let size = self.group_size();
+
+        let current = match self.voices.first() {
+            Some(voice) => voice.current_note(),
+            None => return,
+        };
+
+        match (current, next) {
+            (_, None) => {
+                for voice in self.voices[..size].iter_mut() {
+                    voice.note_off();
+                }
+            },
+
+            (Some(current), Some((note, _))) if current == note => (),
+
+            (current, Some((note, vel))) => {
+                let slide = legato && current.is_some();
+                let freq = self.tuning.frequency(note);
+                let unison = &self.unison;
+                let rng = &mut self.rng;
+                for (i, voice) in self.voices[..size].iter_mut().enumerate() {
+                    start_voice(
+                        voice, unison, rng, i, note, freq, vel, slide);
+                }
+            },
+        }
+    }
+
     pub fn control_value_change(&mut self, cc: u8, new_val: u8)
     {
         // all voices get the change
         for voice in &mut self.voices {
             voice.control_value_change(cc, new_val)
         }
+
+        let event = self.controllers.control_value_change(cc, new_val);
+
+        if let Some(ControlEvent::Controller { cc, .. }) = event {
+            // data entry without a parameter selected still reports as a
+            // controller, but it couldn't be saved as a mapping
+            let learning = if control::is_bindable(cc) {
+                self.learning.take()
+            } else {
+                None
+            };
+
+            if let Some(mut binding) = learning {
+                binding.cc = cc;
+
+                // every voice has the same ports, so they all succeed or fail
+                let mut res = Ok(binding);
+                for voice in &mut self.voices {
+                    if let Err(e) = voice.bind(binding) {
+                        res = Err(e);
+                    }
+                }
+
+                self.learned = Some(res);
+            }
+        }
+
+        if let Some(event) = event {
+            for voice in &mut self.voices {
+                voice.control_event(event)
+            }
+        }
+    }
+
+    /// The names of the ports in the voices, which are the same in every
+    /// voice. None if there aren't any voices
+    pub fn port_directory(&self) -> Option<PortDirectory<'a>>
+    {
+        self.voices.first().map(|v| v.port_directory())
+    }
+
+    /// Start MIDI learn. The next controller to move will be bound to the
+    /// binding's port, with the binding's range and curve. The cc of the
+    /// binding passed in is ignored.
+    pub fn learn(&mut self, binding: Binding<'a>)
+    {
+        self.learning = Some(binding);
+    }
+
+    /// Returns the outcome of the most recent learn, if it has not already
+    /// been taken
+    pub fn take_learned(&mut self) -> Option<Result<Binding<'a>, LearnError>>
+    {
+        self.learned.take()
+    }
+
+    /// Handle a system exclusive message. MIDI Tuning Standard messages retune
+    /// the soundscape, starting with the next note played
+    pub fn handle_sysex(&mut self, data: &[u8])
+    {
+        self.tuning.handle_sysex(data);
     }
 
     pub fn handle_audio_property_change(&mut self, prop: AudioProperties)
@@ -64,16 +543,289 @@ impl<'a> Soundscape<'a> {
         for voice in &mut self.voices {
             voice.handle_audio_property_change(prop)
         }
+
+        self.effects.handle_audio_property_change(prop);
     }
 
-    pub fn generate(&mut self) -> f32
+    /// Frames by which the output lags the notes played. Every voice plays the
+    /// same patch, and then the effects add their own latency
+    pub fn latency(&mut self) -> usize
+    {
+        let voices = self.voices.first_mut().map(|v| v.latency()).unwrap_or(0);
+        voices + self.effects.latency()
+    }
+
+    /// Move the MIDI sent by every voice into another queue, at the given
+    /// frame
+    pub fn drain_midi(&mut self, frame: usize, out: &mut EventQueue)
     {
-        let mut sample = 0.0;
         for voice in &mut self.voices {
-            let subsample = voice.generate();
-            sample += subsample;
+            voice.drain_midi(frame, out);
         }
+    }
+
+    /// Number of audio channels in every frame
+    pub fn channels(&self) -> usize
+    {
+        self.voice_frame.len()
+    }
+
+    /// Mix a single frame from all of the voices
+    pub fn generate(&mut self, frame: &mut [f32])
+    {
+        debug_assert!(frame.len() == self.channels());
+
+        for sample in frame.iter_mut() {
+            *sample = 0.0;
+        }
+
+        for voice in &mut self.voices {
+            voice.generate(&mut self.voice_frame);
+
+            let gain = self.mixing.gain
+                * self.mixing.voice_gain(voice.velocity());
+
+            for (sample, v) in frame.iter_mut().zip(self.voice_frame.iter()) {
+                *sample += gain * *v;
+            }
+        }
+
+        self.effects.process(frame);
+
+        for sample in frame.iter_mut() {
+            *sample = self.mixing.limiter.process(*sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{connect, delay};
 
-        sample * (1.0 / self.voices.len() as f32)
+    fn mono(mode: VoiceMode, priority: NotePriority) -> Soundscape<'static>
+    {
+        let mut s = Soundscape::new(1, Patch::new()).unwrap();
+        s.set_voice_mode(mode, priority);
+        s
+    }
+
+    #[test]
+    fn test_poly_note_off() {
+        let mut s = Soundscape::new(2, Patch::new()).unwrap();
+        s.note_on(60, 1.0);
+        s.note_on(64, 1.0);
+        s.note_off(60);
+
+        assert_eq!(s.voices[0].current_note(), None);
+        assert_eq!(s.voices[1].current_note(), Some(64));
+    }
+
+    fn unison(voices: usize) -> Unison
+    {
+        Unison {
+            voices,
+            detune: 20.0,
+            phase: 1.0,
+            spread: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_unison_note() {
+        let mut s = Soundscape::new(4, Patch::new()).unwrap();
+        s.set_unison(unison(3));
+        s.note_on(69, 1.0);
+
+        let f0 = s.voices[0].current_frequency().unwrap();
+        let f1 = s.voices[1].current_frequency().unwrap();
+        let f2 = s.voices[2].current_frequency().unwrap();
+        assert!(s.voices[3].current_note().is_none());
+
+        // detuned symmetrically around the note, 20 cents apart in total
+        assert_eq!(f1, 440.0);
+        assert!((f0 * f2 - 440.0 * 440.0).abs() < 0.1);
+        assert!(((f2 / f0).log2() * 1200.0 - 20.0).abs() < 0.01);
+
+        assert_eq!(s.voices[0].pan(), -1.0);
+        assert_eq!(s.voices[1].pan(), 0.0);
+        assert_eq!(s.voices[2].pan(), 1.0);
+
+        // and the whole group is released together
+        s.note_off(69);
+        for voice in s.voices.iter() {
+            assert!(voice.current_note().is_none());
+        }
+    }
+
+    #[test]
+    fn test_unison_stealing() {
+        let mut s = Soundscape::new(5, Patch::new()).unwrap();
+        s.set_unison(unison(2));
+        s.note_on(60, 1.0);
+        s.note_on(62, 1.0);
+
+        // there is only one free voice, so the oldest note is stolen as a whole
+        s.note_on(64, 1.0);
+
+        let playing = |s: &Soundscape, note| {
+            s.voices.iter().filter(|v| v.current_note() == Some(note)).count()
+        };
+
+        assert_eq!(playing(&s, 60), 0);
+        assert_eq!(playing(&s, 62), 2);
+        assert_eq!(playing(&s, 64), 2);
+
+        s.note_on(65, 1.0);
+        assert_eq!(playing(&s, 62), 0);
+        assert_eq!(playing(&s, 64), 2);
+        assert_eq!(playing(&s, 65), 2);
+    }
+
+    #[test]
+    fn test_stealing_single_voices() {
+        let mut s = Soundscape::new(2, Patch::new()).unwrap();
+        s.note_on(60, 1.0);
+        s.note_on(62, 1.0);
+        s.note_on(64, 1.0);
+
+        assert_eq!(s.voices[0].current_note(), Some(64));
+        assert_eq!(s.voices[1].current_note(), Some(62));
+    }
+
+    #[test]
+    fn test_last_note_priority() {
+        let mut s = mono(VoiceMode::Mono, NotePriority::Last);
+        s.note_on(60, 1.0);
+        s.note_on(64, 1.0);
+        s.note_on(67, 1.0);
+        assert_eq!(s.voices[0].current_note(), Some(67));
+
+        // releasing a key returns to the previously held note
+        s.note_off(67);
+        assert_eq!(s.voices[0].current_note(), Some(64));
+
+        // releasing a note which isn't sounding changes nothing
+        s.note_off(60);
+        assert_eq!(s.voices[0].current_note(), Some(64));
+
+        s.note_off(64);
+        assert_eq!(s.voices[0].current_note(), None);
+    }
+
+    #[test]
+    fn test_low_and_high_note_priority() {
+        let mut s = mono(VoiceMode::Legato, NotePriority::Low);
+        s.note_on(64, 1.0);
+        s.note_on(60, 1.0);
+        s.note_on(67, 1.0);
+        assert_eq!(s.voices[0].current_note(), Some(60));
+        s.note_off(60);
+        assert_eq!(s.voices[0].current_note(), Some(64));
+
+        let mut s = mono(VoiceMode::Legato, NotePriority::High);
+        s.note_on(64, 1.0);
+        s.note_on(67, 1.0);
+        s.note_on(60, 1.0);
+        assert_eq!(s.voices[0].current_note(), Some(67));
+        s.note_off(67);
+        assert_eq!(s.voices[0].current_note(), Some(64));
+    }
+
+    /// Each voice plays its gate, through a delay which leaves it dry
+    fn gate_patch() -> Patch
+    {
+        let mut patch = Patch::new();
+        patch.components.push(Box::new(delay("dry", 0.0, 0.0)));
+        connect(&mut patch, ("voice", "midi_gate_out"), ("dry", "samples_in"));
+        connect(&mut patch, ("dry", "samples_out"), ("voice", "samples_in"));
+
+        patch
+    }
+
+    fn first_frames(s: &mut Soundscape, frames: usize) -> Vec<f32>
+    {
+        s.handle_audio_property_change(AudioProperties::SampleRate(1000.0));
+        (0..frames)
+            .map(|_| {
+                let mut frame = [0.0];
+                s.generate(&mut frame);
+                frame[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_effects_after_mixing() {
+        let mut patch = gate_patch();
+        patch.effects.push(Box::new(delay("echo", 0.003, 1.0)));
+
+        let mut s = Soundscape::new(4, patch).unwrap();
+        s.note_on(60, 1.0);
+        assert_eq!(first_frames(&mut s, 5), vec![0.0, 0.0, 0.0, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_loudness_independent_of_polyphony() {
+        let note = |polyphony: usize| {
+            let mut s = Soundscape::new(polyphony, gate_patch()).unwrap();
+            s.note_on(60, 1.0);
+            first_frames(&mut s, 1)[0]
+        };
+
+        assert_eq!(note(1), 0.5);
+        assert_eq!(note(4), 0.5);
+        assert_eq!(note(16), 0.5);
+
+        // a chord is louder than a single note, but the soft clipper stops it
+        // from going past full scale
+        let mut s = Soundscape::new(16, gate_patch()).unwrap();
+        for n in 60..76 {
+            s.note_on(n, 1.0);
+        }
+
+        let chord = first_frames(&mut s, 1)[0];
+        assert!(chord > 0.9 && chord <= 1.0);
+    }
+
+    #[test]
+    fn test_master_gain_and_velocity() {
+        let patch = || {
+            let mut patch = gate_patch();
+            patch.mixing = Mixing {
+                gain: 2.0,
+                velocity: 0.5,
+                limiter: Limiter::Off,
+            };
+
+            patch
+        };
+
+        // half the velocity scaling takes a quarter off a half velocity note
+        let mut s = Soundscape::new(4, patch()).unwrap();
+        s.note_on(60, 0.5);
+        assert_eq!(first_frames(&mut s, 1), vec![1.5]);
+
+        // without the limiter, voices add up
+        let mut s = Soundscape::new(4, patch()).unwrap();
+        s.note_on(60, 1.0);
+        s.note_on(62, 1.0);
+        s.note_on(64, 0.0);
+        assert_eq!(first_frames(&mut s, 1), vec![5.0]);
+    }
+
+    #[test]
+    fn test_soft_clip() {
+        let clip = Limiter::SoftClip;
+        assert_eq!(clip.process(0.25), 0.25);
+        assert_eq!(clip.process(-0.5), -0.5);
+
+        let mut last = 0.5;
+        for i in 1..20 {
+            let y = clip.process(0.5 + i as f32 * 0.1);
+            assert!(y > last && y < 1.0);
+            assert_eq!(clip.process(-0.5 - i as f32 * 0.1), -y);
+            last = y;
+        }
     }
 }
@@ -0,0 +1,111 @@
+// Helpers shared by the tests of several modules
+
+use components::{Component, ComponentConfig, DelayConfig};
+use patch::{Connection, Patch};
+use ports::{InputPortHandle, OutputPortHandle, PortName};
+use ports::{PortManager, RealtimePortManager, PortManagerError};
+
+/// Passes its input through, claiming to look ahead by some frames
+#[derive(Debug, Clone)]
+struct LookaheadConfig {
+    name: String,
+    frames: usize,
+}
+
+impl ComponentConfig for LookaheadConfig {
+    fn build_component<'a, 'b>(&'b self) -> Box<Component<'a> + 'a>
+    {
+        Box::new(Lookahead {
+            config: self.clone(),
+            input: None,
+            output: None,
+        })
+    }
+
+    fn box_clone(&self) -> Box<ComponentConfig>
+    {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug)]
+struct Lookahead<'a> {
+    config: LookaheadConfig,
+    input: Option<InputPortHandle<'a>>,
+    output: Option<OutputPortHandle<'a>>,
+}
+
+impl<'a> Component<'a> for Lookahead<'a> {
+    fn initialize_ports(&mut self, ports: &mut PortManager<'a>)
+        -> Result<(), PortManagerError>
+    {
+        let name = &self.config.name;
+        self.input = Some(ports.register_input_port(
+                &PortName::new(name, "samples_in"))?);
+        self.output = Some(ports.register_output_port(
+                &PortName::new(name, "samples_out"))?);
+        Ok( () )
+    }
+
+    fn generate(&mut self, ports: &mut RealtimePortManager)
+    {
+        let x = ports.get_port_value(&self.input.unwrap());
+        ports.set_port_value(&self.output.unwrap(), x);
+    }
+
+    fn latency(&self) -> usize
+    {
+        self.config.frames
+    }
+
+    fn get_name(&self) -> String
+    {
+        self.config.name.clone()
+    }
+}
+
+/// Add a component which passes samples_in through to samples_out, and
+/// reports the given latency
+pub fn lookahead(patch: &mut Patch, name: &str, frames: usize)
+{
+    patch.components.push(Box::new(LookaheadConfig {
+        name: name.to_owned(),
+        frames,
+    }));
+}
+
+/// A delay of `time` seconds without any feedback, with room for up to a second
+pub fn delay(name: &str, time: f32, mix: f32) -> DelayConfig
+{
+    DelayConfig {
+        name: name.to_owned(),
+        time,
+        sync: "none".to_owned(),
+        feedback: 0.0,
+        mix,
+        max_time: 1.0,
+    }
+}
+
+fn connection(first: (&str, &str), second: (&str, &str)) -> Connection
+{
+    Connection {
+        first: PortName::new(first.0, first.1),
+        second: PortName::new(second.0, second.1),
+    }
+}
+
+/// Connect two of the patch's components, given as (component, port)
+pub fn connect(patch: &mut Patch, first: (&str, &str), second: (&str, &str))
+{
+    patch.connections.push(connection(first, second));
+}
+
+/// Connect two of the patch's effects, given as (component, port)
+pub fn connect_effect(
+    patch: &mut Patch,
+    first: (&str, &str),
+    second: (&str, &str))
+{
+    patch.effect_connections.push(connection(first, second));
+}
@@ -5,37 +5,56 @@ use std::collections::VecDeque;
 
 type AdjacencyMatrix = util::nmat::Matrix<bool, util::nmat::RowMajor>;
 
+fn remove_back_edges_dfs(
+    adj: &mut AdjacencyMatrix,
+    i: usize,
+    visited: &mut Vec<bool>,
+    on_path: &mut Vec<bool>)
+{
+    let (n, _) = adj.dim();
+    visited[i] = true;
+    on_path[i] = true;
+
+    for j in 0..n {
+        if adj[(i, j)] {
+            if on_path[j] {
+                // this must be a back edge, remove it from the graph
+                adj[(i, j)] = false;
+            } else if !visited[j] {
+                remove_back_edges_dfs(adj, j, visited, on_path);
+            }
+        }
+    }
+
+    on_path[i] = false;
+}
+
 /// Remove back edges, if any, from the adjacent matrix, in place
-/// Assumes the graph is fully connected, starts traversal from node 0
+/// Starts traversal from node 0
 pub fn remove_back_edges(adj: &mut AdjacencyMatrix)
+{
+    remove_back_edges_from(adj, 0);
+}
+
+/// Remove back edges, if any, from the adjacent matrix, in place
+/// Starts traversal from the given node, so edges leading back to it are the
+/// ones removed. Nodes which can't be reached from it are traversed afterwards
+pub fn remove_back_edges_from(adj: &mut AdjacencyMatrix, start: usize)
 {
     let (n, m) = adj.dim();
     assert!(n == m);
 
     let mut visited: Vec<bool> = iter::repeat(false).take(n).collect();
-    let mut next = VecDeque::new();
-
-    next.push_back(0);
-
-    while !next.is_empty() {
-        let i = next.pop_front().unwrap();
-        visited[i] = true;
+    let mut on_path: Vec<bool> = iter::repeat(false).take(n).collect();
 
-        // TODO implement some nicer iterator?
-        for j in 0..n {
-            if adj[(i, j)] {
-                if visited[j] {
-                    // this must be a back edge, remove it from the graph
-                    adj[(i, j)] = false;
-                } else {
-                    next.push_back(j);
-                }
-            }
+    let rest = (0..n).filter(|&i| i != start);
+    for i in iter::once(start).chain(rest) {
+        if i < n && !visited[i] {
+            remove_back_edges_dfs(adj, i, &mut visited, &mut on_path);
         }
     }
 }
 
-
 // TODO test helper function independently
 
 fn insert_all_with_no_preds(
@@ -160,6 +179,37 @@ mod test {
         assert!(adj[(0, 1)]);
     }
 
+    #[test]
+    fn test_start_unreachable() {
+        let mut adj: AdjacencyMatrix = util::nmat::Matrix::new((3, 3));
+        // 1 and 2 form a cycle, which feeds 0 (which has no outgoing edges)
+        adj[(1, 2)] = true;
+        adj[(2, 1)] = true;
+        adj[(1, 0)] = true;
+
+        remove_back_edges(&mut adj);
+        assert!(!adj[(1, 2)] || !adj[(2, 1)]);
+        assert!(adj[(1, 0)]);
+
+        let res = topological_sort(&mut adj);
+        assert_eq!(res.len(), 3);
+    }
+
+    #[test]
+    fn test_start_from() {
+        let mut adj: AdjacencyMatrix = util::nmat::Matrix::new((3, 3));
+        // connect 0 -> 1, 1 -> 2, 2 -> 0
+        adj[(0, 1)] = true;
+        adj[(1, 2)] = true;
+        adj[(2, 0)] = true;
+
+        remove_back_edges_from(&mut adj, 1);
+
+        // the edge leading back to 1 is removed
+        assert!(!adj[(0, 1)]);
+        assert!(adj[(1, 2)] && adj[(2, 0)]);
+    }
+
     #[test]
     fn simple_topo() {
         let mut adj: AdjacencyMatrix = util::nmat::Matrix::new((3, 3));
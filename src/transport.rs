@@ -0,0 +1,456 @@
+// Tempo and transport
+// The tempo, time signature and transport state reach components as audio
+// property changes, from JACK transport or from MIDI clock. A BeatClock turns
+// those changes back into a position in beats, one frame at a time, which is
+// all a component needs to sync to the beat.
+
+use audioprops::{AudioProperties, TransportState};
+use midi::{MidiMessage, MidiStatus};
+
+const DEFAULT_TEMPO: f32 = 120.0;
+
+// MIDI clock sends 24 ticks for every beat
+const MIDI_CLOCK_TICKS_PER_BEAT: f64 = 24.0;
+
+// song position pointers count sixteenth notes
+const MIDI_BEATS_PER_SONG_POSITION: f64 = 0.25;
+
+// how quickly the measured tempo follows the clock, between 0 and 1
+const MIDI_CLOCK_SMOOTHING: f64 = 0.1;
+
+/// Follows the transport, so a component can find out where the beat is
+/// Starts stopped, at the start of the song, at 120 beats per minute in 4/4
+#[derive(Debug, Clone)]
+pub struct BeatClock {
+    sample_rate: Option<f32>,
+    tempo: f32,
+    beats_per_bar: f32,
+    beat_type: f32,
+    state: TransportState,
+    position: f64,
+}
+
+impl BeatClock {
+    pub fn new() -> Self
+    {
+        Self {
+            sample_rate: None,
+            tempo: DEFAULT_TEMPO,
+            beats_per_bar: 4.0,
+            beat_type: 4.0,
+            state: TransportState::Stopped,
+            position: 0.0,
+        }
+    }
+
+    pub fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        match prop {
+            AudioProperties::SampleRate(r) => self.sample_rate = Some(r),
+            AudioProperties::Tempo(t) => self.tempo = t,
+            AudioProperties::TimeSignature(b, t) => {
+                self.beats_per_bar = b;
+                self.beat_type = t;
+            },
+            AudioProperties::Transport(s) => self.state = s,
+            AudioProperties::SongPosition(p) => self.position = p,
+            _ => (),
+        }
+    }
+
+    /// Move forward by one frame, if the transport is rolling
+    /// Returns the position before moving
+    pub fn tick(&mut self) -> f64
+    {
+        let position = self.position;
+        if self.state == TransportState::Rolling {
+            if let Some(r) = self.sample_rate {
+                self.position += self.tempo as f64 / (60.0 * r as f64);
+            }
+        }
+
+        position
+    }
+
+    /// The position of the transport, in beats from the start of the song
+    pub fn position(&self) -> f64
+    {
+        self.position
+    }
+
+    /// How far through a cycle of the given number of beats the transport is,
+    /// in [0, 1)
+    pub fn phase(&self, beats: f64) -> f32
+    {
+        if beats <= 0.0 {
+            return 0.0;
+        }
+
+        let p = (self.position / beats).fract() as f32;
+        if p < 0.0 { p + 1.0 } else { p }
+    }
+
+    pub fn tempo(&self) -> f32
+    {
+        self.tempo
+    }
+
+    pub fn beats_per_bar(&self) -> f32
+    {
+        self.beats_per_bar
+    }
+
+    pub fn is_rolling(&self) -> bool
+    {
+        self.state == TransportState::Rolling
+    }
+
+    /// The length of a number of beats in frames, if the sample rate is known
+    pub fn beats_to_frames(&self, beats: f32) -> Option<f32>
+    {
+        self.sample_rate.map(|r| beats * 60.0 * r / self.tempo)
+    }
+
+    /// Everything needed to bring another listener up to date
+    pub fn snapshot(&self) -> [AudioProperties; 4]
+    {
+        [
+            AudioProperties::Tempo(self.tempo),
+            AudioProperties::TimeSignature(self.beats_per_bar, self.beat_type),
+            AudioProperties::Transport(self.state),
+            AudioProperties::SongPosition(self.position),
+        ]
+    }
+}
+
+/// The length in beats of a note division, like "1/4" for a quarter note
+/// (one beat), "1/8t" for an eighth note triplet, "1/16d" for a dotted
+/// sixteenth or "2" for two whole notes. A beat is a quarter note
+pub fn division_beats(name: &str) -> Option<f64>
+{
+    // both suffixes are a single byte
+    let (name, scale) = if name.ends_with('t') {
+        (&name[..name.len() - 1], 2.0 / 3.0)
+    } else if name.ends_with('d') {
+        (&name[..name.len() - 1], 1.5)
+    } else {
+        (name, 1.0)
+    };
+
+    let mut parts = name.splitn(2, '/');
+    let num: f64 = parts.next()?.parse().ok()?;
+    let den: f64 = match parts.next() {
+        Some(d) => d.parse().ok()?,
+        None => 1.0,
+    };
+
+    let beats = 4.0 * num / den * scale;
+    if beats > 0.0 && beats.is_finite() { Some(beats) } else { None }
+}
+
+/// Turns MIDI clock, start, stop, continue and song position messages into
+/// property changes. The tempo is measured from the time between clock ticks
+#[derive(Debug, Clone)]
+pub struct MidiClock {
+    sample_rate: Option<f32>,
+    // frame of the last clock tick
+    last_tick: Option<u64>,
+    // smoothed number of frames between ticks
+    interval: Option<f64>,
+    // the tempo last sent, to the nearest tenth of a beat per minute
+    tempo: Option<f32>,
+}
+
+impl MidiClock {
+    pub fn new() -> Self
+    {
+        Self {
+            sample_rate: None,
+            last_tick: None,
+            interval: None,
+            tempo: None,
+        }
+    }
+
+    pub fn handle_audio_property_change(&mut self, prop: AudioProperties)
+    {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+        }
+    }
+
+    /// Handle a MIDI message which arrived at the given frame (counted from
+    /// any fixed point). Returns the properties which changed
+    pub fn handle(&mut self, data: &[u8], frame: u64)
+        -> [Option<AudioProperties>; 2]
+    {
+        let rolling = AudioProperties::Transport(TransportState::Rolling);
+        let stopped = AudioProperties::Transport(TransportState::Stopped);
+
+        match (MidiMessage { data }.status(), data.len()) {
+            (Some(MidiStatus::TimingClock), _) => [self.tick(frame), None],
+
+            (Some(MidiStatus::Start), _) => {
+                self.last_tick = None;
+                [Some(AudioProperties::SongPosition(0.0)), Some(rolling)]
+            },
+
+            (Some(MidiStatus::Continue), _) => {
+                self.last_tick = None;
+                [Some(rolling), None]
+            },
+
+            (Some(MidiStatus::Stop), _) => [Some(stopped), None],
+
+            (Some(MidiStatus::SongPositionPointer), 3) => {
+                let p = data[1] as u16 | (data[2] as u16) << 7;
+                let beats = p as f64 * MIDI_BEATS_PER_SONG_POSITION;
+                [Some(AudioProperties::SongPosition(beats)), None]
+            },
+
+            _ => [None, None],
+        }
+    }
+
+    fn tick(&mut self, frame: u64) -> Option<AudioProperties>
+    {
+        let last = self.last_tick.replace(frame);
+        let elapsed = match last {
+            Some(last) if frame > last => (frame - last) as f64,
+            _ => return None,
+        };
+
+        let interval = match self.interval {
+            Some(i) => i + (elapsed - i) * MIDI_CLOCK_SMOOTHING,
+            None => elapsed,
+        };
+        self.interval = Some(interval);
+
+        let rate = self.sample_rate? as f64;
+        let ticks_per_minute = 60.0 * rate / interval;
+        let beats_per_minute = ticks_per_minute / MIDI_CLOCK_TICKS_PER_BEAT;
+        let tempo = (beats_per_minute * 10.0).round() as f32 / 10.0;
+
+        if self.tempo == Some(tempo) {
+            None
+        } else {
+            self.tempo = Some(tempo);
+            Some(AudioProperties::Tempo(tempo))
+        }
+    }
+}
+
+/// A snapshot of an external transport, like JACK's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportPosition {
+    pub rolling: bool,
+    /// The frame the transport is at, and the sample rate of its frames
+    pub frame: u64,
+    pub frame_rate: u32,
+    /// Only known if the transport keeps track of bars and beats
+    pub tempo: Option<f32>,
+    pub time_signature: Option<(f32, f32)>,
+    pub beats: Option<f64>,
+}
+
+/// Turns snapshots of an external transport, taken once a block, into
+/// property changes
+#[derive(Debug, Clone)]
+pub struct TransportFollower {
+    last: Option<TransportPosition>,
+    tempo: f32,
+}
+
+impl TransportFollower {
+    pub fn new() -> Self
+    {
+        Self {
+            last: None,
+            tempo: DEFAULT_TEMPO,
+        }
+    }
+
+    /// Compare a snapshot to the one taken a block of `frames` earlier.
+    /// Returns the properties which changed
+    pub fn update(&mut self, pos: TransportPosition, frames: u64)
+        -> [Option<AudioProperties>; 4]
+    {
+        let mut changes = [None; 4];
+        let last = self.last.replace(pos);
+
+        if let Some(t) = pos.tempo {
+            self.tempo = t;
+        }
+
+        let state = if pos.rolling {
+            TransportState::Rolling
+        } else {
+            TransportState::Stopped
+        };
+
+        // the position is only sent when it doesn't follow from the last one
+        let jumped = match last {
+            Some(last) => {
+                let expected = if last.rolling {
+                    last.frame + frames
+                } else {
+                    last.frame
+                };
+
+                last.rolling != pos.rolling || pos.frame != expected
+            },
+            None => true,
+        };
+
+        if last.map(|l| l.rolling) != Some(pos.rolling) {
+            changes[0] = Some(AudioProperties::Transport(state));
+        }
+
+        if pos.tempo.is_some() && last.and_then(|l| l.tempo) != pos.tempo {
+            changes[1] = Some(AudioProperties::Tempo(self.tempo));
+        }
+
+        if let Some((b, t)) = pos.time_signature {
+            if last.and_then(|l| l.time_signature) != pos.time_signature {
+                changes[2] = Some(AudioProperties::TimeSignature(b, t));
+            }
+        }
+
+        if jumped {
+            let beats = pos.beats.unwrap_or_else(|| {
+                let seconds = pos.frame as f64 / pos.frame_rate as f64;
+                seconds * self.tempo as f64 / 60.0
+            });
+
+            changes[3] = Some(AudioProperties::SongPosition(beats));
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_beat_clock() {
+        let mut clock = BeatClock::new();
+        clock.handle_audio_property_change(AudioProperties::SampleRate(100.0));
+        clock.handle_audio_property_change(AudioProperties::Tempo(60.0));
+
+        // stopped, so the position doesn't move
+        clock.tick();
+        assert_eq!(clock.position(), 0.0);
+
+        let rolling = AudioProperties::Transport(TransportState::Rolling);
+        clock.handle_audio_property_change(rolling);
+        for _ in 0..150 {
+            clock.tick();
+        }
+
+        assert!((clock.position() - 1.5).abs() < 1e-9);
+        assert!((clock.phase(1.0) - 0.5).abs() < 1e-6);
+        assert!((clock.phase(4.0) - 0.375).abs() < 1e-6);
+        assert_eq!(clock.beats_to_frames(2.0), Some(200.0));
+
+        clock.handle_audio_property_change(AudioProperties::SongPosition(8.0));
+        assert_eq!(clock.phase(4.0), 0.0);
+    }
+
+    #[test]
+    fn test_division_beats() {
+        assert_eq!(division_beats("1/4"), Some(1.0));
+        assert_eq!(division_beats("1/16"), Some(0.25));
+        assert_eq!(division_beats("2"), Some(8.0));
+        assert_eq!(division_beats("1/8d"), Some(0.75));
+        assert!((division_beats("1/4t").unwrap() - 2.0 / 3.0).abs() < 1e-12);
+
+        assert_eq!(division_beats("quarter"), None);
+        assert_eq!(division_beats("1/0"), None);
+        assert_eq!(division_beats(""), None);
+    }
+
+    #[test]
+    fn test_midi_clock_tempo() {
+        let mut clock = MidiClock::new();
+        let rate = AudioProperties::SampleRate(48000.0);
+        clock.handle_audio_property_change(rate);
+
+        // 120 beats per minute is 1000 frames per tick
+        assert_eq!(clock.handle(&[0xF8], 0), [None, None]);
+        assert_eq!(clock.handle(&[0xF8], 1000),
+                   [Some(AudioProperties::Tempo(120.0)), None]);
+
+        // the same tempo isn't sent again
+        assert_eq!(clock.handle(&[0xF8], 2000), [None, None]);
+
+        // a jittery clock settles on the tempo
+        let mut frame = 2000;
+        for i in 0..200 {
+            frame += if i % 2 == 0 { 990 } else { 1010 };
+            clock.handle(&[0xF8], frame);
+        }
+        assert_eq!(clock.tempo.map(|t| t.round()), Some(120.0));
+    }
+
+    #[test]
+    fn test_midi_transport() {
+        let mut clock = MidiClock::new();
+        let rolling = AudioProperties::Transport(TransportState::Rolling);
+        let stopped = AudioProperties::Transport(TransportState::Stopped);
+
+        assert_eq!(clock.handle(&[0xFA], 0),
+                   [Some(AudioProperties::SongPosition(0.0)), Some(rolling)]);
+        assert_eq!(clock.handle(&[0xFC], 0), [Some(stopped), None]);
+
+        // 0x81 sixteenth notes is 32.25 beats
+        assert_eq!(clock.handle(&[0xF2, 0x01, 0x01], 0),
+                   [Some(AudioProperties::SongPosition(32.25)), None]);
+        assert_eq!(clock.handle(&[0xFB], 0), [Some(rolling), None]);
+        assert_eq!(clock.handle(&[0x90, 60, 100], 0), [None, None]);
+    }
+
+    #[test]
+    fn test_transport_follower() {
+        let mut follower = TransportFollower::new();
+        let mut pos = TransportPosition {
+            rolling: false,
+            frame: 0,
+            frame_rate: 48000,
+            tempo: None,
+            time_signature: None,
+            beats: None,
+        };
+
+        let stopped = AudioProperties::Transport(TransportState::Stopped);
+        let rolling = AudioProperties::Transport(TransportState::Rolling);
+
+        assert_eq!(follower.update(pos, 64), [
+            Some(stopped), None, None, Some(AudioProperties::SongPosition(0.0)),
+        ]);
+        assert_eq!(follower.update(pos, 64), [None; 4]);
+
+        // rolling without bars and beats, at the default tempo
+        pos.rolling = true;
+        pos.frame = 48000;
+        assert_eq!(follower.update(pos, 64), [
+            Some(rolling), None, None, Some(AudioProperties::SongPosition(2.0)),
+        ]);
+
+        pos.frame += 64;
+        assert_eq!(follower.update(pos, 64), [None; 4]);
+
+        // relocated, with bars and beats
+        pos.frame = 96000;
+        pos.tempo = Some(90.0);
+        pos.time_signature = Some((3.0, 4.0));
+        pos.beats = Some(7.5);
+        assert_eq!(follower.update(pos, 64), [
+            None,
+            Some(AudioProperties::Tempo(90.0)),
+            Some(AudioProperties::TimeSignature(3.0, 4.0)),
+            Some(AudioProperties::SongPosition(7.5)),
+        ]);
+    }
+}
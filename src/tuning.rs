@@ -0,0 +1,542 @@
+// Mapping MIDI notes to frequencies
+// The default tuning is 12 tone equal temperament at A440. Other tunings can
+// be loaded from Scala scale (.scl) and keyboard mapping (.kbm) files, and
+// individual notes can be retuned at runtime with MIDI Tuning Standard sysex
+// messages.
+// Scala file formats: http://www.huygens-fokker.org/scala/scl_format.html
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const NOTES: usize = 128;
+
+// MTS sysex messages
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const NON_REALTIME: u8 = 0x7E;
+const REALTIME: u8 = 0x7F;
+const MIDI_TUNING: u8 = 0x08;
+const BULK_DUMP: u8 = 0x01;
+const SINGLE_NOTE: u8 = 0x02;
+const SINGLE_NOTE_BANK: u8 = 0x07;
+
+/// The frequency of a (possibly fractional) note in 12 tone equal temperament
+fn equal_tempered(note: f32) -> f32
+{
+    440.0 * 2.0_f32.powf((note - 69.0) / 12.0)
+}
+
+fn read_file(path: &Path) -> Result<String, String>
+{
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    Ok(text)
+}
+
+/// Lines of a Scala file which are not comments
+/// The description line of a scale file may be blank, so blank lines are kept
+fn scala_lines(text: &str) -> Vec<&str>
+{
+    text.lines()
+        .filter(|l| !l.starts_with('!'))
+        .map(|l| l.trim())
+        .collect()
+}
+
+/// A Scala scale: a list of pitches (in cents) above the implicit 1/1.
+/// The last pitch is the period of the scale, usually an octave
+#[derive(Debug, PartialEq, Clone)]
+pub struct Scale {
+    pub description: String,
+    pub cents: Vec<f64>,
+}
+
+fn parse_pitch(s: &str) -> Result<f64, String>
+{
+    // only the first field on the line matters
+    let s = s.split_whitespace().next().unwrap_or("");
+    let err = |_| format!("bad pitch '{}'", s);
+
+    if s.contains('.') {
+        return s.parse::<f64>().map_err(err);
+    }
+
+    let ratio = match s.find('/') {
+        Some(i) => {
+            let n = s[..i].parse::<f64>().map_err(err)?;
+            let d = s[i + 1..].parse::<f64>().map_err(err)?;
+            n / d
+        },
+        None => s.parse::<f64>().map_err(err)?,
+    };
+
+    if ratio <= 0.0 {
+        return Err(format!("bad pitch '{}'", s));
+    }
+
+    Ok(1200.0 * ratio.log2())
+}
+
+impl Scale {
+    pub fn parse(text: &str) -> Result<Self, String>
+    {
+        let lines = scala_lines(text);
+        if lines.len() < 2 {
+            return Err("scale is missing description or note count".to_owned());
+        }
+
+        let count = lines[1].split_whitespace().next().unwrap_or("")
+            .parse::<usize>()
+            .map_err(|_| format!("bad note count '{}'", lines[1]))?;
+
+        let pitches: Vec<&str> = lines[2..].iter()
+            .cloned()
+            .filter(|l| !l.is_empty())
+            .collect();
+
+        if count == 0 || pitches.len() < count {
+            return Err(format!("expected {} pitches in scale", count));
+        }
+
+        let mut cents = Vec::new();
+        for p in pitches[..count].iter() {
+            cents.push(parse_pitch(p)?);
+        }
+
+        Ok(Self {
+            description: lines[0].to_owned(),
+            cents,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String>
+    {
+        Self::parse(&read_file(path)?)
+    }
+
+    /// Pitch of any scale degree, in cents. Degrees past the end of the scale
+    /// (or below zero) are transposed by the period
+    fn degree_cents(&self, degree: i64) -> f64
+    {
+        let n = self.cents.len() as i64;
+        let period = self.cents[self.cents.len() - 1];
+
+        let octave = (degree as f64 / n as f64).floor();
+        let step = degree - octave as i64 * n;
+
+        let base = if step == 0 { 0.0 } else { self.cents[step as usize - 1] };
+        octave * period + base
+    }
+}
+
+/// A Scala keyboard mapping, describing which keys play which scale degrees
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    /// The key which plays the first degree of the scale
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_frequency: f64,
+    /// The scale degree which is repeated between mapping patterns. If this is
+    /// zero, the period of the scale is used
+    pub octave_degree: usize,
+    /// The pattern of scale degrees, repeated across the keyboard. None is an
+    /// unmapped key. If the pattern is empty, keys map to consecutive degrees
+    pub mapping: Vec<Option<usize>>,
+}
+
+fn parse_field<T: ::std::str::FromStr>(lines: &[&str], i: usize, name: &str)
+    -> Result<T, String>
+{
+    lines.get(i)
+        .and_then(|l| l.split_whitespace().next())
+        .and_then(|f| f.parse::<T>().ok())
+        .ok_or(format!("bad or missing {} in keyboard mapping", name))
+}
+
+impl KeyboardMapping {
+    /// Consecutive keys play consecutive degrees, starting at middle C, and A
+    /// above middle C is 440Hz
+    pub fn standard() -> Self
+    {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String>
+    {
+        let lines: Vec<&str> = scala_lines(text).into_iter()
+            .filter(|l| !l.is_empty())
+            .collect();
+
+        let size: usize = parse_field(&lines, 0, "map size")?;
+
+        let mut mapping = Vec::new();
+        for i in 0..size {
+            let entry = lines.get(7 + i)
+                .and_then(|l| l.split_whitespace().next())
+                .unwrap_or("x");
+
+            if entry == "x" {
+                mapping.push(None);
+            } else {
+                mapping.push(Some(parse_field(&lines, 7 + i, "mapping")?));
+            }
+        }
+
+        Ok(Self {
+            first_note: parse_field(&lines, 1, "first note")?,
+            last_note: parse_field(&lines, 2, "last note")?,
+            middle_note: parse_field(&lines, 3, "middle note")?,
+            reference_note: parse_field(&lines, 4, "reference note")?,
+            reference_frequency: parse_field(&lines, 5, "reference frequency")?,
+            octave_degree: parse_field(&lines, 6, "octave degree")?,
+            mapping,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String>
+    {
+        Self::parse(&read_file(path)?)
+    }
+
+    /// The pitch of the given key in cents above the middle note, if the key
+    /// is mapped
+    fn cents(&self, scale: &Scale, note: u8) -> Option<f64>
+    {
+        let offset = note as i64 - self.middle_note as i64;
+        if self.mapping.is_empty() {
+            return Some(scale.degree_cents(offset));
+        }
+
+        let size = self.mapping.len() as i64;
+        let pattern = (offset as f64 / size as f64).floor() as i64;
+        let key = (offset - pattern * size) as usize;
+
+        // each repeat of the pattern is transposed by the formal octave
+        let octave = match self.octave_degree {
+            0 => scale.degree_cents(scale.cents.len() as i64),
+            d => scale.degree_cents(d as i64),
+        };
+
+        self.mapping[key]
+            .map(|d| pattern as f64 * octave + scale.degree_cents(d as i64))
+    }
+}
+
+/// A table of frequencies for every MIDI note
+#[derive(Clone)]
+pub struct Tuning {
+    frequencies: [f32; NOTES],
+}
+
+impl fmt::Debug for Tuning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error>
+    {
+        write!(f, "Tuning {{ frequencies: {:?} }}", &self.frequencies[..])
+    }
+}
+
+impl Tuning {
+    /// 12 tone equal temperament, A440
+    pub fn equal_temperament() -> Self
+    {
+        let mut frequencies = [0.0; NOTES];
+        for (note, f) in frequencies.iter_mut().enumerate() {
+            *f = equal_tempered(note as f32);
+        }
+
+        Self { frequencies }
+    }
+
+    /// Tune the keyboard to a Scala scale. Keys which are not mapped, or are
+    /// outside of the mapped range, keep their equal tempered frequency
+    pub fn from_scala(scale: &Scale, mapping: &KeyboardMapping)
+        -> Result<Self, String>
+    {
+        let reference = mapping.cents(scale, mapping.reference_note)
+            .ok_or("the reference note is not mapped".to_owned())?;
+
+        let mut tuning = Self::equal_temperament();
+        for note in mapping.first_note..(mapping.last_note.saturating_add(1)) {
+            if note as usize >= NOTES {
+                break;
+            }
+
+            if let Some(c) = mapping.cents(scale, note) {
+                let cents = c - reference;
+                let f = mapping.reference_frequency * (cents / 1200.0).exp2();
+                tuning.frequencies[note as usize] = f as f32;
+            }
+        }
+
+        Ok(tuning)
+    }
+
+    pub fn from_files(scale: &Path, mapping: Option<&Path>)
+        -> Result<Self, String>
+    {
+        let scale = Scale::from_file(scale)?;
+        let mapping = match mapping {
+            Some(path) => KeyboardMapping::from_file(path)?,
+            None => KeyboardMapping::standard(),
+        };
+
+        Self::from_scala(&scale, &mapping)
+    }
+
+    pub fn frequency(&self, note: u8) -> f32
+    {
+        self.frequencies[note as usize & 0x7F]
+    }
+
+    /// Apply a MIDI Tuning Standard sysex message. Supports bulk tuning dumps
+    /// and single note tuning changes. Tuning programs and banks are ignored,
+    /// every message retunes the active tuning.
+    /// Returns true if the message changed the tuning. Never allocates.
+    pub fn handle_sysex(&mut self, data: &[u8]) -> bool
+    {
+        // F0 <7E|7F> <device> 08 <format> ...
+        if data.len() < 6 || data[0] != SYSEX_START || data[3] != MIDI_TUNING {
+            return false;
+        }
+
+        if data[1] != NON_REALTIME && data[1] != REALTIME {
+            return false;
+        }
+
+        let body = &data[5..];
+        match data[4] {
+            // <program> <16 byte name> <128 * 3 bytes> <checksum> F7
+            BULK_DUMP => {
+                if body.len() < 1 + 16 + 3 * NOTES {
+                    return false;
+                }
+
+                let values = &body[17..17 + 3 * NOTES];
+                for (note, v) in values.chunks(3).enumerate() {
+                    self.set_from_mts(note as u8, v);
+                }
+
+                true
+            },
+
+            // <program> <count> <count * (note + 3 bytes)> F7
+            SINGLE_NOTE => self.single_note_changes(&body[1..]),
+
+            // <bank> <program> <count> <count * (note + 3 bytes)> F7
+            SINGLE_NOTE_BANK if body.len() > 2 => {
+                self.single_note_changes(&body[2..])
+            },
+
+            _ => false,
+        }
+    }
+
+    fn single_note_changes(&mut self, data: &[u8]) -> bool
+    {
+        let count = match data.first() {
+            Some(&c) => c as usize,
+            None => return false,
+        };
+
+        let changes = &data[1..];
+        if changes.len() < 4 * count {
+            return false;
+        }
+
+        for change in changes[..4 * count].chunks(4) {
+            self.set_from_mts(change[0], &change[1..]);
+        }
+
+        true
+    }
+
+    /// MTS frequencies are a semitone, then a 14-bit fraction of a semitone
+    fn set_from_mts(&mut self, note: u8, v: &[u8])
+    {
+        // 7F 7F 7F means "leave this note alone"
+        if v[0] == 0x7F && v[1] == 0x7F && v[2] == 0x7F {
+            return;
+        }
+
+        if v.iter().any(|&b| b == SYSEX_END) || note as usize >= NOTES {
+            return;
+        }
+
+        let fraction = ((v[1] as u32) << 7 | v[2] as u32) as f32 / 16384.0;
+        let f = equal_tempered(v[0] as f32 + fraction);
+        self.frequencies[note as usize] = f;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool
+    {
+        (a - b).abs() < 0.01
+    }
+
+    const TWELVE_TET: &'static str = "! 12tet.scl
+!
+12 tone equal temperament
+ 12
+!
+ 100.0
+ 200.
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    const JUST: &'static str = "! just.scl
+5 limit just major scale
+7
+9/8
+5/4
+4/3
+3/2 a comment after the pitch
+5/3
+15/8
+2
+";
+
+    // a white keys only mapping, with D4 as the reference at 300Hz
+    const WHITE_KEYS: &'static str = "! white.kbm
+12
+0
+127
+60
+62
+300.0
+7
+! mapping
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    #[test]
+    fn test_equal_temperament() {
+        let t = Tuning::equal_temperament();
+        assert!(close(t.frequency(69), 440.0));
+        assert!(close(t.frequency(81), 880.0));
+        assert!(close(t.frequency(60), 261.63));
+    }
+
+    #[test]
+    fn test_parse_scale() {
+        let s = Scale::parse(JUST).unwrap();
+        assert_eq!(s.description, "5 limit just major scale");
+        assert_eq!(s.cents.len(), 7);
+        assert!((s.cents[3] - 701.955).abs() < 0.001);
+        assert_eq!(s.cents[6], 1200.0);
+
+        assert!(Scale::parse("desc\n3\n100.0\n").is_err());
+        assert!(Scale::parse("desc\n1\nnonsense\n").is_err());
+    }
+
+    #[test]
+    fn test_scala_equal_temperament() {
+        let s = Scale::parse(TWELVE_TET).unwrap();
+        let t = Tuning::from_scala(&s, &KeyboardMapping::standard()).unwrap();
+        let et = Tuning::equal_temperament();
+
+        for note in 0..128 {
+            let (a, b) = (t.frequency(note), et.frequency(note));
+            assert!((a / b - 1.0).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_keyboard_mapping() {
+        let s = Scale::parse(JUST).unwrap();
+        let m = KeyboardMapping::parse(WHITE_KEYS).unwrap();
+        assert_eq!(m.mapping.len(), 12);
+        assert_eq!(m.mapping[1], None);
+        assert_eq!(m.mapping[11], Some(6));
+
+        let t = Tuning::from_scala(&s, &m).unwrap();
+        assert!(close(t.frequency(62), 300.0));
+
+        // middle C is a 9/8 below the reference, and C5 an octave above
+        assert!(close(t.frequency(60), 300.0 * 8.0 / 9.0));
+        assert!(close(t.frequency(72), 600.0 * 8.0 / 9.0));
+
+        // G4 is a 3/2 above C
+        assert!(close(t.frequency(67), 300.0 * 8.0 / 9.0 * 1.5));
+
+        // unmapped black keys are left alone
+        assert!(close(t.frequency(61), equal_tempered(61.0)));
+    }
+
+    #[test]
+    fn test_mts_single_note() {
+        let mut t = Tuning::equal_temperament();
+
+        // retune note 60 to a quarter tone above A4, leave 61 alone
+        let msg = [
+            0xF0, 0x7F, 0x7F, 0x08, 0x02, 0x00, 0x02,
+            60, 69, 0x40, 0x00,
+            61, 0x7F, 0x7F, 0x7F,
+            0xF7,
+        ];
+
+        assert!(t.handle_sysex(&msg));
+        assert!(close(t.frequency(60), equal_tempered(69.5)));
+        assert!(close(t.frequency(61), equal_tempered(61.0)));
+    }
+
+    #[test]
+    fn test_mts_bulk_dump() {
+        let mut t = Tuning::equal_temperament();
+
+        // every note plays A4
+        let mut msg = vec![0xF0, 0x7E, 0x00, 0x08, 0x01, 0x00];
+        msg.extend_from_slice(b"all the same A   ");
+        msg.truncate(6 + 16);
+        for _ in 0..128 {
+            msg.extend_from_slice(&[69, 0, 0]);
+        }
+        msg.push(0x00);
+        msg.push(0xF7);
+
+        assert!(t.handle_sysex(&msg));
+        assert!(close(t.frequency(0), 440.0));
+        assert!(close(t.frequency(127), 440.0));
+
+        // not a tuning message
+        assert!(!t.handle_sysex(&[0xF0, 0x7E, 0x00, 0x06, 0x01, 0xF7]));
+    }
+}
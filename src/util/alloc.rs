@@ -0,0 +1,144 @@
+// Allocation auditing, for checking that code is safe to run on the audio
+// thread. Only allocations made by the thread being audited are seen, so tests
+// running in parallel don't interfere with each other.
+//
+// The allocator is only installed in tests, or when the `alloc-audit` feature
+// is enabled. Without it, none of the functions here can see anything.
+//
+// Taking a lock can hold the audio thread up just as badly, so the Mutex in
+// here is audited the same way as an allocation. Locks from anywhere else
+// aren't seen, so anything the audio thread might share should use this one.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::process;
+use std::sync;
+
+pub struct AuditingAllocator;
+
+#[derive(Clone, Copy)]
+enum Audit {
+    Off,
+    // number of allocations, frees and locks seen so far
+    Counting(usize),
+    // any allocation or free is a bug
+    #[cfg_attr(not(feature = "alloc-audit"), allow(dead_code))]
+    Forbidden,
+}
+
+thread_local! {
+    static AUDIT: Cell<Audit> = Cell::new(Audit::Off);
+}
+
+fn record(what: &str)
+{
+    // the thread local may already be gone while the thread is exiting
+    let _ = AUDIT.try_with(|a| {
+        match a.get() {
+            Audit::Off => (),
+            Audit::Counting(n) => a.set(Audit::Counting(n + 1)),
+            Audit::Forbidden => {
+                // the allocator isn't allowed to unwind, and printing needs
+                // to allocate, so stop auditing and abort
+                a.set(Audit::Off);
+                eprintln!("{} on the audio thread", what);
+                process::abort();
+            },
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for AuditingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        record("allocation");
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        record("free");
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize)
+        -> *mut u8
+    {
+        record("reallocation");
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Run the function, and return the number of times it allocated, reallocated
+/// or freed memory, or took a lock
+pub fn count_allocations<F: FnOnce()>(f: F) -> usize
+{
+    AUDIT.with(|a| a.set(Audit::Counting(0)));
+    f();
+    match AUDIT.with(|a| a.replace(Audit::Off)) {
+        Audit::Counting(n) => n,
+        _ => 0,
+    }
+}
+
+/// Run a function which must not allocate or lock. With the `alloc-audit`
+/// feature, the process is aborted if it does
+#[cfg(feature = "alloc-audit")]
+pub fn realtime<R, F: FnOnce() -> R>(f: F) -> R
+{
+    AUDIT.with(|a| a.set(Audit::Forbidden));
+    let res = f();
+    AUDIT.with(|a| a.set(Audit::Off));
+    res
+}
+
+/// Run a function which must not allocate or lock. With the `alloc-audit`
+/// feature, the process is aborted if it does
+#[cfg(not(feature = "alloc-audit"))]
+#[inline(always)]
+pub fn realtime<R, F: FnOnce() -> R>(f: F) -> R
+{
+    f()
+}
+
+/// A mutex which is audited like an allocation
+#[derive(Debug)]
+pub struct Mutex<T> {
+    inner: sync::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self
+    {
+        Self {
+            inner: sync::Mutex::new(value),
+        }
+    }
+
+    /// Lock the mutex, waiting for it if needed. A panic while the lock was
+    /// held doesn't leave the value any less usable here, so poisoning is
+    /// ignored
+    pub fn lock<'a>(&'a self) -> sync::MutexGuard<'a, T>
+    {
+        record("lock");
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_counts_allocations() {
+        assert_eq!(count_allocations(|| ()), 0);
+        assert_eq!(count_allocations(|| { vec![1.0f32; 16]; }), 2);
+    }
+
+    #[test]
+    fn test_counts_locks() {
+        let m = Mutex::new(1);
+        assert_eq!(count_allocations(|| { *m.lock() += 1; }), 1);
+        assert_eq!(*m.lock(), 2);
+    }
+}
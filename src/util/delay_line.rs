@@ -0,0 +1,113 @@
+// A ring buffer of past samples, for delays and the effects built on them
+// The buffer only ever changes size in `reserve`, which is where all of the
+// allocation happens, so pushing and reading are safe on the audio thread.
+// Components reserve when they hear the sample rate, which the engine sees to
+// before their session reaches the audio thread.
+
+#[derive(Debug, Clone)]
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    // where the next sample will be written
+    write: usize,
+}
+
+impl DelayLine {
+    pub fn new() -> Self
+    {
+        Self {
+            buffer: Vec::new(),
+            write: 0,
+        }
+    }
+
+    /// Longest delay, in frames, which can be read
+    pub fn capacity(&self) -> usize
+    {
+        self.buffer.len().saturating_sub(1)
+    }
+
+    /// Make sure delays of up to `frames` can be read. Growing the line
+    /// allocates, and forgets everything in it
+    pub fn reserve(&mut self, frames: usize)
+    {
+        if frames <= self.capacity() {
+            return;
+        }
+
+        // one more, so the longest delay can still be interpolated
+        self.buffer = vec![0.0; frames + 1];
+        self.write = 0;
+    }
+
+    /// Forget everything in the line
+    pub fn clear(&mut self)
+    {
+        for sample in self.buffer.iter_mut() {
+            *sample = 0.0;
+        }
+    }
+
+    pub fn push(&mut self, sample: f32)
+    {
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        self.buffer[self.write] = sample;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+
+    /// The sample pushed `delay` frames ago, where 1 is the last sample pushed.
+    /// Fractional delays are linearly interpolated, and delays are kept
+    /// between 1 and the capacity
+    pub fn read(&self, delay: f32) -> f32
+    {
+        let len = self.buffer.len();
+        if len < 2 {
+            return 0.0;
+        }
+
+        let delay = delay.max(1.0).min(self.capacity() as f32);
+        let whole = delay.floor();
+        let frac = delay - whole;
+
+        let newer = (self.write + len - whole as usize) % len;
+        let older = (newer + len - 1) % len;
+        let newer = self.buffer[newer];
+        let older = self.buffer[older];
+
+        newer + (older - newer) * frac
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay_line() {
+        let mut line = DelayLine::new();
+        assert_eq!(line.read(1.0), 0.0);
+
+        line.reserve(4);
+        assert_eq!(line.capacity(), 4);
+        for i in 1..7 {
+            line.push(i as f32);
+        }
+
+        assert_eq!(line.read(1.0), 6.0);
+        assert_eq!(line.read(4.0), 3.0);
+        assert_eq!(line.read(2.5), 4.5);
+
+        // out of range delays are clamped
+        assert_eq!(line.read(0.0), 6.0);
+        assert_eq!(line.read(10.0), 3.0);
+
+        // shrinking does nothing, growing starts again
+        line.reserve(2);
+        assert_eq!(line.read(4.0), 3.0);
+        line.reserve(8);
+        assert_eq!(line.capacity(), 8);
+        assert_eq!(line.read(1.0), 0.0);
+    }
+}
@@ -0,0 +1,375 @@
+// Filter design
+// Biquad coefficients from Robert Bristow-Johnson's Audio EQ Cookbook, and
+// steeper low and high pass filters built from cascades of biquads.
+//
+// Butterworth filters are maximally flat, and are 3dB down at the cutoff.
+// Linkwitz-Riley filters are two Butterworth filters in series, which puts
+// them 6dB down at the cutoff, so a low and high pass pair sums flat (used as
+// crossovers).
+
+use std::cmp;
+use std::f32;
+use std::f64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Response {
+    LowPass,
+    HighPass,
+    /// Peaks at 0dB, however narrow it is
+    BandPass,
+    Notch,
+    /// Boosts or cuts around the frequency
+    Peaking,
+    /// Boosts or cuts everything below the frequency
+    LowShelf,
+    /// Boosts or cuts everything above the frequency
+    HighShelf,
+    /// Changes the phase, but not the level
+    AllPass,
+}
+
+impl Response {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "lowpass"   => Some(Response::LowPass),
+            "highpass"  => Some(Response::HighPass),
+            "bandpass"  => Some(Response::BandPass),
+            "notch"     => Some(Response::Notch),
+            "peaking"   => Some(Response::Peaking),
+            "lowshelf"  => Some(Response::LowShelf),
+            "highshelf" => Some(Response::HighShelf),
+            "allpass"   => Some(Response::AllPass),
+            _           => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alignment {
+    Butterworth,
+    LinkwitzRiley,
+}
+
+impl Alignment {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "butterworth"    => Some(Alignment::Butterworth),
+            "linkwitz-riley" => Some(Alignment::LinkwitzRiley),
+            _                => None,
+        }
+    }
+}
+
+/// Coefficients of a biquad, normalized so that a0 is 1
+/// y[n] = b0 x[n] + b1 x[n-1] + b2 x[n-2] - a1 y[n-1] - a2 y[n-2]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coefficients {
+    pub b0: f32,
+    pub b1: f32,
+    pub b2: f32,
+    pub a1: f32,
+    pub a2: f32,
+}
+
+impl Coefficients {
+    /// Passes everything through unchanged
+    pub fn identity() -> Self
+    {
+        Self { b0: 1.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0 }
+    }
+
+    fn normalized(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32)
+        -> Self
+    {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// How much the filter scales a sine wave at the given frequency
+    pub fn magnitude(&self, freq: f32, sample_rate: f32) -> f32
+    {
+        // H(z) at z = e^jw, worked out in double precision
+        let w = 2.0 * f64::consts::PI * freq as f64 / sample_rate as f64;
+        let (c1, s1) = (w.cos(), -w.sin());
+        let (c2, s2) = ((2.0 * w).cos(), -(2.0 * w).sin());
+
+        let (b0, b1, b2) = (self.b0 as f64, self.b1 as f64, self.b2 as f64);
+        let (a1, a2) = (self.a1 as f64, self.a2 as f64);
+
+        let num_re = b0 + b1 * c1 + b2 * c2;
+        let num_im = b1 * s1 + b2 * s2;
+        let den_re = 1.0 + a1 * c1 + a2 * c2;
+        let den_im = a1 * s1 + a2 * s2;
+
+        let num = (num_re * num_re + num_im * num_im).sqrt();
+        let den = (den_re * den_re + den_im * den_im).sqrt();
+        (num / den) as f32
+    }
+}
+
+/// A single biquad. `gain` is in dB, and is only used by the peaking and
+/// shelving filters. For the shelves, `q` sets the steepness of the slope
+pub fn design(
+    response: Response,
+    freq: f32,
+    q: f32,
+    gain: f32,
+    sample_rate: f32)
+-> Coefficients
+{
+    // keep the frequency and q where the formulas make sense
+    let freq = freq.max(1.0).min(sample_rate * 0.49);
+    let q = q.max(0.01);
+
+    let w0 = 2.0 * f32::consts::PI * freq / sample_rate;
+    let (cos, sin) = (w0.cos(), w0.sin());
+    let alpha = sin / (2.0 * q);
+    let a = 10.0_f32.powf(gain / 40.0);
+    let shelf = 2.0 * a.sqrt() * alpha;
+
+    match response {
+        Response::LowPass => Coefficients::normalized(
+            (1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+
+        Response::HighPass => Coefficients::normalized(
+            (1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+
+        Response::BandPass => Coefficients::normalized(
+            alpha, 0.0, -alpha,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+
+        Response::Notch => Coefficients::normalized(
+            1.0, -2.0 * cos, 1.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+
+        Response::Peaking => Coefficients::normalized(
+            1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a,
+            1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a),
+
+        Response::LowShelf => Coefficients::normalized(
+            a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+            (a + 1.0) + (a - 1.0) * cos + shelf,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - shelf),
+
+        Response::HighShelf => Coefficients::normalized(
+            a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+            (a + 1.0) - (a - 1.0) * cos + shelf,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - shelf),
+
+        Response::AllPass => Coefficients::normalized(
+            1.0 - alpha, -2.0 * cos, 1.0 + alpha,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+    }
+}
+
+/// A first order low or high pass, as a biquad. Any other response passes
+/// everything through
+fn first_order(response: Response, freq: f32, sample_rate: f32)
+    -> Coefficients
+{
+    let freq = freq.max(1.0).min(sample_rate * 0.49);
+    let k = (f32::consts::PI * freq / sample_rate).tan();
+    let a1 = (k - 1.0) / (k + 1.0);
+
+    match response {
+        Response::LowPass => Coefficients {
+            b0: k / (k + 1.0),
+            b1: k / (k + 1.0),
+            b2: 0.0,
+            a1,
+            a2: 0.0,
+        },
+
+        Response::HighPass => Coefficients {
+            b0: 1.0 / (k + 1.0),
+            b1: -1.0 / (k + 1.0),
+            b2: 0.0,
+            a1,
+            a2: 0.0,
+        },
+
+        _ => Coefficients::identity(),
+    }
+}
+
+/// The q of one second order section of a Butterworth filter. Odd orders end
+/// with a first order section, which has no q
+pub fn butterworth_q(order: usize, section: usize) -> Option<f32>
+{
+    if section >= order / 2 {
+        return None;
+    }
+
+    // angle of the section's poles from the negative real axis
+    let angle = (order - 2 * section - 1) as f32 / (2 * order) as f32;
+    Some(1.0 / (2.0 * (angle * f32::consts::PI).cos()))
+}
+
+/// Number of biquads needed for a low or high pass of the given order.
+/// Linkwitz-Riley filters only come in even orders, so odd orders are rounded
+/// up
+pub fn cascade_sections(alignment: Alignment, order: usize) -> usize
+{
+    let order = cmp::max(order, 1);
+    match alignment {
+        Alignment::Butterworth => (order + 1) / 2,
+        Alignment::LinkwitzRiley => 2 * cascade_sections(
+            Alignment::Butterworth, (order + 1) / 2),
+    }
+}
+
+/// One biquad of a cascaded low or high pass filter. Running a signal through
+/// every section, from 0 to `cascade_sections`, gives the whole filter
+pub fn cascade_section(
+    response: Response,
+    alignment: Alignment,
+    order: usize,
+    section: usize,
+    freq: f32,
+    sample_rate: f32)
+-> Coefficients
+{
+    let order = cmp::max(order, 1);
+    let (order, section) = match alignment {
+        Alignment::Butterworth => (order, section),
+
+        // the same Butterworth filter, twice
+        Alignment::LinkwitzRiley => {
+            let half = (order + 1) / 2;
+            let sections = cascade_sections(Alignment::Butterworth, half);
+            (half, section % sections)
+        },
+    };
+
+    match butterworth_q(order, section) {
+        Some(q) => design(response, freq, q, 0.0, sample_rate),
+        None => first_order(response, freq, sample_rate),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RATE: f32 = 48000.0;
+
+    fn db(magnitude: f32) -> f32
+    {
+        20.0 * magnitude.log10()
+    }
+
+    fn cascade(alignment: Alignment, order: usize, response: Response)
+        -> Vec<Coefficients>
+    {
+        (0..cascade_sections(alignment, order))
+            .map(|s| cascade_section(
+                    response, alignment, order, s, 1000.0, RATE))
+            .collect()
+    }
+
+    fn cascade_db(sections: &[Coefficients], freq: f32) -> f32
+    {
+        sections.iter().map(|c| db(c.magnitude(freq, RATE))).sum()
+    }
+
+    fn close(a: f32, b: f32) -> bool
+    {
+        (a - b).abs() < 0.1
+    }
+
+    #[test]
+    fn test_pass_filters() {
+        let q = 1.0 / 2.0_f32.sqrt();
+        let lp = design(Response::LowPass, 1000.0, q, 0.0, RATE);
+        let hp = design(Response::HighPass, 1000.0, q, 0.0, RATE);
+
+        assert!(close(db(lp.magnitude(10.0, RATE)), 0.0));
+        assert!(close(db(lp.magnitude(1000.0, RATE)), -3.01));
+        assert!(db(lp.magnitude(10000.0, RATE)) < -38.0);
+
+        assert!(close(db(hp.magnitude(20000.0, RATE)), 0.0));
+        assert!(close(db(hp.magnitude(1000.0, RATE)), -3.01));
+        assert!(db(hp.magnitude(100.0, RATE)) < -38.0);
+    }
+
+    #[test]
+    fn test_band_filters() {
+        let bp = design(Response::BandPass, 1000.0, 4.0, 0.0, RATE);
+        let notch = design(Response::Notch, 1000.0, 4.0, 0.0, RATE);
+        let ap = design(Response::AllPass, 1000.0, 4.0, 0.0, RATE);
+
+        assert!(close(db(bp.magnitude(1000.0, RATE)), 0.0));
+        assert!(db(bp.magnitude(100.0, RATE)) < -20.0);
+
+        assert!(notch.magnitude(1000.0, RATE) < 1e-3);
+        assert!(close(db(notch.magnitude(100.0, RATE)), 0.0));
+
+        for &f in [10.0, 1000.0, 15000.0].iter() {
+            assert!(close(db(ap.magnitude(f, RATE)), 0.0));
+        }
+    }
+
+    #[test]
+    fn test_gain_filters() {
+        let peak = design(Response::Peaking, 1000.0, 2.0, 6.0, RATE);
+        assert!(close(db(peak.magnitude(1000.0, RATE)), 6.0));
+        assert!(close(db(peak.magnitude(20.0, RATE)), 0.0));
+
+        let low = design(Response::LowShelf, 1000.0, 0.707, -12.0, RATE);
+        assert!(close(db(low.magnitude(10.0, RATE)), -12.0));
+        assert!(close(db(low.magnitude(1000.0, RATE)), -6.0));
+        assert!(close(db(low.magnitude(20000.0, RATE)), 0.0));
+
+        let high = design(Response::HighShelf, 1000.0, 0.707, 12.0, RATE);
+        assert!(close(db(high.magnitude(10.0, RATE)), 0.0));
+        assert!(close(db(high.magnitude(1000.0, RATE)), 6.0));
+        assert!(close(db(high.magnitude(20000.0, RATE)), 12.0));
+    }
+
+    #[test]
+    fn test_butterworth() {
+        assert_eq!(cascade_sections(Alignment::Butterworth, 4), 2);
+        assert_eq!(cascade_sections(Alignment::Butterworth, 3), 2);
+
+        for order in 1..9 {
+            let lp = cascade(Alignment::Butterworth, order, Response::LowPass);
+            assert!(close(cascade_db(&lp, 10.0), 0.0));
+            assert!(close(cascade_db(&lp, 1000.0), -3.01));
+
+            // 6dB per octave for each order, well above the cutoff
+            let slope = cascade_db(&lp, 8000.0) - cascade_db(&lp, 16000.0);
+            assert!(slope > 6.0 * order as f32 - 1.0);
+        }
+    }
+
+    #[test]
+    fn test_linkwitz_riley() {
+        assert_eq!(cascade_sections(Alignment::LinkwitzRiley, 4), 2);
+        assert_eq!(cascade_sections(Alignment::LinkwitzRiley, 8), 4);
+
+        for &order in [2, 4, 8].iter() {
+            let lp = cascade(
+                Alignment::LinkwitzRiley, order, Response::LowPass);
+            let hp = cascade(
+                Alignment::LinkwitzRiley, order, Response::HighPass);
+
+            assert!(close(cascade_db(&lp, 1000.0), -6.02));
+            assert!(close(cascade_db(&hp, 1000.0), -6.02));
+        }
+    }
+}
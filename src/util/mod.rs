@@ -1,3 +1,8 @@
+pub mod alloc;
+pub mod delay_line;
+pub mod filter_design;
 pub mod ft;
 pub mod nmat;
+pub mod rng;
+pub mod spsc;
 pub mod vector;
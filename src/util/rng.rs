@@ -0,0 +1,58 @@
+// Pseudo random numbers for audio
+// This is a xorshift generator. It is fast, allocation free, and fully
+// determined by its seed, but it is not suitable for anything other than
+// making noise.
+
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self
+    {
+        // xorshift gets stuck at zero forever
+        let state = if seed == 0 { 0x9E3779B9 } else { seed };
+        Self { state }
+    }
+
+    pub fn next_u32(&mut self) -> u32
+    {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Uniformly distributed in [0, 1)
+    pub fn next_f32(&mut self) -> f32
+    {
+        // use the top 24 bits so that every value is exactly representable
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deterministic() {
+        let mut a = Rng::new(1234);
+        let mut b = Rng::new(1234);
+        for _ in 0..100 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        let mut r = Rng::new(0);
+        for _ in 0..10000 {
+            let v = r.next_f32();
+            assert!(v >= 0.0 && v < 1.0);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Bounded single producer, single consumer queue
+// Neither end ever allocates, locks or blocks, so either end can be used from
+// the audio thread. All of the memory is allocated up front by `channel`.
+// Values are moved through the queue, so large objects can be handed over
+// without copying or allocating.
+
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Ring<T> {
+    slots: Vec<UnsafeCell<Option<T>>>,
+    // next slot to read, only written by the receiver
+    head: AtomicUsize,
+    // next slot to write, only written by the sender
+    tail: AtomicUsize,
+}
+
+// the sender only touches slots the receiver is done with, and the other way
+// around, so sharing the ring is safe as long as the values can be sent
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn next(&self, i: usize) -> usize
+    {
+        (i + 1) % self.slots.len()
+    }
+}
+
+pub struct Sender<T> {
+    ring: Arc<Ring<T>>,
+}
+
+pub struct Receiver<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Create a queue which can hold up to `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>)
+{
+    // one slot is always left empty, to tell a full queue from an empty one
+    let mut slots = Vec::with_capacity(capacity + 1);
+    for _ in 0..(capacity + 1) {
+        slots.push(UnsafeCell::new(None));
+    }
+
+    let ring = Arc::new(Ring {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (Sender { ring: ring.clone() }, Receiver { ring })
+}
+
+impl<T> Sender<T> {
+    /// Add a value to the queue. If the queue is full, the value is handed
+    /// back
+    pub fn send(&mut self, value: T) -> Result<(), T>
+    {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let next = self.ring.next(tail);
+        if next == self.ring.head.load(Ordering::Acquire) {
+            return Err(value);
+        }
+
+        // the slot is empty, the receiver took the last value out of it
+        unsafe { *self.ring.slots[tail].get() = Some(value) };
+        self.ring.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Take the oldest value out of the queue, if there is one
+    pub fn recv(&mut self) -> Option<T>
+    {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*self.ring.slots[head].get()).take() };
+        self.ring.head.store(self.ring.next(head), Ordering::Release);
+        value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_fifo() {
+        let (mut tx, mut rx) = channel(4);
+        assert_eq!(rx.recv(), None);
+
+        for round in 0..3 {
+            tx.send(round).unwrap();
+            tx.send(round + 10).unwrap();
+            assert_eq!(rx.recv(), Some(round));
+            assert_eq!(rx.recv(), Some(round + 10));
+        }
+
+        assert_eq!(rx.recv(), None);
+    }
+
+    #[test]
+    fn test_full() {
+        let (mut tx, mut rx) = channel(2);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(tx.send(3), Err(3));
+
+        assert_eq!(rx.recv(), Some(1));
+        tx.send(3).unwrap();
+        assert_eq!(rx.recv(), Some(2));
+        assert_eq!(rx.recv(), Some(3));
+    }
+
+    #[test]
+    fn test_threads() {
+        let (mut tx, mut rx) = channel(8);
+
+        let producer = thread::spawn(move || {
+            for i in 0..10000 {
+                let mut v = i;
+                while let Err(back) = tx.send(v) {
+                    v = back;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < 10000 {
+            match rx.recv() {
+                Some(v) => {
+                    assert_eq!(v, expected);
+                    expected += 1;
+                },
+                None => thread::yield_now(),
+            }
+        }
+
+        producer.join().unwrap();
+    }
+}
@@ -1,11 +1,63 @@
 use audioprops::AudioProperties;
 use components::Component;
+use control::ControlEvent;
+use events::EventQueue;
+use mappings::{Binding, LearnError};
 use patch::Patch;
 use ports::{InputPortHandle, OutputPortHandle, PortManagerImpl, PortName};
+use ports::PortDirectory;
 use ports::{PortManager, RealtimePortManager, PortManagerError};
-use topo;
+use schedule::Schedule;
 
-use std::collections::HashMap;
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GlideMode {
+    /// Every glide takes the same amount of time
+    ConstantTime,
+    /// Glides take longer the further apart the notes are
+    ConstantRate,
+}
+
+impl GlideMode {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "time" => Some(GlideMode::ConstantTime),
+            "rate" => Some(GlideMode::ConstantRate),
+            _      => None,
+        }
+    }
+}
+
+/// Portamento settings.
+/// For ConstantTime, `time` is the length of every glide in seconds.
+/// For ConstantRate, `time` is the number of seconds needed to glide an octave
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Glide {
+    pub time: f32,
+    pub mode: GlideMode,
+}
+
+impl Glide {
+    /// No glide at all, pitch changes are immediate
+    pub fn off() -> Self
+    {
+        Self {
+            time: 0.0,
+            mode: GlideMode::ConstantTime,
+        }
+    }
+
+    /// Number of samples a glide between the two frequencies should take
+    fn samples(&self, from: f32, to: f32, sample_rate: f32) -> usize
+    {
+        let seconds = match self.mode {
+            GlideMode::ConstantTime => self.time,
+            GlideMode::ConstantRate => (to / from).log2().abs() * self.time,
+        };
+
+        (seconds * sample_rate) as usize
+    }
+}
 
 /// Monophonic set of components.
 #[derive(Debug)]
@@ -17,8 +69,108 @@ pub struct Voice<'a> {
     midi_frequency_in: OutputPortHandle<'a>,
     midi_gate_in: OutputPortHandle<'a>,
     midi_vel_in: OutputPortHandle<'a>,
+    unison_pan_in: OutputPortHandle<'a>,
     midi_control_ports: Vec<OutputPortHandle<'a>>,
-    samples_out: InputPortHandle<'a>,
+    // normalized (and 14-bit, where possible) controller values
+    // the fine controllers (32-63) are folded into their coarse controller
+    midi_control_hires_ports: Vec<Option<OutputPortHandle<'a>>>,
+    // there are far too many parameter numbers to register all of them, so
+    // these are only registered if the patch uses them
+    midi_rpn_ports: Vec<(u16, OutputPortHandle<'a>)>,
+    midi_nrpn_ports: Vec<(u16, OutputPortHandle<'a>)>,
+    // controllers bound directly to component inputs, at most one per port.
+    // Room for every port is reserved up front, so binding never allocates
+    bindings: Vec<Binding<'a>>,
+    // one port per audio channel
+    samples_out: Vec<InputPortHandle<'a>>,
+    // the order the components run in
+    schedule: Schedule,
+
+    // the note currently being played
+    note: Option<u8>,
+    // velocity of the last note started, 0 to 1
+    velocity: f32,
+    // stereo position of this voice within its unison group, -1 to 1
+    pan: f32,
+    // set when the gate has been closed to retrigger a note that was still
+    // sounding, the gate is reopened after the next sample
+    retrigger: bool,
+
+    sample_rate: Option<f32>,
+    glide: Glide,
+    // the frequency written to midi_frequency_out is slewed towards the
+    // target by multiplying it by the glide ratio every sample
+    frequency: f32,
+    target_frequency: f32,
+    glide_ratio: f32,
+    glide_remaining: usize,
+}
+
+/// Name of the voice port which collects the given channel of audio.
+/// Mono patches use "samples_in", stereo patches use "left_in" and "right_in",
+/// and patches with more channels use "channel_N_in", counting from 0
+pub fn channel_port_name(channels: usize, channel: usize) -> String
+{
+    match (channels, channel) {
+        (1, _) => "samples_in".to_owned(),
+        (2, 0) => "left_in".to_owned(),
+        (2, _) => "right_in".to_owned(),
+        (_, n) => format!("channel_{}_in", n),
+    }
+}
+
+fn find_input_port<'a>(ports: &PortManagerImpl<'a>, name: &PortName)
+    -> Result<InputPortHandle<'a>, PortManagerError>
+{
+    ports.find_port(name)
+        .ok_or(PortManagerError::NoSuchPort(name.clone()))
+        .and_then(|port| port.promote_to_input())
+}
+
+/// Replace the binding for the same port, or use a spare slot. Never grows
+/// the bindings past the capacity they were created with
+fn bind<'a>(bindings: &mut Vec<Binding<'a>>, binding: Binding<'a>)
+    -> Result<(), LearnError>
+{
+    let port = binding.port;
+    if let Some(b) = bindings.iter_mut().find(|b| b.port == port) {
+        *b = binding;
+        return Ok(());
+    }
+
+    if bindings.len() == bindings.capacity() {
+        return Err(LearnError::NoFreeBinding);
+    }
+
+    bindings.push(binding);
+    Ok(())
+}
+
+/// Find all of the parameter numbers for ports named "prefix_N" on the voice
+/// which are used by some connection in the patch
+fn used_parameter_ports(patch: &Patch, prefix: &str) -> Vec<u16>
+{
+    let mut parameters = Vec::new();
+    for connection in patch.connections.iter() {
+        let name = &connection.first;
+        if name.component() != "voice" || !name.port().starts_with(prefix) {
+            continue;
+        }
+
+        if let Ok(n) = name.port()[prefix.len()..].parse::<u16>() {
+            if !parameters.contains(&n) {
+                parameters.push(n);
+            }
+        }
+    }
+
+    parameters
+}
+
+fn find_parameter_port<'a>(ports: &[(u16, OutputPortHandle<'a>)], n: u16)
+    -> Option<OutputPortHandle<'a>>
+{
+    ports.iter().find(|&&(p, _)| p == n).map(|&(_, handle)| handle)
 }
 
 impl<'a> Voice<'a> {
@@ -35,8 +187,15 @@ impl<'a> Voice<'a> {
         let midi_vel_in = ports.register_output_port(
             &PortName::new("voice", "midi_velocity_out"))?;
 
-        let samples_out = ports.register_input_port(
-            &PortName::new("voice", "samples_in"))?;
+        let unison_pan_in = ports.register_output_port(
+            &PortName::new("voice", "unison_pan_out"))?;
+
+        let mut samples_out = Vec::new();
+        for c in 0..patch.channels {
+            let n = channel_port_name(patch.channels, c);
+            let pn = PortName::new("voice", n);
+            samples_out.push(ports.register_input_port(&pn)?);
+        }
 
         // register all of the midi control signals
         // there are 128 total available in the midi spec
@@ -47,6 +206,31 @@ impl<'a> Voice<'a> {
             midi_control_ports.push(ports.register_output_port(&pn)?);
         }
 
+        let mut midi_control_hires_ports = Vec::new();
+        for i in 0..128 {
+            if i >= 32 && i < 64 {
+                midi_control_hires_ports.push(None);
+                continue;
+            }
+
+            let n = format!("midi_control_hires_{}", i);
+            let pn = PortName::new("voice", n);
+            midi_control_hires_ports.push(
+                Some(ports.register_output_port(&pn)?));
+        }
+
+        let mut midi_rpn_ports = Vec::new();
+        for p in used_parameter_ports(patch, "midi_rpn_") {
+            let pn = PortName::new("voice", format!("midi_rpn_{}", p));
+            midi_rpn_ports.push((p, ports.register_output_port(&pn)?));
+        }
+
+        let mut midi_nrpn_ports = Vec::new();
+        for p in used_parameter_ports(patch, "midi_nrpn_") {
+            let pn = PortName::new("voice", format!("midi_nrpn_{}", p));
+            midi_nrpn_ports.push((p, ports.register_output_port(&pn)?));
+        }
+
         // Register all of the components with the port manager
         let mut components = Vec::new();
 
@@ -65,35 +249,14 @@ impl<'a> Voice<'a> {
             ports.connect_by_name(&connection.first, &connection.second)?;
         }
 
-        // now the port manager knows all of the connections, we topologically
-        // sort the component connection graph. Some components will use values
-        // produced by other components. We need to do this sort to make sure
-        // that the all of the port values get updated in the right order.
-        let (names, mut adj) = ports.get_component_adjacency_matrix();
-
-        // TODO make sure that the (0, 0) element is always a sane one to start
-        // from.
-
-        // To allow cycles in the component graph, remove any edges which would
-        // form a cycle by pointing back to a previously visited component
-        topo::remove_back_edges(&mut adj);
-
-        let ordering = topo::topological_sort(&mut adj);
-
-        // figure out what that ordering means, and reorder the vector of
-        // components
-        let mut order_by_component_name = HashMap::new();
-        for (index, element) in ordering.iter().enumerate() {
-            let comp_name = names.get(element).unwrap();
-            order_by_component_name.insert(comp_name, index);
+        let mut bindings = Vec::with_capacity(ports.port_count());
+        for mapping in patch.mappings.iter() {
+            let handle = find_input_port(&ports, &mapping.port)?;
+            // there's a slot for every port, so this can't run out
+            bind(&mut bindings, Binding::new(mapping, handle)).unwrap();
         }
 
-        components.sort_by(|ref e1, ref e2| {
-            let o1 = order_by_component_name[&e1.get_name()];
-            let o2 = order_by_component_name[&e2.get_name()];
-
-            o1.cmp(&o2)
-        });
+        let schedule = Schedule::new(&mut components, &ports, "voice");
 
         // phew, we made it out alive
         Ok(Self {
@@ -102,23 +265,118 @@ impl<'a> Voice<'a> {
             midi_frequency_in,
             midi_vel_in,
             midi_gate_in,
+            unison_pan_in,
             midi_control_ports,
+            midi_control_hires_ports,
+            midi_rpn_ports,
+            midi_nrpn_ports,
+            bindings,
             samples_out,
+            schedule,
+            note: None,
+            velocity: 0.0,
+            pan: 0.0,
+            retrigger: false,
+            sample_rate: None,
+            glide: Glide::off(),
+            frequency: 0.0,
+            target_frequency: 0.0,
+            glide_ratio: 1.0,
+            glide_remaining: 0,
         })
     }
 
-    pub fn note_on(&mut self, freq: f32, vel: f32)
+    /// Start playing a note. If the voice is already playing a note, the note
+    /// is retriggered and the pitch glides to the new frequency
+    pub fn note_on(&mut self, note: u8, freq: f32, vel: f32)
     {
-        // TODO realtime safe
-        self.ports.set_port_value(&self.midi_frequency_in, freq);
-        self.ports.set_port_value(&self.midi_gate_in, 1.0);
+        if self.note.is_some() {
+            // close the gate for one sample so components see a new note
+            self.ports.set_port_value(&self.midi_gate_in, 0.0);
+            self.retrigger = true;
+            self.glide_to(freq);
+        } else {
+            self.ports.set_port_value(&self.midi_gate_in, 1.0);
+            self.jump_to(freq);
+        }
+
+        self.note = Some(note);
+        self.velocity = vel;
         self.ports.set_port_value(&self.midi_vel_in, vel);
     }
 
-    pub fn note_off(&mut self, _freq: f32)
+    /// Change the note being played without retriggering it
+    pub fn slide_to(&mut self, note: u8, freq: f32)
+    {
+        self.note = Some(note);
+        self.glide_to(freq);
+    }
+
+    pub fn note_off(&mut self)
     {
-        // TODO realtime safe
         self.ports.set_port_value(&self.midi_gate_in, 0.0);
+        self.note = None;
+        self.retrigger = false;
+    }
+
+    /// Set the stereo position of the voice, from -1 (left) to 1 (right)
+    /// The position is available to the patch on ("voice" "unison_pan_out")
+    pub fn set_pan(&mut self, pan: f32)
+    {
+        self.pan = pan;
+        self.ports.set_port_value(&self.unison_pan_in, pan);
+    }
+
+    pub fn pan(&self) -> f32
+    {
+        self.pan
+    }
+
+    /// Velocity of the last note started, which the voice keeps through its
+    /// release
+    pub fn velocity(&self) -> f32
+    {
+        self.velocity
+    }
+
+    /// Restart all of the oscillators in the voice at the given phase
+    pub fn reset_phase(&mut self, phase: f32)
+    {
+        for comp in &mut self.components {
+            comp.reset_phase(phase);
+        }
+    }
+
+    pub fn set_glide(&mut self, glide: Glide)
+    {
+        self.glide = glide;
+    }
+
+    fn jump_to(&mut self, freq: f32)
+    {
+        self.frequency = freq;
+        self.target_frequency = freq;
+        self.glide_remaining = 0;
+        self.ports.set_port_value(&self.midi_frequency_in, freq);
+    }
+
+    fn glide_to(&mut self, freq: f32)
+    {
+        let samples = match self.sample_rate {
+            Some(rate) if self.frequency > 0.0 && freq > 0.0 =>
+                self.glide.samples(self.frequency, freq, rate),
+            _ => 0,
+        };
+
+        if samples == 0 {
+            self.jump_to(freq);
+            return;
+        }
+
+        // glide in the log domain so the pitch changes evenly
+        self.target_frequency = freq;
+        self.glide_ratio = (freq / self.frequency).powf(1.0 / samples as f32);
+        self.glide_remaining = samples;
     }
 
     pub fn control_value_change(&mut self, cc: u8, new_val: u8)
@@ -127,32 +385,274 @@ impl<'a> Voice<'a> {
         self.ports.set_port_value(handle, new_val as f32);
     }
 
+    /// Publish a decoded controller change on the voice's output ports
+    pub fn control_event(&mut self, event: ControlEvent)
+    {
+        let (handle, value) = match event {
+            ControlEvent::Controller { cc, value } =>
+                (self.midi_control_hires_ports[cc as usize], value),
+
+            ControlEvent::Registered { parameter, value } =>
+                (find_parameter_port(&self.midi_rpn_ports, parameter), value),
+
+            ControlEvent::NonRegistered { parameter, value } =>
+                (find_parameter_port(&self.midi_nrpn_ports, parameter), value),
+        };
+
+        if let Some(handle) = handle {
+            self.ports.set_port_value(&handle, value);
+        }
+
+        if let ControlEvent::Controller { cc, value } = event {
+            for binding in &self.bindings {
+                if binding.cc == cc {
+                    let v = binding.apply(value);
+                    self.ports.set_input_port_value(&binding.port, v);
+                }
+            }
+        }
+    }
+
+    /// The names of the ports in this voice, for resolving mappings to
+    /// bindings away from the audio thread
+    pub fn port_directory(&self) -> PortDirectory<'a>
+    {
+        self.ports.directory()
+    }
+
+    /// Bind a controller to one of the component input ports in this voice,
+    /// replacing whatever controller was bound to the port before
+    pub fn bind(&mut self, binding: Binding<'a>) -> Result<(), LearnError>
+    {
+        bind(&mut self.bindings, binding)
+    }
+
     pub fn handle_audio_property_change(&mut self, prop: AudioProperties)
     {
+        if let AudioProperties::SampleRate(r) = prop {
+            self.sample_rate = Some(r);
+        }
+
         for comp in &mut self.components {
             comp.handle_audio_property_change(prop);
         }
     }
 
+    pub fn current_note(&self) -> Option<u8>
+    {
+        self.note
+    }
+
+    /// The frequency currently being played, which may be part of the way
+    /// through a glide
     pub fn current_frequency(&self) -> Option<f32>
     {
-        // TODO realtime safe
-        if self.ports.get_port_value(&self.midi_gate_in) != 0.0 {
-            Some(self.ports.get_port_value(&self.midi_frequency_in))
-        } else {
-            None
-        }
+        self.note.map(|_| self.frequency)
+    }
+
+    /// Move the MIDI sent by the voice's components into another queue, at the
+    /// given frame
+    pub fn drain_midi(&mut self, frame: usize, out: &mut EventQueue)
+    {
+        self.ports.drain_midi(frame, out);
+    }
+
+    /// Frames by which the voice's output lags the notes it is played. This is
+    /// the slowest path through the components, adding up the latency of
+    /// each component along the way
+    pub fn latency(&mut self) -> usize
+    {
+        self.schedule.latency(&self.components)
     }
 
-    /// Generate a single sample
-    pub fn generate(&mut self) -> f32
+    /// Number of audio channels the voice produces
+    pub fn channels(&self) -> usize
     {
-        // TODO realtime safe
+        self.samples_out.len()
+    }
+
+    /// Generate a single frame, one sample for each channel
+    pub fn generate(&mut self, frame: &mut [f32])
+    {
+        debug_assert!(frame.len() == self.channels());
+
+        if self.glide_remaining > 0 {
+            self.glide_remaining -= 1;
+            self.frequency = if self.glide_remaining == 0 {
+                self.target_frequency
+            } else {
+                self.frequency * self.glide_ratio
+            };
+
+            self.ports.set_port_value(&self.midi_frequency_in, self.frequency);
+        }
+
         for comp in &mut self.components {
             comp.generate(&mut self.ports);
         }
 
-        // get the value on the output wire
-        self.ports.get_port_value(&self.samples_out)
+        // get the values on the output wires
+        for (sample, port) in frame.iter_mut().zip(self.samples_out.iter()) {
+            *sample = self.ports.get_port_value(port);
+        }
+
+        if self.retrigger {
+            self.ports.set_port_value(&self.midi_gate_in, 1.0);
+            self.retrigger = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use components::OnOffConfig;
+    use mappings::Mapping;
+    use patch::Connection;
+    use testing::{connect, lookahead};
+
+    #[test]
+    fn test_parameter_ports_registered_on_demand() {
+        let nrpn = PortName::new("voice", "midi_nrpn_300");
+        let gate = PortName::new("onoff", "gate_in");
+
+        let mut patch = Patch::new();
+        patch.components.push(
+            Box::new(OnOffConfig { name: "onoff".to_owned() }));
+        patch.connections.push(
+            Connection { first: nrpn.clone(), second: gate.clone() });
+
+        let mut voice = Voice::new(&patch).unwrap();
+        assert!(voice.ports.find_port(&nrpn).is_some());
+
+        let unused = PortName::new("voice", "midi_nrpn_301");
+        assert!(voice.ports.find_port(&unused).is_none());
+
+        voice.control_event(ControlEvent::NonRegistered {
+            parameter: 300,
+            value: 0.5,
+        });
+
+        let gate = voice.ports.find_port(&gate).unwrap();
+        assert_eq!(voice.ports.get_port_value(&gate), 0.5);
+    }
+
+    #[test]
+    fn test_binding_replaces() {
+        let mut patch = Patch::new();
+        lookahead(&mut patch, "a", 0);
+
+        let mut voice = Voice::new(&patch).unwrap();
+        let name = PortName::new("a", "samples_in");
+        let port = voice.port_directory().find_input_port(&name).unwrap();
+
+        let mut mapping = Mapping::new(1, name);
+        voice.bind(Binding::new(&mapping, port)).unwrap();
+        mapping.cc = 2;
+        voice.bind(Binding::new(&mapping, port)).unwrap();
+        assert_eq!(voice.bindings.len(), 1);
+
+        // the controller bound first no longer moves the port
+        voice.control_event(ControlEvent::Controller { cc: 1, value: 0.5 });
+        assert_eq!(voice.ports.get_port_value(&port), 0.0);
+
+        voice.control_event(ControlEvent::Controller { cc: 2, value: 0.5 });
+        assert_eq!(voice.ports.get_port_value(&port), 0.5);
+    }
+
+    #[test]
+    fn test_glide() {
+        let mut voice = Voice::new(&Patch::new()).unwrap();
+        voice.handle_audio_property_change(AudioProperties::SampleRate(100.0));
+        voice.set_glide(Glide {
+            time: 1.0,
+            mode: GlideMode::ConstantTime,
+        });
+
+        voice.note_on(57, 220.0, 1.0);
+        assert_eq!(voice.current_frequency(), Some(220.0));
+
+        voice.slide_to(69, 440.0);
+        for _ in 0..50 {
+            voice.generate(&mut [0.0]);
+        }
+
+        // halfway through, in pitch
+        let f = voice.current_frequency().unwrap();
+        assert!((f - 220.0 * 2.0_f32.sqrt()).abs() < 0.1);
+
+        for _ in 0..50 {
+            voice.generate(&mut [0.0]);
+        }
+        assert_eq!(voice.current_frequency(), Some(440.0));
+    }
+
+    #[test]
+    fn test_constant_rate_glide() {
+        let glide = Glide {
+            time: 1.0,
+            mode: GlideMode::ConstantRate,
+        };
+
+        assert_eq!(glide.samples(220.0, 440.0, 100.0), 100);
+        assert_eq!(glide.samples(880.0, 220.0, 100.0), 200);
+    }
+
+    #[test]
+    fn test_retrigger() {
+        let mut voice = Voice::new(&Patch::new()).unwrap();
+        let gate = voice.midi_gate_in;
+
+        voice.note_on(60, 261.6, 1.0);
+        assert_eq!(voice.ports.get_port_value(&gate), 1.0);
+
+        // the gate closes for exactly one sample
+        voice.note_on(62, 293.7, 1.0);
+        assert_eq!(voice.ports.get_port_value(&gate), 0.0);
+        voice.generate(&mut [0.0]);
+        assert_eq!(voice.ports.get_port_value(&gate), 1.0);
+        assert_eq!(voice.current_note(), Some(62));
+    }
+
+    #[test]
+    fn test_stereo_voice() {
+        let mut patch = Patch::new();
+        patch.channels = 2;
+
+        let mut voice = Voice::new(&patch).unwrap();
+        assert_eq!(voice.channels(), 2);
+
+        let mono = PortName::new("voice", "samples_in");
+        assert!(voice.ports.find_port(&mono).is_none());
+
+        let right = PortName::new("voice", "right_in");
+        let right = find_input_port(&voice.ports, &right).unwrap();
+        voice.ports.set_input_port_value(&right, 0.5);
+
+        let mut frame = [1.0, 1.0];
+        voice.generate(&mut frame);
+        assert_eq!(frame, [0.0, 0.5]);
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut patch = Patch::new();
+        patch.channels = 2;
+        lookahead(&mut patch, "a", 10);
+        lookahead(&mut patch, "b", 5);
+        lookahead(&mut patch, "c", 12);
+
+        // the left channel goes through a and b, the right through c
+        connect(&mut patch, ("voice", "midi_gate_out"), ("a", "samples_in"));
+        connect(&mut patch, ("a", "samples_out"), ("b", "samples_in"));
+        connect(&mut patch, ("b", "samples_out"), ("voice", "left_in"));
+        connect(&mut patch, ("voice", "midi_gate_out"), ("c", "samples_in"));
+        connect(&mut patch, ("c", "samples_out"), ("voice", "right_in"));
+
+        let mut voice = Voice::new(&patch).unwrap();
+        assert_eq!(voice.latency(), 15);
+
+        // nothing on the way to the output
+        assert_eq!(Voice::new(&Patch::new()).unwrap().latency(), 0);
     }
 }
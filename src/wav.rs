@@ -0,0 +1,84 @@
+// Minimal WAV writer for offline rendering
+// Samples are written as 32-bit IEEE floats, interleaved by channel
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const FORMAT_IEEE_FLOAT: u16 = 3;
+const BYTES_PER_SAMPLE: u32 = 4;
+
+fn write_u16<W: Write>(w: &mut W, v: u16) -> io::Result<()>
+{
+    w.write_all(&[v as u8, (v >> 8) as u8])
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()>
+{
+    w.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8])
+}
+
+/// Write interleaved frames to any writer
+pub fn write<W: Write>(
+    w: &mut W,
+    sample_rate: u32,
+    channels: u16,
+    samples: &[f32])
+-> io::Result<()>
+{
+    let data_size = samples.len() as u32 * BYTES_PER_SAMPLE;
+    let block_align = channels as u32 * BYTES_PER_SAMPLE;
+
+    w.write_all(b"RIFF")?;
+    write_u32(w, 4 + (8 + 16) + (8 + data_size))?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    write_u32(w, 16)?;
+    write_u16(w, FORMAT_IEEE_FLOAT)?;
+    write_u16(w, channels)?;
+    write_u32(w, sample_rate)?;
+    write_u32(w, sample_rate * block_align)?;
+    write_u16(w, block_align as u16)?;
+    write_u16(w, 8 * BYTES_PER_SAMPLE as u16)?;
+
+    w.write_all(b"data")?;
+    write_u32(w, data_size)?;
+    for s in samples {
+        write_u32(w, s.to_bits())?;
+    }
+
+    Ok(())
+}
+
+pub fn write_file(
+    path: &Path,
+    sample_rate: u32,
+    channels: u16,
+    samples: &[f32])
+-> io::Result<()>
+{
+    let mut w = BufWriter::new(File::create(path)?);
+    write(&mut w, sample_rate, channels, samples)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let mut out = Vec::new();
+        write(&mut out, 48000, 2, &[0.0, 1.0]).unwrap();
+
+        assert_eq!(out.len(), 44 + 8);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[4..8], &[44, 0, 0, 0]);
+        assert_eq!(&out[8..16], b"WAVEfmt ");
+        assert_eq!(&out[22..24], &[2, 0]);
+        assert_eq!(&out[24..28], &[0x80, 0xBB, 0, 0]);
+        assert_eq!(&out[36..44], b"data\x08\0\0\0");
+        assert_eq!(&out[48..52], &[0, 0, 0x80, 0x3F]);
+    }
+}